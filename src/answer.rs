@@ -1,145 +1,245 @@
-use bytes::{Buf, Bytes, BytesMut};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
 
-use crate::question::{Label, LabelPointer, LabelSequence, QuestionClass, QuestionType};
-use std::net::Ipv4Addr;
+use crate::name::DomainName;
+use crate::question::{QuestionClass, QuestionType};
+use crate::view::DecodeError;
+use std::net::{AddrParseError, Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
 
+/// Type specific data carried by a resource record.
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) enum RData {
+pub enum RData {
     A(Ipv4Addr),
+    AAAA(Ipv6Addr),
+    NS(DomainName),
+    CNAME(DomainName),
+    PTR(DomainName),
+    /// Mail exchange (RFC 1035 section 3.3.9).
+    MX {
+        preference: u16,
+        exchange: DomainName,
+    },
+    /// Start of a zone of authority (RFC 1035 section 3.3.13).
+    SOA {
+        mname: DomainName,
+        rname: DomainName,
+        serial: u32,
+        refresh: u32,
+        retry: u32,
+        expire: u32,
+        minimum: u32,
+    },
+    /// Host information, two character-strings (RFC 1035 section 3.3.2).
+    HINFO {
+        cpu: Bytes,
+        os: Bytes,
+    },
+    /// Data of a type this crate does not interpret, kept as it was received.
+    Unknown(Bytes),
+}
+
+impl RData {
+    /// Reads `length` bytes of data for a record of type `typ` from the front
+    /// of `buf`. Names inside the data may be compressed against `msg`.
+    pub fn read<B: Buf>(
+        typ: &QuestionType,
+        buf: &mut B,
+        msg: &[u8],
+        length: usize,
+    ) -> Result<Self, DecodeError> {
+        if buf.remaining() < length {
+            return Err(DecodeError::Truncated);
+        }
+        let invalid = || DecodeError::InvalidRData(u16::from(*typ));
+        match typ {
+            QuestionType::A if length == 4 => Ok(RData::A(Ipv4Addr::from(buf.get_u32()))),
+            QuestionType::AAAA if length == 16 => Ok(RData::AAAA(Ipv6Addr::from(buf.get_u128()))),
+            QuestionType::A | QuestionType::AAAA => Err(invalid()),
+            QuestionType::NS | QuestionType::CNAME | QuestionType::PTR => {
+                let before = buf.remaining();
+                let name = DomainName::read(buf, msg)?;
+                if before - buf.remaining() != length {
+                    return Err(invalid());
+                }
+                Ok(match typ {
+                    QuestionType::NS => RData::NS(name),
+                    QuestionType::CNAME => RData::CNAME(name),
+                    _ => RData::PTR(name),
+                })
+            }
+            QuestionType::MX => {
+                let before = buf.remaining();
+                if length < 3 {
+                    return Err(invalid());
+                }
+                let preference = buf.get_u16();
+                let exchange = DomainName::read(buf, msg)?;
+                if before - buf.remaining() != length {
+                    return Err(invalid());
+                }
+                Ok(RData::MX {
+                    preference,
+                    exchange,
+                })
+            }
+            QuestionType::SOA => {
+                let before = buf.remaining();
+                let mname = DomainName::read(buf, msg)?;
+                let rname = DomainName::read(buf, msg)?;
+                if before - buf.remaining() + 20 != length {
+                    return Err(invalid());
+                }
+                Ok(RData::SOA {
+                    mname,
+                    rname,
+                    serial: buf.get_u32(),
+                    refresh: buf.get_u32(),
+                    retry: buf.get_u32(),
+                    expire: buf.get_u32(),
+                    minimum: buf.get_u32(),
+                })
+            }
+            QuestionType::HINFO => {
+                let mut data = buf.copy_to_bytes(length);
+                let cpu = read_character_string(&mut data).ok_or_else(invalid)?;
+                let os = read_character_string(&mut data).ok_or_else(invalid)?;
+                if data.has_remaining() {
+                    return Err(invalid());
+                }
+                Ok(RData::HINFO { cpu, os })
+            }
+            _ => Ok(RData::Unknown(buf.copy_to_bytes(length))),
+        }
+    }
+}
+
+/// Reads a length-prefixed <character-string>.
+fn read_character_string(data: &mut Bytes) -> Option<Bytes> {
+    if !data.has_remaining() {
+        return None;
+    }
+    let length = data.get_u8() as usize;
+    if data.remaining() < length {
+        return None;
+    }
+    Some(data.split_to(length))
+}
+
+/// Writes a <character-string>, cut at the 255 bytes its length byte can hold.
+fn write_character_string(value: &[u8], dst: &mut BytesMut) {
+    let value = &value[..value.len().min(255)];
+    dst.put_u8(value.len() as u8);
+    dst.put_slice(value);
 }
 
 impl From<RData> for Bytes {
     fn from(value: RData) -> Self {
         match value {
             RData::A(ip) => Bytes::copy_from_slice(&ip.octets()),
-            _ => panic!("Unsupported RData type"),
+            RData::AAAA(ip) => Bytes::copy_from_slice(&ip.octets()),
+            RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => Bytes::from(&name),
+            RData::MX {
+                preference,
+                exchange,
+            } => {
+                let mut data = BytesMut::new();
+                data.put_u16(preference);
+                exchange.write(&mut data);
+                data.freeze()
+            }
+            RData::SOA {
+                mname,
+                rname,
+                serial,
+                refresh,
+                retry,
+                expire,
+                minimum,
+            } => {
+                let mut data = BytesMut::new();
+                mname.write(&mut data);
+                rname.write(&mut data);
+                for value in [serial, refresh, retry, expire, minimum] {
+                    data.put_u32(value);
+                }
+                data.freeze()
+            }
+            RData::HINFO { cpu, os } => {
+                let mut data = BytesMut::new();
+                write_character_string(&cpu, &mut data);
+                write_character_string(&os, &mut data);
+                data.freeze()
+            }
+            RData::Unknown(data) => data,
         }
     }
 }
 
-impl From<String> for RData {
-    fn from(value: String) -> Self {
-        match value.parse::<Ipv4Addr>() {
-            Ok(ip) => RData::A(ip),
-            Err(e) => panic!("Unsupported RData type. ({}, {})", e, value),
-        }
-    }
-}
+/// Parses the address of an A record.
+impl FromStr for RData {
+    type Err = AddrParseError;
 
-impl From<Bytes> for RData {
-    fn from(value: Bytes) -> Self {
-        match value.len() {
-            4 => RData::A(Ipv4Addr::from(value.clone().get_u32())), // TODO: Check if clone can be avoided.
-            _ => panic!("Unsupported RData type."),
-        }
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(RData::A)
     }
 }
 
+/// A resource record of the answer section.
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct Answer {
-    pub(crate) name: Vec<Label>,
-    pub(crate) typ: QuestionType,
-    pub(crate) class: QuestionClass,
-    pub(crate) ttl: u32,
-    pub(crate) length: u16,
-    pub(crate) data: RData,
+pub struct Answer {
+    pub name: DomainName,
+    pub typ: QuestionType,
+    pub class: QuestionClass,
+    pub ttl: u32,
+    pub data: RData,
 }
 
 impl Answer {
-    pub(crate) fn new(
-        name: String,
-        typ: u16,
-        class: u16,
-        ttl: u32,
-        length: u16,
-        data: String,
-    ) -> Self {
-        let mut labels = Vec::new();
-        for label in name.split('.') {
-            labels.push(Label::Sequence(LabelSequence {
-                content: label.to_string(),
-                length: label.len() as u8,
-            }));
-        }
+    /// Panics if `name` is not a valid domain name or `data` is not an IPv4
+    /// address.
+    pub fn new(name: String, typ: u16, class: u16, ttl: u32, data: String) -> Self {
         Answer {
-            name: labels,
+            name: name.parse().expect("Invalid domain name"),
             typ: QuestionType::from(typ),
             class: QuestionClass::from(class),
             ttl,
-            length,
-            data: RData::from(data),
+            data: data.parse().expect("Invalid IPv4 address"),
         }
     }
-}
 
-impl<B: Buf> From<&mut B> for Answer {
-    fn from(value: &mut B) -> Self {
-        let mut labels: Vec<Label> = Vec::new();
-        loop {
-            let first_byte = value.chunk()[0];
-            match (first_byte & 0b1100_0000) >> 6 {
-                0 => {
-                    if first_byte == b'\0' {
-                        value.advance(1);
-                        break;
-                    }
-                    let length = value.get_u8() as usize;
-                    let mut content = String::new();
-                    let label_bytes = value.copy_to_bytes(length).to_vec();
-                    content.push_str(String::from_utf8(label_bytes).unwrap().as_str()); // TODO: Handle errors here
-                    labels.push(Label::Sequence(LabelSequence {
-                        content,
-                        length: length as u8,
-                    }));
-                }
-                3 => {
-                    let pointer =
-                        ((value.get_u8() & 0b0011_1111) as u16) << 8 | value.get_u8() as u16;
-                    labels.push(Label::Pointer(LabelPointer { pointer }));
-                    break;
-                }
-                _ => panic!("Invalid Label"),
-            }
+    /// Reads a resource record from the front of `buf`, resolving compressed
+    /// names against `msg`. See [`DomainName::read`].
+    pub fn read<B: Buf>(buf: &mut B, msg: &[u8]) -> Result<Self, DecodeError> {
+        let name = DomainName::read(buf, msg)?;
+        if buf.remaining() < 10 {
+            return Err(DecodeError::Truncated);
         }
-        let typ = QuestionType::from(value.get_u16());
-        let class = QuestionClass::from(value.get_u16());
-        let ttl = value.get_u32();
-        let length = value.get_u16();
-        let data = RData::from(value.copy_to_bytes(length as usize));
-        Answer {
-            name: labels,
+        let typ = QuestionType::from(buf.get_u16());
+        let class = QuestionClass::from(buf.get_u16());
+        let ttl = buf.get_u32();
+        let length = buf.get_u16() as usize;
+        let data = RData::read(&typ, buf, msg, length)?;
+        Ok(Answer {
+            name,
             typ,
             class,
             ttl,
-            length,
             data,
-        }
+        })
     }
 }
 
 impl From<Answer> for Bytes {
     fn from(value: Answer) -> Self {
         let mut bytes = BytesMut::new();
-        for label in &value.name {
-            match label {
-                Label::Pointer(pointer) => {
-                    bytes.extend_from_slice(&[0b1100_0000 | (pointer.pointer >> 8) as u8]);
-                    bytes.extend_from_slice(&[pointer.pointer as u8]);
-                }
-                Label::Sequence(sequence) => {
-                    bytes.extend_from_slice(&[sequence.length]);
-                    bytes.extend_from_slice(sequence.content.as_bytes());
-                }
-            }
-        }
-        if let Label::Sequence(_) = value.name.last().unwrap() {
-            bytes.extend_from_slice(&[0])
-        }
+        value.name.write(&mut bytes);
         bytes.extend_from_slice(&Bytes::from(value.typ));
         bytes.extend_from_slice(&Bytes::from(value.class));
         bytes.extend_from_slice(&value.ttl.to_be_bytes());
-        bytes.extend_from_slice(&value.length.to_be_bytes());
-        bytes.extend_from_slice(&Bytes::from(value.data));
+        // RDLENGTH is always derived from the encoded data so the two cannot disagree.
+        let data = Bytes::from(value.data);
+        bytes.extend_from_slice(&(data.len() as u16).to_be_bytes());
+        bytes.extend_from_slice(&data);
 
         bytes.freeze()
     }
@@ -148,14 +248,15 @@ impl From<Answer> for Bytes {
 #[cfg(test)]
 mod answer_tests {
     use super::*;
-    use std::net::Ipv4Addr;
 
     #[test]
-    fn test_rdata_from_string() {
+    fn test_rdata_from_str() {
         assert_eq!(
-            RData::from(String::from("127.0.0.1")),
-            RData::A(Ipv4Addr::new(127, 0, 0, 1))
+            "127.0.0.1".parse::<RData>(),
+            Ok(RData::A(Ipv4Addr::new(127, 0, 0, 1)))
         );
+        assert!("::1".parse::<RData>().is_err());
+        assert!("codecrafters.io".parse::<RData>().is_err());
     }
 
     #[test]
@@ -173,26 +274,15 @@ mod answer_tests {
             1,
             1,
             3600,
-            4,
             "127.0.0.1".to_string(),
         );
         assert_eq!(
             answer,
             Answer {
-                name: vec![
-                    Label::Sequence(LabelSequence {
-                        content: "codecrafters".to_string(),
-                        length: 12,
-                    }),
-                    Label::Sequence(LabelSequence {
-                        content: "io".to_string(),
-                        length: 2,
-                    }),
-                ],
+                name: "codecrafters.io".parse().unwrap(),
                 typ: QuestionType::A,
                 class: QuestionClass::IN,
                 ttl: 3600,
-                length: 4,
                 data: RData::A(Ipv4Addr::new(127, 0, 0, 1)),
             }
         );
@@ -204,15 +294,214 @@ mod answer_tests {
             3, 119, 119, 119, 4, 116, 101, 115, 116, 3, 99, 111, 109, 0, 0, 1, 0, 1, 0, 0, 0, 0, 0,
             4, 127, 0, 0, 1,
         ];
-        let answer = Answer::new(
-            "www.test.com".to_string(),
+        let answer = Answer::new("www.test.com".to_string(), 1, 1, 0, "127.0.0.1".to_string());
+        let bytes = Bytes::from(answer);
+        assert_eq!(bytes, Bytes::copy_from_slice(&bytes_sample));
+    }
+
+    #[test]
+    fn test_answer_from_bytes() {
+        // A question for "www.test.com" followed by an answer that points to it.
+        let bytes_sample: [u8; 34] = [
+            3,
+            119,
+            119,
+            119,
+            4,
+            116,
+            101,
+            115,
+            116,
+            3,
+            99,
+            111,
+            109,
+            0,
+            0,
+            1,
+            0,
+            1,
+            0b1100_0000,
+            0,
+            0,
             1,
+            0,
             1,
             0,
+            0,
+            0x0e,
+            0x10,
+            0,
             4,
-            "127.0.0.1".to_string(),
+            127,
+            0,
+            0,
+            1,
+        ];
+        let mut buf = Bytes::copy_from_slice(&bytes_sample[18..]);
+        assert_eq!(
+            Answer::read(&mut buf, &bytes_sample),
+            Ok(Answer::new(
+                "www.test.com".to_string(),
+                1,
+                1,
+                3600,
+                "127.0.0.1".to_string()
+            ))
+        );
+    }
+
+    #[test]
+    fn test_rdata_read() {
+        let read = |typ: QuestionType, data: &[u8]| {
+            RData::read(&typ, &mut Bytes::copy_from_slice(data), data, data.len())
+        };
+        assert_eq!(
+            read(QuestionType::A, &[127, 0, 0, 1]),
+            Ok(RData::A(Ipv4Addr::new(127, 0, 0, 1)))
+        );
+        assert_eq!(
+            read(QuestionType::A, &[127, 0, 0]),
+            Err(DecodeError::InvalidRData(1))
+        );
+        assert_eq!(
+            read(QuestionType::AAAA, &Ipv6Addr::LOCALHOST.octets()),
+            Ok(RData::AAAA(Ipv6Addr::LOCALHOST))
+        );
+        assert_eq!(
+            read(QuestionType::CNAME, &[2, b'i', b'o', 0]),
+            Ok(RData::CNAME("io".parse().unwrap()))
+        );
+        assert_eq!(
+            read(QuestionType::PTR, &[2, b'i', b'o']),
+            Err(DecodeError::Name(crate::NameError::Truncated))
+        );
+        assert_eq!(
+            read(QuestionType::Unknown(99), &[0]),
+            Ok(RData::Unknown(Bytes::from_static(&[0])))
+        );
+    }
+
+    #[test]
+    fn test_hinfo() {
+        let data: &[u8] = &[7, b'R', b'F', b'C', b'8', b'4', b'8', b'2', 0];
+        let hinfo = RData::HINFO {
+            cpu: Bytes::from_static(b"RFC8482"),
+            os: Bytes::new(),
+        };
+        assert_eq!(Bytes::from(hinfo.clone()).as_ref(), data);
+        assert_eq!(
+            RData::read(
+                &QuestionType::HINFO,
+                &mut Bytes::copy_from_slice(data),
+                data,
+                data.len()
+            ),
+            Ok(hinfo)
+        );
+        for broken in [&data[..5], &data[..8], &[0, 0, 0][..]] {
+            assert_eq!(
+                RData::read(
+                    &QuestionType::HINFO,
+                    &mut Bytes::copy_from_slice(broken),
+                    broken,
+                    broken.len()
+                ),
+                Err(DecodeError::InvalidRData(13))
+            );
+        }
+    }
+
+    #[test]
+    fn test_compressed_rdata() {
+        // "io" at offset 0, then a CNAME whose data points back to it.
+        let msg: &[u8] = &[2, b'i', b'o', 0, 1, b'a', 0b1100_0000, 0];
+        let mut buf = Bytes::copy_from_slice(&msg[4..]);
+        assert_eq!(
+            RData::read(&QuestionType::CNAME, &mut buf, msg, 4),
+            Ok(RData::CNAME("a.io".parse().unwrap()))
+        );
+        assert_eq!(
+            Bytes::from(RData::CNAME("a.io".parse().unwrap())).as_ref(),
+            &[1, b'a', 2, b'i', b'o', 0]
+        );
+    }
+
+    #[test]
+    fn test_mx_and_soa() {
+        // "io" at offset 0, then an MX and a SOA whose names point back to it.
+        let msg: &[u8] = &[
+            2,
+            b'i',
+            b'o',
+            0,
+            0,
+            10,
+            2,
+            b'm',
+            b'x',
+            0b1100_0000,
+            0,
+            0b1100_0000,
+            0,
+            1,
+            b'h',
+            0b1100_0000,
+            0,
+            0,
+            0,
+            0,
+            1,
+            0,
+            0,
+            0,
+            2,
+            0,
+            0,
+            0,
+            3,
+            0,
+            0,
+            0,
+            4,
+            0,
+            0,
+            0,
+            5,
+        ];
+        let mx = RData::MX {
+            preference: 10,
+            exchange: "mx.io".parse().unwrap(),
+        };
+        let mut buf = Bytes::copy_from_slice(&msg[4..]);
+        assert_eq!(
+            RData::read(&QuestionType::MX, &mut buf, msg, 7),
+            Ok(mx.clone())
+        );
+        assert_eq!(
+            Bytes::from(mx).as_ref(),
+            &[0, 10, 2, b'm', b'x', 2, b'i', b'o', 0]
+        );
+
+        let soa = RData::SOA {
+            mname: "io".parse().unwrap(),
+            rname: "h.io".parse().unwrap(),
+            serial: 1,
+            refresh: 2,
+            retry: 3,
+            expire: 4,
+            minimum: 5,
+        };
+        assert_eq!(
+            RData::read(&QuestionType::SOA, &mut buf, msg, 26),
+            Ok(soa.clone())
+        );
+        assert!(!buf.has_remaining());
+        assert_eq!(Bytes::from(soa).len(), 4 + 6 + 20);
+        let mut short = Bytes::copy_from_slice(&msg[11..]);
+        assert_eq!(
+            RData::read(&QuestionType::SOA, &mut short, msg, 25),
+            Err(DecodeError::InvalidRData(6))
         );
-        let bytes = Bytes::from(answer);
-        assert_eq!(bytes, Bytes::copy_from_slice(&bytes_sample));
     }
 }
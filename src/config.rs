@@ -0,0 +1,869 @@
+use crate::middleware;
+use ipnet::IpNet;
+use log::LevelFilter;
+use rust_dns::DomainName;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub(crate) enum ConfigError {
+    #[error("Failed to read config file {path:?}: {source}")]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to open {path:?}: {source}")]
+    Open {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Failed to parse config file: {0}")]
+    Parse(#[from] toml::de::Error),
+    #[error("Invalid value for '{field}': {reason}")]
+    Invalid { field: &'static str, reason: String },
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl From<LogLevel> for LevelFilter {
+    fn from(value: LogLevel) -> Self {
+        match value {
+            LogLevel::Error => LevelFilter::Error,
+            LogLevel::Warn => LevelFilter::Warn,
+            LogLevel::Info => LevelFilter::Info,
+            LogLevel::Debug => LevelFilter::Debug,
+            LogLevel::Trace => LevelFilter::Trace,
+        }
+    }
+}
+
+impl TryFrom<&str> for LogLevel {
+    type Error = ConfigError;
+
+    fn try_from(value: &str) -> Result<Self, ConfigError> {
+        match value.to_lowercase().as_str() {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "trace" => Ok(LogLevel::Trace),
+            _ => Err(ConfigError::Invalid {
+                field: "log.level",
+                reason: format!("unknown log level '{}'", value),
+            }),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ServerConfig {
+    pub(crate) addr: String,
+    pub(crate) port: u16,
+    /// Seconds to wait for in-flight queries when shutting down.
+    pub(crate) shutdown_timeout: u64,
+    /// Middleware names in the order queries pass through them.
+    pub(crate) middleware: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            addr: "127.0.0.1".to_string(),
+            port: 2053,
+            shutdown_timeout: 5,
+            middleware: vec![
+                "rrl".to_string(),
+                "any".to_string(),
+                "hosts".to_string(),
+                "blocklist".to_string(),
+                "rebind".to_string(),
+                "forward".to_string(),
+                "fallback".to_string(),
+            ],
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct UpstreamConfig {
+    pub(crate) resolver: Option<String>,
+    /// Seconds to wait for the resolver before answering SERVFAIL.
+    pub(crate) timeout: u64,
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        UpstreamConfig {
+            resolver: None,
+            timeout: 2,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct HostsConfig {
+    /// `/etc/hosts`-style files answered by the `hosts` middleware.
+    pub(crate) files: Vec<PathBuf>,
+    /// TTL of the records served from the files.
+    pub(crate) ttl: u32,
+    /// Seconds between checks for changes to the files, at least one.
+    pub(crate) refresh: u64,
+}
+
+impl Default for HostsConfig {
+    fn default() -> Self {
+        HostsConfig {
+            files: Vec::new(),
+            ttl: 300,
+            refresh: 5,
+        }
+    }
+}
+
+/// How the `blocklist` middleware answers blocked names.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum BlockMode {
+    /// Answer NXDOMAIN, as if the name did not exist.
+    #[default]
+    Nxdomain,
+    /// Answer `0.0.0.0` and `::`.
+    Null,
+    /// Answer `sinkhole_ipv4` and `sinkhole_ipv6`.
+    Sinkhole,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct BlocklistConfig {
+    /// Lists of blocked domains in hosts, plain domain or adblock format.
+    pub(crate) lists: Vec<PathBuf>,
+    /// Domains that are never blocked, even when a list contains them.
+    pub(crate) allowlists: Vec<PathBuf>,
+    pub(crate) mode: BlockMode,
+    pub(crate) sinkhole_ipv4: Option<Ipv4Addr>,
+    pub(crate) sinkhole_ipv6: Option<Ipv6Addr>,
+    /// TTL of the addresses answered in `null` and `sinkhole` mode.
+    pub(crate) ttl: u32,
+    /// Seconds between checks for changes to the lists, at least one.
+    pub(crate) refresh: u64,
+}
+
+impl Default for BlocklistConfig {
+    fn default() -> Self {
+        BlocklistConfig {
+            lists: Vec::new(),
+            allowlists: Vec::new(),
+            mode: BlockMode::Nxdomain,
+            sinkhole_ipv4: None,
+            sinkhole_ipv6: None,
+            ttl: 60,
+            refresh: 5,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct QueryLogConfig {
+    /// File that gets a JSON line per query. Nothing is logged without one.
+    pub(crate) path: Option<PathBuf>,
+    /// Size in bytes at which the file is rotated.
+    pub(crate) max_size: u64,
+    /// Rotated files kept next to the current one.
+    pub(crate) max_files: u32,
+}
+
+impl Default for QueryLogConfig {
+    fn default() -> Self {
+        QueryLogConfig {
+            path: None,
+            max_size: 10 * 1024 * 1024,
+            max_files: 5,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct DnstapConfig {
+    /// Unix socket of a Frame Streams collector such as `dnstap` or `fstrm_capture`.
+    pub(crate) socket: Option<PathBuf>,
+    /// File to write a Frame Streams capture to instead, truncated on startup.
+    pub(crate) file: Option<PathBuf>,
+    /// Sent as the dnstap identity, to tell servers apart.
+    pub(crate) identity: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct MetricsConfig {
+    /// Address serving Prometheus metrics at `/metrics`, e.g. `127.0.0.1:9153`.
+    pub(crate) listen: Option<String>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ControlConfig {
+    /// Unix socket accepting control commands such as `reload`.
+    pub(crate) socket: Option<PathBuf>,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct LogConfig {
+    pub(crate) level: LogLevel,
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            level: LogLevel::Info,
+        }
+    }
+}
+
+/// Response rate limiting, see the `rrl` middleware.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RrlConfig {
+    /// Identical responses allowed per client prefix each second. Zero turns
+    /// rate limiting off.
+    pub(crate) responses_per_second: u32,
+    /// Seconds a client that keeps going over the limit stays limited.
+    pub(crate) window: u64,
+    /// Every `slip`th withheld response is sent truncated instead. Zero
+    /// drops them all. Truncation asks the client to retry over TCP, which
+    /// this server does not listen on yet, so slipping only helps once it
+    /// does and is off by default.
+    pub(crate) slip: u64,
+    /// Only log the responses that would be withheld.
+    pub(crate) log_only: bool,
+    pub(crate) ipv4_prefix_length: u8,
+    pub(crate) ipv6_prefix_length: u8,
+}
+
+impl Default for RrlConfig {
+    fn default() -> Self {
+        RrlConfig {
+            responses_per_second: 0,
+            window: 15,
+            slip: 0,
+            log_only: false,
+            ipv4_prefix_length: 24,
+            ipv6_prefix_length: 56,
+        }
+    }
+}
+
+/// How ANY queries are answered, see RFC 8482.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AnyMode {
+    /// Answer a single synthesized HINFO record.
+    #[default]
+    Hinfo,
+    /// Answer with the A records only.
+    Rrset,
+    /// Answer REFUSED.
+    Refuse,
+    /// Handle them like any other query.
+    Pass,
+}
+
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AnyConfig {
+    /// Mode for names outside every zone below, which are forwarded.
+    pub(crate) forwarded: AnyMode,
+    /// Modes for names at or below these, the closest one winning.
+    pub(crate) zones: BTreeMap<String, AnyMode>,
+    /// TTL of the synthesized HINFO record.
+    pub(crate) ttl: u32,
+}
+
+impl Default for AnyConfig {
+    fn default() -> Self {
+        AnyConfig {
+            forwarded: AnyMode::Hinfo,
+            zones: BTreeMap::new(),
+            ttl: 3600,
+        }
+    }
+}
+
+impl AnyConfig {
+    pub(crate) fn zones(&self) -> Result<HashMap<DomainName, AnyMode>, ConfigError> {
+        self.zones
+            .iter()
+            .map(|(zone, mode)| match zone.parse::<DomainName>() {
+                Ok(name) => Ok((name, *mode)),
+                Err(e) => Err(ConfigError::Invalid {
+                    field: "any.zones",
+                    reason: format!("'{}' is not a valid name ({})", zone, e),
+                }),
+            })
+            .collect()
+    }
+}
+
+/// What the `rebind` middleware does with upstream answers that point at
+/// private addresses.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum RebindMode {
+    /// Leave them alone.
+    #[default]
+    Off,
+    /// Remove the private A and AAAA records.
+    Strip,
+    /// Answer REFUSED instead.
+    Reject,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct RebindConfig {
+    pub(crate) mode: RebindMode,
+    /// Names that may resolve to private addresses, with everything below them.
+    pub(crate) allow: Vec<String>,
+}
+
+impl RebindConfig {
+    pub(crate) fn allowed(&self) -> Result<HashSet<DomainName>, ConfigError> {
+        self.allow
+            .iter()
+            .map(|name| {
+                name.parse().map_err(|e| ConfigError::Invalid {
+                    field: "rebind.allow",
+                    reason: format!("'{}' is not a valid name ({})", name, e),
+                })
+            })
+            .collect()
+    }
+}
+
+/// What happens to queries from clients an access list does not let in.
+#[derive(Debug, PartialEq, Clone, Copy, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum AclAction {
+    /// Answer REFUSED.
+    #[default]
+    Refuse,
+    /// Send nothing back.
+    Drop,
+}
+
+/// Source networks let in (all of them when `allow` is empty) and kept out.
+/// `deny` wins over `allow`.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AclRule {
+    pub(crate) allow: Vec<IpNet>,
+    pub(crate) deny: Vec<IpNet>,
+}
+
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct AclConfig {
+    /// Who may have queries forwarded upstream.
+    pub(crate) recursion: AclRule,
+    /// Who may get answers from local data such as hosts files.
+    pub(crate) authoritative: AclRule,
+    pub(crate) action: AclAction,
+}
+
+/// Clients whose source address falls in `networks` get their own upstream,
+/// hosts and blocklist settings. A section that is given replaces the
+/// top-level one as a whole, so fields it leaves out take their defaults
+/// rather than the top-level values. Sections that are left out are
+/// inherited from the top level of the config.
+#[derive(Debug, PartialEq, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct GroupConfig {
+    pub(crate) name: String,
+    pub(crate) networks: Vec<IpNet>,
+    pub(crate) upstream: Option<UpstreamConfig>,
+    pub(crate) hosts: Option<HostsConfig>,
+    pub(crate) blocklist: Option<BlocklistConfig>,
+}
+
+/// Server configuration as read from the `--config` TOML file.
+///
+/// Every section and field is optional; anything left out falls back to the
+/// same defaults the CLI flags use.
+#[derive(Debug, PartialEq, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Config {
+    pub(crate) server: ServerConfig,
+    pub(crate) upstream: UpstreamConfig,
+    pub(crate) hosts: HostsConfig,
+    pub(crate) blocklist: BlocklistConfig,
+    pub(crate) acl: AclConfig,
+    pub(crate) rrl: RrlConfig,
+    pub(crate) any: AnyConfig,
+    pub(crate) rebind: RebindConfig,
+    pub(crate) query_log: QueryLogConfig,
+    pub(crate) dnstap: DnstapConfig,
+    pub(crate) metrics: MetricsConfig,
+    pub(crate) control: ControlConfig,
+    pub(crate) log: LogConfig,
+    /// Per-client policy groups, matched in order against the source address.
+    pub(crate) groups: Vec<GroupConfig>,
+}
+
+impl Config {
+    pub(crate) fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::parse(&content)
+    }
+
+    pub(crate) fn parse(content: &str) -> Result<Self, ConfigError> {
+        let config: Config = toml::from_str(content)?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Checks that every address in the config resolves and every middleware
+    /// exists, so that errors are reported at startup instead of when the
+    /// server first needs them.
+    pub(crate) fn validate(&self) -> Result<(), ConfigError> {
+        self.listen_addr()?;
+        self.metrics_addr()?;
+        self.validate_policy()?;
+        if self.rrl.ipv4_prefix_length > 32 {
+            return Err(ConfigError::Invalid {
+                field: "rrl.ipv4_prefix_length",
+                reason: "must be at most 32".to_string(),
+            });
+        }
+        if self.rrl.ipv6_prefix_length > 128 {
+            return Err(ConfigError::Invalid {
+                field: "rrl.ipv6_prefix_length",
+                reason: "must be at most 128".to_string(),
+            });
+        }
+        self.any.zones()?;
+        self.rebind.allowed()?;
+        if self.dnstap.socket.is_some() && self.dnstap.file.is_some() {
+            return Err(ConfigError::Invalid {
+                field: "dnstap",
+                reason: "set either socket or file, not both".to_string(),
+            });
+        }
+        middleware::validate_names(&self.server.middleware)?;
+        for (i, group) in self.groups.iter().enumerate() {
+            let invalid = |reason: String| ConfigError::Invalid {
+                field: "groups",
+                reason: format!("group '{}' {}", group.name, reason),
+            };
+            if group.networks.is_empty() {
+                return Err(invalid("has no networks".to_string()));
+            }
+            if self.groups[..i]
+                .iter()
+                .any(|other| other.name == group.name)
+            {
+                return Err(invalid("is defined more than once".to_string()));
+            }
+            self.for_group(group)
+                .validate_policy()
+                .map_err(|e| invalid(e.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// The checks that also apply to the sections a group overrides.
+    fn validate_policy(&self) -> Result<(), ConfigError> {
+        self.resolver_addr()?;
+        if self.upstream.timeout == 0 {
+            return Err(ConfigError::Invalid {
+                field: "upstream.timeout",
+                reason: "must be at least one second".to_string(),
+            });
+        }
+        let blocklist = &self.blocklist;
+        if blocklist.mode == BlockMode::Sinkhole
+            && blocklist.sinkhole_ipv4.is_none()
+            && blocklist.sinkhole_ipv6.is_none()
+        {
+            return Err(ConfigError::Invalid {
+                field: "blocklist.sinkhole_ipv4",
+                reason: "sinkhole mode needs sinkhole_ipv4 or sinkhole_ipv6".to_string(),
+            });
+        }
+        Ok(())
+    }
+
+    /// This config with the sections `group` overrides swapped in whole.
+    pub(crate) fn for_group(&self, group: &GroupConfig) -> Config {
+        let mut config = self.clone();
+        config.groups.clear();
+        if let Some(upstream) = &group.upstream {
+            config.upstream = upstream.clone();
+        }
+        if let Some(hosts) = &group.hosts {
+            config.hosts = hosts.clone();
+        }
+        if let Some(blocklist) = &group.blocklist {
+            config.blocklist = blocklist.clone();
+        }
+        config
+    }
+
+    pub(crate) fn listen_addr(&self) -> Result<SocketAddr, ConfigError> {
+        resolve(
+            "server.addr",
+            &format!("{}:{}", self.server.addr, self.server.port),
+        )
+    }
+
+    pub(crate) fn metrics_addr(&self) -> Result<Option<SocketAddr>, ConfigError> {
+        match &self.metrics.listen {
+            Some(listen) => resolve("metrics.listen", listen).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn resolver_addr(&self) -> Result<Option<SocketAddr>, ConfigError> {
+        match &self.upstream.resolver {
+            Some(resolver) => resolve("upstream.resolver", resolver).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn resolve(field: &'static str, addr: &str) -> Result<SocketAddr, ConfigError> {
+    addr.to_socket_addrs()
+        .map_err(|e| ConfigError::Invalid {
+            field,
+            reason: format!("'{}' is not a valid socket address ({})", addr, e),
+        })?
+        .next()
+        .ok_or_else(|| ConfigError::Invalid {
+            field,
+            reason: format!("'{}' did not resolve to any address", addr),
+        })
+}
+
+#[cfg(test)]
+mod config_tests {
+    use super::*;
+
+    #[test]
+    fn test_empty_config_uses_defaults() {
+        let config = Config::parse("").unwrap();
+        assert_eq!(config, Config::default());
+        assert_eq!(
+            config.listen_addr().unwrap(),
+            "127.0.0.1:2053".parse().unwrap()
+        );
+        assert_eq!(config.resolver_addr().unwrap(), None);
+        assert_eq!(config.log.level, LogLevel::Info);
+    }
+
+    #[test]
+    fn test_parse_config() {
+        let config = Config::parse(
+            r#"
+            [server]
+            addr = "0.0.0.0"
+            port = 53
+            shutdown_timeout = 10
+
+            [upstream]
+            resolver = "8.8.8.8:53"
+            timeout = 3
+
+            [hosts]
+            files = ["/etc/hosts", "/etc/hosts.local"]
+            refresh = 30
+
+            [query_log]
+            path = "/var/log/dns-queries.log"
+            max_size = 1048576
+
+            [control]
+            socket = "/run/dns-server.sock"
+
+            [log]
+            level = "debug"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.listen_addr().unwrap(), "0.0.0.0:53".parse().unwrap());
+        assert_eq!(config.server.shutdown_timeout, 10);
+        assert_eq!(config.upstream.timeout, 3);
+        assert_eq!(config.hosts.files.len(), 2);
+        assert_eq!(config.hosts.refresh, 30);
+        assert_eq!(config.hosts.ttl, 300);
+        assert_eq!(
+            config.resolver_addr().unwrap(),
+            Some("8.8.8.8:53".parse().unwrap())
+        );
+        assert_eq!(
+            config.control.socket,
+            Some(PathBuf::from("/run/dns-server.sock"))
+        );
+        assert_eq!(config.log.level, LogLevel::Debug);
+        assert_eq!(config.query_log.max_size, 1048576);
+        assert_eq!(config.query_log.max_files, 5);
+    }
+
+    #[test]
+    fn test_zero_upstream_timeout_is_rejected() {
+        assert!(matches!(
+            Config::parse("[upstream]\ntimeout = 0\n"),
+            Err(ConfigError::Invalid {
+                field: "upstream.timeout",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_blocklist_config() {
+        let config = Config::parse(
+            r#"
+            [blocklist]
+            lists = ["/etc/dns/ads.txt"]
+            mode = "sinkhole"
+            sinkhole_ipv4 = "10.0.0.53"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.blocklist.mode, BlockMode::Sinkhole);
+        assert_eq!(
+            config.blocklist.sinkhole_ipv4,
+            Some(Ipv4Addr::new(10, 0, 0, 53))
+        );
+        assert_eq!(config.blocklist.ttl, 60);
+
+        assert!(matches!(
+            Config::parse("[blocklist]\nmode = \"sinkhole\"\n"),
+            Err(ConfigError::Invalid {
+                field: "blocklist.sinkhole_ipv4",
+                ..
+            })
+        ));
+        assert!(Config::parse("[blocklist]\nmode = \"refuse\"\n").is_err());
+    }
+
+    #[test]
+    fn test_groups_config() {
+        let config = Config::parse(
+            r#"
+            [upstream]
+            resolver = "8.8.8.8:53"
+            timeout = 10
+
+            [[groups]]
+            name = "kids"
+            networks = ["10.0.1.0/24", "fd00:1::/64"]
+            blocklist = { lists = ["/etc/dns/strict.txt"] }
+
+            [[groups]]
+            name = "servers"
+            networks = ["10.0.2.0/24"]
+            upstream = { resolver = "10.0.2.1:53" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.groups.len(), 2);
+        assert_eq!(
+            config.groups[0].networks[0],
+            "10.0.1.0/24".parse::<IpNet>().unwrap()
+        );
+
+        let kids = config.for_group(&config.groups[0]);
+        assert_eq!(kids.blocklist.lists.len(), 1);
+        assert_eq!(kids.upstream, config.upstream);
+        let servers = config.for_group(&config.groups[1]);
+        assert_eq!(
+            servers.resolver_addr().unwrap(),
+            Some("10.0.2.1:53".parse().unwrap())
+        );
+        // The group's section is not merged with the top-level one.
+        assert_eq!(servers.upstream.timeout, UpstreamConfig::default().timeout);
+        assert!(servers.groups.is_empty());
+    }
+
+    #[test]
+    fn test_invalid_groups_are_rejected() {
+        for content in [
+            "[[groups]]\nname = \"a\"\nnetworks = []\n",
+            "[[groups]]\nname = \"a\"\nnetworks = [\"10.0.0.0/8\"]\n\
+             [[groups]]\nname = \"a\"\nnetworks = [\"10.0.0.0/8\"]\n",
+            "[[groups]]\nname = \"a\"\nnetworks = [\"10.0.0.0/8\"]\n\
+             upstream = { timeout = 0 }\n",
+        ] {
+            assert!(
+                matches!(
+                    Config::parse(content),
+                    Err(ConfigError::Invalid {
+                        field: "groups",
+                        ..
+                    })
+                ),
+                "{}",
+                content
+            );
+        }
+        assert!(matches!(
+            Config::parse("[[groups]]\nname = \"a\"\nnetworks = [\"10.0.0.300/8\"]\n"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_acl_config() {
+        let config = Config::parse(
+            r#"
+            [acl]
+            action = "drop"
+            recursion = { allow = ["10.0.0.0/8"], deny = ["10.0.66.0/24"] }
+            authoritative = { deny = ["203.0.113.0/24"] }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.acl.action, AclAction::Drop);
+        assert_eq!(config.acl.recursion.allow.len(), 1);
+        assert_eq!(config.acl.recursion.deny.len(), 1);
+        assert!(config.acl.authoritative.allow.is_empty());
+        assert_eq!(Config::default().acl.action, AclAction::Refuse);
+    }
+
+    #[test]
+    fn test_rrl_config() {
+        let config =
+            Config::parse("[rrl]\nresponses_per_second = 5\nslip = 3\nlog_only = true\n").unwrap();
+        assert_eq!(config.rrl.responses_per_second, 5);
+        assert_eq!(config.rrl.slip, 3);
+        assert!(config.rrl.log_only);
+        assert_eq!(config.rrl.window, 15);
+        assert!(matches!(
+            Config::parse("[rrl]\nipv4_prefix_length = 33\n"),
+            Err(ConfigError::Invalid {
+                field: "rrl.ipv4_prefix_length",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_any_config() {
+        let config = Config::parse(
+            r#"
+            [any]
+            forwarded = "refuse"
+            zones = { "lan" = "rrset", "example.com" = "pass" }
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.any.forwarded, AnyMode::Refuse);
+        let zones = config.any.zones().unwrap();
+        assert_eq!(zones[&"LAN".parse().unwrap()], AnyMode::Rrset);
+        assert_eq!(Config::default().any.forwarded, AnyMode::Hinfo);
+        assert!(matches!(
+            Config::parse("[any]\nzones = { \"a..b\" = \"pass\" }\n"),
+            Err(ConfigError::Invalid {
+                field: "any.zones",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_rebind_config() {
+        let config =
+            Config::parse("[rebind]\nmode = \"strip\"\nallow = [\"lan\", \"plex.direct\"]\n")
+                .unwrap();
+        assert_eq!(config.rebind.mode, RebindMode::Strip);
+        assert_eq!(config.rebind.allowed().unwrap().len(), 2);
+        assert_eq!(Config::default().rebind.mode, RebindMode::Off);
+        assert!(matches!(
+            Config::parse("[rebind]\nallow = [\"a..b\"]\n"),
+            Err(ConfigError::Invalid {
+                field: "rebind.allow",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_dnstap_config() {
+        let config =
+            Config::parse("[dnstap]\nsocket = \"/run/dnstap.sock\"\nidentity = \"ns1\"\n").unwrap();
+        assert_eq!(
+            config.dnstap.socket.as_deref(),
+            Some(Path::new("/run/dnstap.sock"))
+        );
+        assert_eq!(config.dnstap.identity.as_deref(), Some("ns1"));
+        assert!(matches!(
+            Config::parse("[dnstap]\nsocket = \"/run/dnstap.sock\"\nfile = \"dns.fstrm\"\n"),
+            Err(ConfigError::Invalid {
+                field: "dnstap",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_metrics_config() {
+        let config = Config::parse("[metrics]\nlisten = \"127.0.0.1:9153\"\n").unwrap();
+        assert_eq!(
+            config.metrics_addr().unwrap(),
+            Some("127.0.0.1:9153".parse().unwrap())
+        );
+        assert_eq!(Config::default().metrics_addr().unwrap(), None);
+        assert!(matches!(
+            Config::parse("[metrics]\nlisten = \"9153\"\n"),
+            Err(ConfigError::Invalid {
+                field: "metrics.listen",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_unknown_field_is_rejected() {
+        assert!(matches!(
+            Config::parse("[server]\nbind = \"0.0.0.0\"\n"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+
+    #[test]
+    fn test_invalid_resolver_is_rejected() {
+        assert!(matches!(
+            Config::parse("[upstream]\nresolver = \"8.8.8.8\"\n"),
+            Err(ConfigError::Invalid {
+                field: "upstream.resolver",
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_log_level_from_str() {
+        assert_eq!(LogLevel::try_from("WARN").unwrap(), LogLevel::Warn);
+        assert!(LogLevel::try_from("verbose").is_err());
+    }
+}
@@ -0,0 +1,135 @@
+use crate::reload::Reloader;
+use log::{error, info, warn};
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{UnixListener, UnixStream};
+
+/// Line based commands accepted on the control socket.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum Command {
+    Reload,
+}
+
+impl FromStr for Command {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "reload" => Ok(Command::Reload),
+            other => Err(format!("unknown command '{}'", other)),
+        }
+    }
+}
+
+/// Serves the control socket at `path`, answering every command with a single
+/// `OK` or `ERR <reason>` line.
+pub(crate) async fn serve(path: PathBuf, reloader: Arc<Reloader>) {
+    if let Err(e) = remove_stale_socket(&path) {
+        error!("Failed to remove stale control socket {:?}: {}", path, e);
+        return;
+    }
+    let listener = match UnixListener::bind(&path) {
+        Ok(listener) => listener,
+        Err(e) => {
+            error!("Failed to bind control socket {:?}: {}", path, e);
+            return;
+        }
+    };
+    info!("Control socket is listening on {:?}", path);
+
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let reloader = reloader.clone();
+                tokio::spawn(async move { handle(stream, reloader).await });
+            }
+            Err(e) => warn!("Failed to accept control connection: {}", e),
+        }
+    }
+}
+
+fn remove_stale_socket(path: &Path) -> std::io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(meta) if meta.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            "path exists and is not a socket",
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+async fn handle(stream: UnixStream, reloader: Arc<Reloader>) {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Ok(Some(line)) = lines.next_line().await {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let reply = match line.parse::<Command>() {
+            Ok(Command::Reload) => match reloader.clone().reload_blocking().await {
+                Ok(()) => "OK".to_string(),
+                Err(e) => {
+                    error!(
+                        "Failed to reload configuration, keeping the current one: {:#}",
+                        e
+                    );
+                    format!("ERR {:#}", e)
+                }
+            },
+            Err(e) => format!("ERR {}", e),
+        };
+        if write
+            .write_all(format!("{}\n", reply).as_bytes())
+            .await
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod control_tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::server::{ServerState, StateHandle};
+    use crate::testutil::temp_path;
+
+    #[test]
+    fn test_parse_command() {
+        assert_eq!("reload\n".parse::<Command>(), Ok(Command::Reload));
+        assert_eq!(" RELOAD ".parse::<Command>(), Ok(Command::Reload));
+        assert!("restart".parse::<Command>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_reload_over_socket() {
+        let path = temp_path("control").with_extension("sock");
+        let config = Config::default();
+        let state = StateHandle::new(ServerState::from_config(&config).unwrap());
+        let reloader = Arc::new(Reloader::new(
+            config,
+            Box::new(|| Ok(Config::parse("[server]\nmiddleware = [\"fallback\"]\n")?)),
+            state.clone(),
+        ));
+        tokio::spawn(serve(path.clone(), reloader));
+
+        let mut stream = loop {
+            match UnixStream::connect(&path).await {
+                Ok(stream) => break stream,
+                Err(_) => tokio::task::yield_now().await,
+            }
+        };
+        stream.write_all(b"bogus\nreload\n").await.unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert!(lines.next_line().await.unwrap().unwrap().starts_with("ERR"));
+        assert_eq!(lines.next_line().await.unwrap().unwrap(), "OK");
+        assert_eq!(state.load().chain.names(), vec!["fallback"]);
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,507 @@
+use crate::config::DnstapConfig;
+use crate::metrics::METRICS;
+use bytes::{BufMut, Bytes, BytesMut};
+use log::{debug, error, info, warn};
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::UnixStream;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+/// Frame Streams content type of dnstap payloads.
+const CONTENT_TYPE: &[u8] = b"protobuf:dnstap.Dnstap";
+
+/// Frame Streams control frame types.
+const CONTROL_ACCEPT: u32 = 0x01;
+const CONTROL_START: u32 = 0x02;
+const CONTROL_STOP: u32 = 0x03;
+const CONTROL_READY: u32 = 0x04;
+const CONTROL_FINISH: u32 = 0x05;
+const FIELD_CONTENT_TYPE: u32 = 0x01;
+
+/// Frames waiting to be written. Frames beyond this are dropped.
+const QUEUE_LEN: usize = 1024;
+/// Pause between attempts to reach the collector's socket.
+const RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// The kinds of dnstap `Message` this server emits.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum MessageType {
+    ClientQuery = 5,
+    ClientResponse = 6,
+    ForwarderQuery = 7,
+    ForwarderResponse = 8,
+}
+
+/// A dnstap `Message`. Queries carry `query_time` and `query_message`,
+/// responses both times and `response_message`.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) struct Message {
+    pub(crate) kind: MessageType,
+    /// Where the query came from: the client, or our socket when forwarding.
+    pub(crate) query_address: Option<SocketAddr>,
+    /// Where the query went: our listener, or the upstream resolver.
+    pub(crate) response_address: Option<SocketAddr>,
+    pub(crate) query_time: SystemTime,
+    pub(crate) response_time: Option<SystemTime>,
+    pub(crate) query_message: Option<Bytes>,
+    pub(crate) response_message: Option<Bytes>,
+}
+
+impl Message {
+    pub(crate) fn query(
+        kind: MessageType,
+        query_address: Option<SocketAddr>,
+        response_address: Option<SocketAddr>,
+        query_message: Bytes,
+    ) -> Self {
+        Self {
+            kind,
+            query_address,
+            response_address,
+            query_time: SystemTime::now(),
+            response_time: None,
+            query_message: Some(query_message),
+            response_message: None,
+        }
+    }
+
+    /// The response to `query`, received or sent just now.
+    pub(crate) fn response(query: &Message, kind: MessageType, response_message: Bytes) -> Self {
+        Self {
+            kind,
+            query_address: query.query_address,
+            response_address: query.response_address,
+            query_time: query.query_time,
+            response_time: Some(SystemTime::now()),
+            query_message: None,
+            response_message: Some(response_message),
+        }
+    }
+
+    /// Encodes the `Message` protobuf.
+    fn encode(&self, dst: &mut BytesMut) {
+        put_uint(dst, 1, self.kind as u64);
+        if let Some(addr) = self.query_address.or(self.response_address) {
+            let family = match addr.ip() {
+                IpAddr::V4(_) => 1,
+                IpAddr::V6(_) => 2,
+            };
+            put_uint(dst, 2, family);
+        }
+        // Only UDP is served so far.
+        put_uint(dst, 3, 1);
+        if let Some(addr) = self.query_address {
+            put_bytes(dst, 4, &ip_octets(addr.ip()));
+            put_uint(dst, 6, addr.port() as u64);
+        }
+        if let Some(addr) = self.response_address {
+            put_bytes(dst, 5, &ip_octets(addr.ip()));
+            put_uint(dst, 7, addr.port() as u64);
+        }
+        let (secs, nanos) = epoch(self.query_time);
+        put_uint(dst, 8, secs);
+        put_fixed32(dst, 9, nanos);
+        if let Some(message) = &self.query_message {
+            put_bytes(dst, 10, message);
+        }
+        if let Some(time) = self.response_time {
+            let (secs, nanos) = epoch(time);
+            put_uint(dst, 12, secs);
+            put_fixed32(dst, 13, nanos);
+        }
+        if let Some(message) = &self.response_message {
+            put_bytes(dst, 14, message);
+        }
+    }
+}
+
+fn ip_octets(ip: IpAddr) -> Vec<u8> {
+    match ip {
+        IpAddr::V4(ip) => ip.octets().to_vec(),
+        IpAddr::V6(ip) => ip.octets().to_vec(),
+    }
+}
+
+fn epoch(time: SystemTime) -> (u64, u32) {
+    let since = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    (since.as_secs(), since.subsec_nanos())
+}
+
+fn put_varint(dst: &mut BytesMut, mut value: u64) {
+    while value >= 0x80 {
+        dst.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    dst.put_u8(value as u8);
+}
+
+fn put_uint(dst: &mut BytesMut, field: u64, value: u64) {
+    put_varint(dst, field << 3);
+    put_varint(dst, value);
+}
+
+fn put_fixed32(dst: &mut BytesMut, field: u64, value: u32) {
+    put_varint(dst, field << 3 | 5);
+    dst.put_u32_le(value);
+}
+
+fn put_bytes(dst: &mut BytesMut, field: u64, value: &[u8]) {
+    put_varint(dst, field << 3 | 2);
+    put_varint(dst, value.len() as u64);
+    dst.put_slice(value);
+}
+
+/// Encodes the outer `Dnstap` protobuf around `message`.
+fn encode(identity: Option<&[u8]>, version: &[u8], message: &Message) -> Bytes {
+    let mut inner = BytesMut::new();
+    message.encode(&mut inner);
+    let mut dst = BytesMut::new();
+    if let Some(identity) = identity {
+        put_bytes(&mut dst, 1, identity);
+    }
+    put_bytes(&mut dst, 2, version);
+    put_bytes(&mut dst, 14, &inner);
+    // Type MESSAGE.
+    put_uint(&mut dst, 15, 1);
+    dst.freeze()
+}
+
+fn control_frame(kind: u32, with_content_type: bool) -> Bytes {
+    let mut payload = BytesMut::new();
+    payload.put_u32(kind);
+    if with_content_type {
+        payload.put_u32(FIELD_CONTENT_TYPE);
+        payload.put_u32(CONTENT_TYPE.len() as u32);
+        payload.put_slice(CONTENT_TYPE);
+    }
+    let mut frame = BytesMut::new();
+    frame.put_u32(0);
+    frame.put_u32(payload.len() as u32);
+    frame.put_slice(&payload);
+    frame.freeze()
+}
+
+fn data_frame(payload: &[u8]) -> Bytes {
+    let mut frame = BytesMut::with_capacity(4 + payload.len());
+    frame.put_u32(payload.len() as u32);
+    frame.put_slice(payload);
+    frame.freeze()
+}
+
+/// Reads a control frame, returning its type.
+async fn read_control<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<u32> {
+    if reader.read_u32().await? != 0 {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "expected a control frame",
+        ));
+    }
+    let len = reader.read_u32().await? as usize;
+    let mut payload = vec![0; len];
+    reader.read_exact(&mut payload).await?;
+    match payload.get(..4) {
+        Some(kind) => Ok(u32::from_be_bytes(kind.try_into().unwrap())),
+        None => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            "empty control frame",
+        )),
+    }
+}
+
+/// Where frames are written.
+#[derive(Debug, PartialEq, Clone)]
+enum Output {
+    /// A Frame Streams file, started from scratch.
+    File(PathBuf),
+    /// A collector listening on a Unix socket, spoken to bidirectionally.
+    Socket(PathBuf),
+}
+
+/// Copies frames from `rx` to `writer` until `done` is cancelled and the
+/// frames already queued are written, then sends STOP.
+async fn pump<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    rx: &mut mpsc::Receiver<Bytes>,
+    done: &CancellationToken,
+) -> std::io::Result<()> {
+    loop {
+        tokio::select! {
+            payload = rx.recv() => match payload {
+                Some(payload) => writer.write_all(&data_frame(&payload)).await?,
+                None => break,
+            },
+            _ = done.cancelled(), if !rx.is_closed() => rx.close(),
+        }
+    }
+    writer
+        .write_all(&control_frame(CONTROL_STOP, false))
+        .await?;
+    writer.flush().await
+}
+
+async fn write_file(
+    path: &Path,
+    rx: &mut mpsc::Receiver<Bytes>,
+    done: &CancellationToken,
+) -> std::io::Result<()> {
+    let mut file = tokio::fs::File::create(path).await?;
+    file.write_all(&control_frame(CONTROL_START, true)).await?;
+    pump(&mut file, rx, done).await
+}
+
+/// Runs the bidirectional handshake, then streams frames.
+async fn write_socket(
+    path: &Path,
+    rx: &mut mpsc::Receiver<Bytes>,
+    done: &CancellationToken,
+) -> std::io::Result<()> {
+    let mut stream = UnixStream::connect(path).await?;
+    stream
+        .write_all(&control_frame(CONTROL_READY, true))
+        .await?;
+    let reply = read_control(&mut stream).await?;
+    if reply != CONTROL_ACCEPT {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("expected ACCEPT, got control frame {}", reply),
+        ));
+    }
+    stream
+        .write_all(&control_frame(CONTROL_START, true))
+        .await?;
+    info!("Streaming dnstap to {:?}", path);
+    pump(&mut stream, rx, done).await?;
+    if read_control(&mut stream).await? != CONTROL_FINISH {
+        warn!("dnstap collector did not FINISH the stream");
+    }
+    Ok(())
+}
+
+/// Writes frames from `rx` to `output` until `done` is cancelled. A collector
+/// that cannot be reached is retried until then.
+async fn run(output: Output, mut rx: mpsc::Receiver<Bytes>, done: CancellationToken) {
+    match output {
+        Output::File(path) => {
+            if let Err(e) = write_file(&path, &mut rx, &done).await {
+                error!("Failed to write dnstap file {:?}: {}", path, e);
+            }
+        }
+        Output::Socket(path) => loop {
+            match write_socket(&path, &mut rx, &done).await {
+                Ok(()) => return,
+                Err(e) => warn!(
+                    "dnstap socket {:?} failed, retrying in {:?}: {}",
+                    path, RECONNECT_DELAY, e
+                ),
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(RECONNECT_DELAY) => {}
+                _ = done.cancelled() => return,
+            }
+        },
+    }
+}
+
+/// Queues dnstap frames for a background writer.
+///
+/// Logging never waits: when the writer falls behind or the collector is
+/// unreachable, frames are dropped and counted.
+pub(crate) struct Dnstap {
+    tx: mpsc::Sender<Bytes>,
+    identity: Option<Bytes>,
+    version: Bytes,
+}
+
+impl Dnstap {
+    fn spawn(
+        output: Output,
+        identity: Option<String>,
+        done: CancellationToken,
+    ) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        let writer = tokio::spawn(run(output, rx, done));
+        let dnstap = Self {
+            tx,
+            identity: identity.map(Bytes::from),
+            version: Bytes::from(format!(
+                "{} {}",
+                env!("CARGO_PKG_NAME"),
+                env!("CARGO_PKG_VERSION")
+            )),
+        };
+        (dnstap, writer)
+    }
+
+    pub(crate) fn log(&self, message: &Message) {
+        let frame = encode(self.identity.as_deref(), &self.version, message);
+        if self.tx.try_send(frame).is_err() {
+            METRICS.dnstap_dropped.inc();
+            debug!("Dropped a dnstap frame");
+        }
+    }
+}
+
+/// The process wide dnstap output, set up once by [`start`].
+static DNSTAP: OnceLock<Dnstap> = OnceLock::new();
+
+/// Starts writing dnstap if `config` asks for it. The writer sends STOP and
+/// finishes once `done` is cancelled. Must be called from within the
+/// runtime; the output cannot be changed afterwards.
+pub(crate) fn start(config: &DnstapConfig, done: CancellationToken) -> Option<JoinHandle<()>> {
+    let output = match (&config.socket, &config.file) {
+        (Some(socket), _) => Output::Socket(socket.clone()),
+        (None, Some(file)) => Output::File(file.clone()),
+        (None, None) => return None,
+    };
+    info!("Writing dnstap to {:?}", output);
+    let (dnstap, writer) = Dnstap::spawn(output, config.identity.clone(), done);
+    let _ = DNSTAP.set(dnstap);
+    Some(writer)
+}
+
+pub(crate) fn enabled() -> bool {
+    DNSTAP.get().is_some()
+}
+
+/// Emits `message` if dnstap is enabled.
+pub(crate) fn log(message: &Message) {
+    if let Some(dnstap) = DNSTAP.get() {
+        dnstap.log(message);
+    }
+}
+
+#[cfg(test)]
+mod dnstap_tests {
+    use super::*;
+    use crate::testutil::temp_path;
+    use tokio::net::UnixListener;
+
+    fn message() -> Message {
+        Message {
+            kind: MessageType::ClientQuery,
+            query_address: Some("192.0.2.1:5353".parse().unwrap()),
+            response_address: Some("192.0.2.53:53".parse().unwrap()),
+            query_time: UNIX_EPOCH + Duration::new(300, 5),
+            response_time: None,
+            query_message: Some(Bytes::from_static(&[0xab, 0xcd])),
+            response_message: None,
+        }
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut dst = BytesMut::new();
+        put_varint(&mut dst, 1);
+        put_varint(&mut dst, 300);
+        put_varint(&mut dst, u64::MAX);
+        assert_eq!(
+            &dst[..],
+            &[1, 0xac, 0x02, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]
+        );
+    }
+
+    #[test]
+    fn test_encode_message() {
+        let mut dst = BytesMut::new();
+        message().encode(&mut dst);
+        #[rustfmt::skip]
+        let expected: &[u8] = &[
+            0x08, 5,                            // type CLIENT_QUERY
+            0x10, 1,                            // socket_family INET
+            0x18, 1,                            // socket_protocol UDP
+            0x22, 4, 192, 0, 2, 1,              // query_address
+            0x30, 0xe9, 0x29,                   // query_port 5353
+            0x2a, 4, 192, 0, 2, 53,             // response_address
+            0x38, 53,                           // response_port
+            0x40, 0xac, 0x02,                   // query_time_sec 300
+            0x4d, 5, 0, 0, 0,                   // query_time_nsec
+            0x52, 2, 0xab, 0xcd,                // query_message
+        ];
+        assert_eq!(&dst[..], expected);
+
+        let frame = encode(Some(b"ns1"), b"v1", &message());
+        assert_eq!(
+            &frame[..9],
+            &[0x0a, 3, b'n', b's', b'1', 0x12, 2, b'v', b'1']
+        );
+        assert_eq!(&frame[9..11], &[0x72, expected.len() as u8]);
+        assert_eq!(&frame[frame.len() - 2..], &[0x78, 1]);
+    }
+
+    #[test]
+    fn test_control_frame() {
+        let frame = control_frame(CONTROL_START, true);
+        assert_eq!(&frame[..4], &[0, 0, 0, 0]);
+        assert_eq!(
+            &frame[4..8],
+            &(12 + CONTENT_TYPE.len() as u32).to_be_bytes()
+        );
+        assert_eq!(&frame[8..16], &[0, 0, 0, 2, 0, 0, 0, 1]);
+        assert_eq!(&frame[20..], CONTENT_TYPE);
+        assert_eq!(
+            &control_frame(CONTROL_STOP, false)[..],
+            &[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 3]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_file_output() {
+        let path = temp_path("dnstap").with_extension("fstrm");
+        let done = CancellationToken::new();
+        let (dnstap, writer) = Dnstap::spawn(Output::File(path.clone()), None, done.clone());
+        dnstap.log(&message());
+        let payload = encode(None, &dnstap.version, &message());
+
+        // The writer stops once it is done, even though the sender is alive.
+        done.cancel();
+        writer.await.unwrap();
+        let content = std::fs::read(&path).unwrap();
+        let start = control_frame(CONTROL_START, true);
+        let data = data_frame(&payload);
+        let stop = control_frame(CONTROL_STOP, false);
+        assert_eq!(content, [&start[..], &data[..], &stop[..]].concat());
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn test_socket_handshake() {
+        let path = temp_path("dnstap").with_extension("sock");
+        let _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let done = CancellationToken::new();
+        let (dnstap, writer) = Dnstap::spawn(
+            Output::Socket(path.clone()),
+            Some("ns1".to_string()),
+            done.clone(),
+        );
+        let payload = encode(Some(b"ns1"), &dnstap.version, &message());
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(read_control(&mut stream).await.unwrap(), CONTROL_READY);
+        stream
+            .write_all(&control_frame(CONTROL_ACCEPT, true))
+            .await
+            .unwrap();
+        assert_eq!(read_control(&mut stream).await.unwrap(), CONTROL_START);
+
+        dnstap.log(&message());
+        let len = stream.read_u32().await.unwrap() as usize;
+        let mut frame = vec![0; len];
+        stream.read_exact(&mut frame).await.unwrap();
+        assert_eq!(frame, payload);
+
+        done.cancel();
+        assert_eq!(read_control(&mut stream).await.unwrap(), CONTROL_STOP);
+        stream
+            .write_all(&control_frame(CONTROL_FINISH, false))
+            .await
+            .unwrap();
+        writer.await.unwrap();
+        let _ = std::fs::remove_file(&path);
+    }
+}
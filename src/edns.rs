@@ -0,0 +1,271 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+
+use crate::view::{DecodeError, RecordRef};
+
+/// TYPE of the OPT pseudo-record.
+pub const OPT_TYPE: u16 = 41;
+/// OPTION-CODE of an Extended DNS Error (RFC 8914).
+pub const EDE_OPTION_CODE: u16 = 15;
+
+/// INFO-CODE of an Extended DNS Error, RFC 8914 section 4.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ExtendedErrorCode {
+    Other,
+    UnsupportedDnskeyAlgorithm,
+    UnsupportedDsDigestType,
+    StaleAnswer,
+    ForgedAnswer,
+    DnssecIndeterminate,
+    DnssecBogus,
+    SignatureExpired,
+    SignatureNotYetValid,
+    DnskeyMissing,
+    RrsigsMissing,
+    NoZoneKeyBitSet,
+    NsecMissing,
+    CachedError,
+    NotReady,
+    Blocked,
+    Censored,
+    Filtered,
+    Prohibited,
+    StaleNxdomainAnswer,
+    NotAuthoritative,
+    NotSupported,
+    NoReachableAuthority,
+    NetworkError,
+    InvalidData,
+    Unknown(u16),
+}
+
+impl From<u16> for ExtendedErrorCode {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => ExtendedErrorCode::Other,
+            1 => ExtendedErrorCode::UnsupportedDnskeyAlgorithm,
+            2 => ExtendedErrorCode::UnsupportedDsDigestType,
+            3 => ExtendedErrorCode::StaleAnswer,
+            4 => ExtendedErrorCode::ForgedAnswer,
+            5 => ExtendedErrorCode::DnssecIndeterminate,
+            6 => ExtendedErrorCode::DnssecBogus,
+            7 => ExtendedErrorCode::SignatureExpired,
+            8 => ExtendedErrorCode::SignatureNotYetValid,
+            9 => ExtendedErrorCode::DnskeyMissing,
+            10 => ExtendedErrorCode::RrsigsMissing,
+            11 => ExtendedErrorCode::NoZoneKeyBitSet,
+            12 => ExtendedErrorCode::NsecMissing,
+            13 => ExtendedErrorCode::CachedError,
+            14 => ExtendedErrorCode::NotReady,
+            15 => ExtendedErrorCode::Blocked,
+            16 => ExtendedErrorCode::Censored,
+            17 => ExtendedErrorCode::Filtered,
+            18 => ExtendedErrorCode::Prohibited,
+            19 => ExtendedErrorCode::StaleNxdomainAnswer,
+            20 => ExtendedErrorCode::NotAuthoritative,
+            21 => ExtendedErrorCode::NotSupported,
+            22 => ExtendedErrorCode::NoReachableAuthority,
+            23 => ExtendedErrorCode::NetworkError,
+            24 => ExtendedErrorCode::InvalidData,
+            _ => ExtendedErrorCode::Unknown(value),
+        }
+    }
+}
+
+impl From<ExtendedErrorCode> for u16 {
+    fn from(value: ExtendedErrorCode) -> Self {
+        match value {
+            ExtendedErrorCode::Other => 0,
+            ExtendedErrorCode::UnsupportedDnskeyAlgorithm => 1,
+            ExtendedErrorCode::UnsupportedDsDigestType => 2,
+            ExtendedErrorCode::StaleAnswer => 3,
+            ExtendedErrorCode::ForgedAnswer => 4,
+            ExtendedErrorCode::DnssecIndeterminate => 5,
+            ExtendedErrorCode::DnssecBogus => 6,
+            ExtendedErrorCode::SignatureExpired => 7,
+            ExtendedErrorCode::SignatureNotYetValid => 8,
+            ExtendedErrorCode::DnskeyMissing => 9,
+            ExtendedErrorCode::RrsigsMissing => 10,
+            ExtendedErrorCode::NoZoneKeyBitSet => 11,
+            ExtendedErrorCode::NsecMissing => 12,
+            ExtendedErrorCode::CachedError => 13,
+            ExtendedErrorCode::NotReady => 14,
+            ExtendedErrorCode::Blocked => 15,
+            ExtendedErrorCode::Censored => 16,
+            ExtendedErrorCode::Filtered => 17,
+            ExtendedErrorCode::Prohibited => 18,
+            ExtendedErrorCode::StaleNxdomainAnswer => 19,
+            ExtendedErrorCode::NotAuthoritative => 20,
+            ExtendedErrorCode::NotSupported => 21,
+            ExtendedErrorCode::NoReachableAuthority => 22,
+            ExtendedErrorCode::NetworkError => 23,
+            ExtendedErrorCode::InvalidData => 24,
+            ExtendedErrorCode::Unknown(value) => value,
+        }
+    }
+}
+
+/// An option carried in the OPT record.
+#[derive(Debug, PartialEq, Clone)]
+pub enum EdnsOption {
+    /// Extended DNS Error: why a response carries the RCODE it does.
+    ExtendedError {
+        code: ExtendedErrorCode,
+        text: String,
+    },
+    Unknown(u16, Bytes),
+}
+
+impl EdnsOption {
+    fn write(&self, dst: &mut BytesMut) {
+        match self {
+            EdnsOption::ExtendedError { code, text } => {
+                dst.put_u16(EDE_OPTION_CODE);
+                dst.put_u16(2 + text.len() as u16);
+                dst.put_u16(u16::from(*code));
+                dst.put_slice(text.as_bytes());
+            }
+            EdnsOption::Unknown(code, data) => {
+                dst.put_u16(*code);
+                dst.put_u16(data.len() as u16);
+                dst.put_slice(data);
+            }
+        }
+    }
+
+    fn read(code: u16, mut data: Bytes) -> Result<Self, DecodeError> {
+        match code {
+            EDE_OPTION_CODE => {
+                if data.len() < 2 {
+                    return Err(DecodeError::InvalidRData(OPT_TYPE));
+                }
+                let code = ExtendedErrorCode::from(data.get_u16());
+                Ok(EdnsOption::ExtendedError {
+                    code,
+                    text: String::from_utf8_lossy(&data).into_owned(),
+                })
+            }
+            _ => Ok(EdnsOption::Unknown(code, data)),
+        }
+    }
+}
+
+/// The EDNS(0) OPT pseudo-record of RFC 6891.
+///
+/// The EXTENDED-RCODE field is not kept here: it is folded into the header's
+/// [`Rcode`](crate::Rcode) when decoding and taken from it when encoding.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Edns {
+    pub udp_payload_size: u16,
+    pub version: u8,
+    /// DNSSEC OK: the requester can handle DNSSEC records.
+    pub dnssec_ok: bool,
+    pub options: Vec<EdnsOption>,
+}
+
+impl Default for Edns {
+    fn default() -> Self {
+        Edns {
+            udp_payload_size: 1232,
+            version: 0,
+            dnssec_ok: false,
+            options: Vec::new(),
+        }
+    }
+}
+
+impl Edns {
+    /// Decodes an OPT record, returning it with its EXTENDED-RCODE.
+    pub fn read(record: &RecordRef<'_>) -> Result<(Self, u8), DecodeError> {
+        let mut data = record.data.clone();
+        let mut options = Vec::new();
+        while data.has_remaining() {
+            if data.remaining() < 4 {
+                return Err(DecodeError::InvalidRData(OPT_TYPE));
+            }
+            let code = data.get_u16();
+            let length = data.get_u16() as usize;
+            if data.remaining() < length {
+                return Err(DecodeError::InvalidRData(OPT_TYPE));
+            }
+            options.push(EdnsOption::read(code, data.split_to(length))?);
+        }
+        let edns = Edns {
+            udp_payload_size: record.class,
+            version: (record.ttl >> 16) as u8,
+            dnssec_ok: record.ttl & 0x8000 != 0,
+            options,
+        };
+        Ok((edns, (record.ttl >> 24) as u8))
+    }
+
+    /// Appends the OPT record to `dst`.
+    pub fn write(&self, extended_rcode: u8, dst: &mut BytesMut) {
+        let mut data = BytesMut::new();
+        for option in &self.options {
+            option.write(&mut data);
+        }
+        dst.put_u8(0);
+        dst.put_u16(OPT_TYPE);
+        dst.put_u16(self.udp_payload_size);
+        dst.put_u8(extended_rcode);
+        dst.put_u8(self.version);
+        dst.put_u16(if self.dnssec_ok { 0x8000 } else { 0 });
+        dst.put_u16(data.len() as u16);
+        dst.put_slice(&data);
+    }
+
+    /// The first Extended DNS Error option, if any.
+    pub fn extended_error(&self) -> Option<(ExtendedErrorCode, &str)> {
+        self.options.iter().find_map(|option| match option {
+            EdnsOption::ExtendedError { code, text } => Some((*code, text.as_str())),
+            _ => None,
+        })
+    }
+}
+
+#[cfg(test)]
+mod edns_tests {
+    use super::*;
+    use crate::view::MessageView;
+
+    #[test]
+    fn test_extended_error_code() {
+        assert_eq!(
+            ExtendedErrorCode::from(22),
+            ExtendedErrorCode::NoReachableAuthority
+        );
+        assert_eq!(u16::from(ExtendedErrorCode::Blocked), 15);
+        assert_eq!(
+            ExtendedErrorCode::from(500),
+            ExtendedErrorCode::Unknown(500)
+        );
+    }
+
+    #[test]
+    fn test_write_and_read() {
+        let edns = Edns {
+            udp_payload_size: 4096,
+            version: 0,
+            dnssec_ok: true,
+            options: vec![
+                EdnsOption::ExtendedError {
+                    code: ExtendedErrorCode::NetworkError,
+                    text: "timed out".to_string(),
+                },
+                EdnsOption::Unknown(10, Bytes::from_static(&[1, 2, 3, 4, 5, 6, 7, 8])),
+            ],
+        };
+        // A message with only an OPT record in the additional section.
+        let mut msg = BytesMut::from(&[0, 1, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 1][..]);
+        edns.write(1, &mut msg);
+        assert_eq!(&msg[12..23], &[0, 0, 41, 0x10, 0, 1, 0, 0x80, 0, 0, 27]);
+
+        let view = MessageView::new(msg.freeze()).unwrap();
+        let record = view.additionals().unwrap().next().unwrap().unwrap();
+        assert_eq!(Edns::read(&record), Ok((edns.clone(), 1)));
+        assert_eq!(
+            edns.extended_error(),
+            Some((ExtendedErrorCode::NetworkError, "timed out"))
+        );
+    }
+}
@@ -0,0 +1,94 @@
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use rust_dns::{Answer, MessageView, Parser, Rcode, UdpPacket};
+use std::net::SocketAddr;
+use tokio_util::codec::Encoder;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) enum Protocol {
+    Udp,
+}
+
+impl Protocol {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Protocol::Udp => "udp",
+        }
+    }
+}
+
+/// Where a request came from.
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub(crate) struct ClientInfo {
+    pub(crate) addr: SocketAddr,
+    pub(crate) protocol: Protocol,
+}
+
+/// A response on its way back to the client.
+#[derive(Debug, Clone)]
+pub(crate) enum Response {
+    /// Built by a layer, encoded when it is sent.
+    Packet(UdpPacket),
+    /// An upstream answer, sent byte for byte apart from the ID.
+    Relayed(MessageView),
+}
+
+impl Response {
+    pub(crate) fn rcode(&self) -> Rcode {
+        match self {
+            Response::Packet(packet) => packet.header.rcode,
+            Response::Relayed(view) => view.rcode(),
+        }
+    }
+
+    /// The answer section. A relayed message is decoded up to its first
+    /// malformed record.
+    pub(crate) fn answers(&self) -> Vec<Answer> {
+        match self {
+            Response::Packet(packet) => packet.answer.clone().unwrap_or_default(),
+            Response::Relayed(view) => view
+                .answers()
+                .into_iter()
+                .flatten()
+                .map_while(|record| record.ok()?.to_answer().ok())
+                .collect(),
+        }
+    }
+
+    /// The bytes to send.
+    pub(crate) fn encode(self) -> std::io::Result<Bytes> {
+        match self {
+            Response::Packet(packet) => {
+                let mut out = BytesMut::new();
+                Parser::new().encode(packet, &mut out)?;
+                Ok(out.freeze())
+            }
+            Response::Relayed(view) => Ok(view.into_bytes()),
+        }
+    }
+
+    /// Decodes the response for a test to look at.
+    #[cfg(test)]
+    pub(crate) fn into_packet(self) -> UdpPacket {
+        match self {
+            Response::Packet(packet) => packet,
+            Response::Relayed(view) => UdpPacket::try_from(view).unwrap(),
+        }
+    }
+}
+
+impl From<UdpPacket> for Response {
+    fn from(packet: UdpPacket) -> Self {
+        Response::Packet(packet)
+    }
+}
+
+/// Produces the response to a single parsed request.
+///
+/// The server only does socket I/O and hands every decoded packet to its
+/// handler, so handlers can be exercised in tests without binding anything.
+#[async_trait]
+pub(crate) trait RequestHandler: Send + Sync {
+    /// Returns the response to send back to `client`, or `None` to send nothing.
+    async fn handle(&self, request: UdpPacket, client: ClientInfo) -> Option<Response>;
+}
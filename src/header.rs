@@ -1,37 +1,196 @@
 use bytes::{Buf, Bytes, BytesMut};
 
+use crate::view::DecodeError;
+
+/// Kind of request carried by a message (OPCODE).
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Opcode {
+    Query,
+    IQuery,
+    Status,
+    Notify,
+    Update,
+    Dso,
+    Unknown(u8),
+}
+
+impl From<u8> for Opcode {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Opcode::Query,
+            1 => Opcode::IQuery,
+            2 => Opcode::Status,
+            4 => Opcode::Notify,
+            5 => Opcode::Update,
+            6 => Opcode::Dso,
+            _ => Opcode::Unknown(value),
+        }
+    }
+}
+
+impl From<Opcode> for u8 {
+    fn from(value: Opcode) -> Self {
+        match value {
+            Opcode::Query => 0,
+            Opcode::IQuery => 1,
+            Opcode::Status => 2,
+            Opcode::Notify => 4,
+            Opcode::Update => 5,
+            Opcode::Dso => 6,
+            Opcode::Unknown(value) => value,
+        }
+    }
+}
+
+/// Response code (RCODE).
+///
+/// With EDNS the code is 12 bits wide: the low 4 bits live in the header and
+/// the high 8 bits in the OPT record. [`Rcode::from_parts`], [`Rcode::low`] and
+/// [`Rcode::high`] convert between the two halves.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Rcode {
+    NoError,
+    FormErr,
+    ServFail,
+    NXDomain,
+    NotImp,
+    Refused,
+    YXDomain,
+    YXRRSet,
+    NXRRSet,
+    NotAuth,
+    NotZone,
+    DSOTypeNI,
+    BadVers,
+    BadKey,
+    BadTime,
+    BadMode,
+    BadName,
+    BadAlg,
+    BadTrunc,
+    BadCookie,
+    Unknown(u16),
+}
+
+impl Rcode {
+    /// Combines the header RCODE with the EXTENDED-RCODE of an OPT record.
+    pub fn from_parts(low: u8, high: u8) -> Self {
+        Rcode::from((high as u16) << 4 | (low & 0b0000_1111) as u16)
+    }
+
+    /// The 4 bits that go into the header.
+    pub fn low(self) -> u8 {
+        (u16::from(self) & 0b0000_1111) as u8
+    }
+
+    /// The 8 bits that go into the EXTENDED-RCODE field of an OPT record.
+    pub fn high(self) -> u8 {
+        (u16::from(self) >> 4) as u8
+    }
+
+    /// Whether the code can only be expressed with EDNS.
+    pub fn is_extended(self) -> bool {
+        self.high() != 0
+    }
+}
+
+impl From<u16> for Rcode {
+    fn from(value: u16) -> Self {
+        match value {
+            0 => Rcode::NoError,
+            1 => Rcode::FormErr,
+            2 => Rcode::ServFail,
+            3 => Rcode::NXDomain,
+            4 => Rcode::NotImp,
+            5 => Rcode::Refused,
+            6 => Rcode::YXDomain,
+            7 => Rcode::YXRRSet,
+            8 => Rcode::NXRRSet,
+            9 => Rcode::NotAuth,
+            10 => Rcode::NotZone,
+            11 => Rcode::DSOTypeNI,
+            16 => Rcode::BadVers,
+            17 => Rcode::BadKey,
+            18 => Rcode::BadTime,
+            19 => Rcode::BadMode,
+            20 => Rcode::BadName,
+            21 => Rcode::BadAlg,
+            22 => Rcode::BadTrunc,
+            23 => Rcode::BadCookie,
+            _ => Rcode::Unknown(value & 0x0fff),
+        }
+    }
+}
+
+impl From<Rcode> for u16 {
+    fn from(value: Rcode) -> Self {
+        match value {
+            Rcode::NoError => 0,
+            Rcode::FormErr => 1,
+            Rcode::ServFail => 2,
+            Rcode::NXDomain => 3,
+            Rcode::NotImp => 4,
+            Rcode::Refused => 5,
+            Rcode::YXDomain => 6,
+            Rcode::YXRRSet => 7,
+            Rcode::NXRRSet => 8,
+            Rcode::NotAuth => 9,
+            Rcode::NotZone => 10,
+            Rcode::DSOTypeNI => 11,
+            Rcode::BadVers => 16,
+            Rcode::BadKey => 17,
+            Rcode::BadTime => 18,
+            Rcode::BadMode => 19,
+            Rcode::BadName => 20,
+            Rcode::BadAlg => 21,
+            Rcode::BadTrunc => 22,
+            Rcode::BadCookie => 23,
+            Rcode::Unknown(value) => value,
+        }
+    }
+}
+
+/// The fixed 12-byte header at the start of every DNS message.
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct Header {
-    pub(crate) id: u16,
-    pub(crate) qdcount: u16,
-    pub(crate) ancount: u16,
-    pub(crate) nscount: u16,
-    pub(crate) arcount: u16,
-    pub(crate) qr: bool,
-    pub(crate) opcode: u8,
-    pub(crate) aa: bool,
-    pub(crate) tc: bool,
-    pub(crate) rd: bool,
-    pub(crate) ra: bool,
-    pub(crate) z: u8,
-    pub(crate) rcode: u8,
+pub struct Header {
+    pub id: u16,
+    pub qdcount: u16,
+    pub ancount: u16,
+    pub nscount: u16,
+    pub arcount: u16,
+    pub qr: bool,
+    pub opcode: Opcode,
+    pub aa: bool,
+    pub tc: bool,
+    pub rd: bool,
+    pub ra: bool,
+    /// The last reserved bit. It must be zero but is kept so that decoding
+    /// and re-encoding a message does not change it.
+    pub z: bool,
+    /// Authentic Data: every record in the answer was validated with DNSSEC.
+    pub ad: bool,
+    /// Checking Disabled: the client does its own DNSSEC validation.
+    pub cd: bool,
+    pub rcode: Rcode,
 }
 
 impl Header {
-    pub(crate) fn new(
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
         id: u16,
         qdcount: u16,
         ancount: u16,
         nscount: u16,
         arcount: u16,
         qr: bool,
-        opcode: u8,
+        opcode: Opcode,
         aa: bool,
         tc: bool,
         rd: bool,
         ra: bool,
-        z: u8,
-        rcode: u8,
+        ad: bool,
+        cd: bool,
+        rcode: Rcode,
     ) -> Self {
         Header {
             id,
@@ -45,55 +204,36 @@ impl Header {
             tc,
             rd,
             ra,
-            z,
+            z: false,
+            ad,
+            cd,
             rcode,
         }
     }
-}
 
-impl From<Header> for Bytes {
-    fn from(value: Header) -> Self {
-        let mut bytes = BytesMut::with_capacity(12);
-        bytes.extend_from_slice(&value.id.to_be_bytes());
-        bytes.extend_from_slice(
-            &((value.qr as u8) << 7
-                | value.opcode << 3
-                | (value.aa as u8) << 2
-                | (value.tc as u8) << 1
-                | (value.rd as u8))
-                .to_be_bytes(),
-        );
-        bytes
-            .extend_from_slice(&((value.ra as u8) << 7 | value.z << 4 | value.rcode).to_be_bytes());
-        bytes.extend_from_slice(&value.qdcount.to_be_bytes());
-        bytes.extend_from_slice(&value.ancount.to_be_bytes());
-        bytes.extend_from_slice(&value.nscount.to_be_bytes());
-        bytes.extend_from_slice(&value.arcount.to_be_bytes());
-        bytes.freeze()
-    }
-}
-
-impl<B: Buf> From<&mut B> for Header {
-    fn from(value: &mut B) -> Self {
+    /// Reads the 12 byte header from the front of `value`.
+    pub fn read<B: Buf>(value: &mut B) -> Result<Self, DecodeError> {
         if value.remaining() < 12 {
-            panic!("Invalid header length");
+            return Err(DecodeError::ShortHeader);
         }
         let id = value.get_u16();
         let flags = value.get_u8();
         let qr = (flags & 0b1000_0000) >> 7 != 0;
-        let opcode = (flags & 0b0111_1000) >> 3;
+        let opcode = Opcode::from((flags & 0b0111_1000) >> 3);
         let aa = (flags & 0b0000_0100) >> 2 != 0;
         let tc = (flags & 0b0000_0010) >> 1 != 0;
         let rd = flags & 0b0000_0001 != 0;
         let more_flags = value.get_u8();
         let ra = (more_flags & 0b1000_0000) >> 7 != 0;
-        let z = (more_flags & 0b0111_0000) >> 4;
-        let rcode = more_flags & 0b0000_1111;
+        let z = (more_flags & 0b0100_0000) >> 6 != 0;
+        let ad = (more_flags & 0b0010_0000) >> 5 != 0;
+        let cd = (more_flags & 0b0001_0000) >> 4 != 0;
+        let rcode = Rcode::from((more_flags & 0b0000_1111) as u16);
         let qdcount = value.get_u16();
         let ancount = value.get_u16();
         let nscount = value.get_u16();
         let arcount = value.get_u16();
-        Header {
+        Ok(Header {
             id,
             qdcount,
             ancount,
@@ -106,8 +246,38 @@ impl<B: Buf> From<&mut B> for Header {
             rd,
             ra,
             z,
+            ad,
+            cd,
             rcode,
-        }
+        })
+    }
+}
+
+impl From<Header> for Bytes {
+    fn from(value: Header) -> Self {
+        let mut bytes = BytesMut::with_capacity(12);
+        bytes.extend_from_slice(&value.id.to_be_bytes());
+        bytes.extend_from_slice(
+            &((value.qr as u8) << 7
+                | (u8::from(value.opcode) & 0b0000_1111) << 3
+                | (value.aa as u8) << 2
+                | (value.tc as u8) << 1
+                | (value.rd as u8))
+                .to_be_bytes(),
+        );
+        bytes.extend_from_slice(
+            &((value.ra as u8) << 7
+                | (value.z as u8) << 6
+                | (value.ad as u8) << 5
+                | (value.cd as u8) << 4
+                | value.rcode.low())
+            .to_be_bytes(),
+        );
+        bytes.extend_from_slice(&value.qdcount.to_be_bytes());
+        bytes.extend_from_slice(&value.ancount.to_be_bytes());
+        bytes.extend_from_slice(&value.nscount.to_be_bytes());
+        bytes.extend_from_slice(&value.arcount.to_be_bytes());
+        bytes.freeze()
     }
 }
 
@@ -126,13 +296,15 @@ mod header_tests {
             nscount: 0,
             arcount: 0,
             qr: true,
-            opcode: 0,
+            opcode: Opcode::Query,
             aa: false,
             tc: false,
             rd: false,
             ra: false,
-            z: 0,
-            rcode: 0,
+            z: false,
+            ad: false,
+            cd: false,
+            rcode: Rcode::NoError,
         };
         let bytes = Bytes::from(header);
         assert_eq!(bytes, Bytes::copy_from_slice(&bytes_sample));
@@ -149,17 +321,115 @@ mod header_tests {
             nscount: 0,
             arcount: 0,
             qr: true,
-            opcode: 0,
+            opcode: Opcode::Query,
             aa: false,
             tc: false,
             rd: false,
             ra: false,
-            z: 0,
-            rcode: 0,
+            z: false,
+            ad: false,
+            cd: false,
+            rcode: Rcode::NoError,
         };
         assert_eq!(
-            Header::from(&mut Bytes::copy_from_slice(&bytes_sample)),
+            Header::read(&mut Bytes::copy_from_slice(&bytes_sample)).unwrap(),
             header_sample
         );
     }
+
+    #[test]
+    fn test_opcode_from_u8() {
+        assert_eq!(Opcode::from(0), Opcode::Query);
+        assert_eq!(Opcode::from(4), Opcode::Notify);
+        assert_eq!(Opcode::from(3), Opcode::Unknown(3));
+        assert_eq!(u8::from(Opcode::Dso), 6);
+        assert_eq!(u8::from(Opcode::Unknown(3)), 3);
+    }
+
+    #[test]
+    fn test_extended_rcode() {
+        assert_eq!(Rcode::from_parts(3, 0), Rcode::NXDomain);
+        assert_eq!(Rcode::from_parts(7, 1), Rcode::BadCookie);
+        assert_eq!(Rcode::BadCookie.low(), 7);
+        assert_eq!(Rcode::BadCookie.high(), 1);
+        assert!(Rcode::BadVers.is_extended());
+        assert!(!Rcode::Refused.is_extended());
+        assert_eq!(Rcode::from(12), Rcode::Unknown(12));
+    }
+
+    #[test]
+    fn test_short_header_is_an_error() {
+        assert_eq!(
+            Header::read(&mut Bytes::from_static(&[0x04, 0xd2, 0x01])),
+            Err(DecodeError::ShortHeader)
+        );
+    }
+
+    #[test]
+    fn test_header_opcode_and_rcode_bits() {
+        let mut header = Header::read(&mut Bytes::copy_from_slice(&[
+            0x04,
+            0xd2,
+            0b0010_0000,
+            0b0000_0011,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]))
+        .unwrap();
+        assert_eq!(header.opcode, Opcode::Notify);
+        assert_eq!(header.rcode, Rcode::NXDomain);
+
+        header.rcode = Rcode::BadCookie;
+        assert_eq!(Bytes::from(header)[3], 0b0000_0111);
+    }
+
+    #[test]
+    fn test_header_ad_and_cd_bits() {
+        let header = Header::read(&mut Bytes::copy_from_slice(&[
+            0x04,
+            0xd2,
+            0x01,
+            0b0011_0000,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]))
+        .unwrap();
+        assert!(header.ad);
+        assert!(header.cd);
+        assert!(!header.z);
+        assert_eq!(header.rcode, Rcode::NoError);
+        assert_eq!(Bytes::from(header)[3], 0b0011_0000);
+
+        let header = Header::read(&mut Bytes::copy_from_slice(&[
+            0x04,
+            0xd2,
+            0x01,
+            0b0100_0000,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ]))
+        .unwrap();
+        assert!(header.z);
+        assert!(!header.ad);
+        assert!(!header.cd);
+        assert_eq!(Bytes::from(header)[3], 0b0100_0000);
+    }
 }
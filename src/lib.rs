@@ -0,0 +1,37 @@
+//! DNS message types and a [`tokio_util::codec`] codec for reading and
+//! writing them.
+//!
+//! The DNS server binary is built on top of this crate, but nothing here
+//! depends on it; the types can be used by any tool that needs to encode or
+//! decode DNS packets.
+//!
+//! ```
+//! use bytes::BytesMut;
+//! use rust_dns::{Message, Parser, Question, UdpPacket};
+//! use tokio_util::codec::{Decoder, Encoder};
+//!
+//! let query: UdpPacket =
+//!     Message::query(1234, Question::new("codecrafters.io".to_string(), 1, 1)).build();
+//!
+//! let mut buf = BytesMut::new();
+//! Parser::new().encode(query.clone(), &mut buf).unwrap();
+//! assert_eq!(Parser::new().decode(&mut buf).unwrap(), Some(query));
+//! ```
+
+pub mod answer;
+pub mod edns;
+pub mod header;
+pub mod message;
+pub mod name;
+pub mod parser;
+pub mod question;
+pub mod view;
+
+pub use answer::{Answer, RData};
+pub use edns::{Edns, EdnsOption, ExtendedErrorCode};
+pub use header::{Header, Opcode, Rcode};
+pub use message::Message;
+pub use name::{DomainName, NameError};
+pub use parser::{Parser, UdpPacket};
+pub use question::{Question, QuestionClass, QuestionType};
+pub use view::{DecodeError, MessageView, NameRef, QuestionRef, RecordRef};
@@ -1,27 +1,69 @@
-mod answer;
-mod header;
-mod parser;
-mod question;
+mod config;
+mod control;
+mod dnstap;
+mod exporter;
+mod handler;
+mod metrics;
+mod middleware;
+mod querylog;
+mod reload;
 mod server;
+mod shutdown;
+#[cfg(test)]
+mod testutil;
 
+use anyhow::Context;
 use clap::Parser as CliParser;
-use log::LevelFilter;
-use server::DnsServer;
+use config::{Config, LogLevel};
+use log::{info, LevelFilter};
+use reload::Reloader;
+use server::{DnsServer, ServerState, StateHandle};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
 
 #[derive(CliParser)]
 #[command(version)]
 struct Cli {
-    #[arg(long, default_value = "127.0.0.1")]
-    addr: String,
+    /// Path to a TOML config file. Flags below override values from the file.
+    #[arg(short, long)]
+    config: Option<PathBuf>,
 
-    #[arg(long, default_value_t = 2053)]
-    port: u16,
+    #[arg(long)]
+    addr: Option<String>,
+
+    #[arg(long)]
+    port: Option<u16>,
 
     #[arg(long)]
     resolver: Option<String>,
 
-    #[arg(short, long, default_value = "info")]
-    loglevel: String,
+    #[arg(short, long)]
+    loglevel: Option<String>,
+}
+
+impl Cli {
+    fn to_config(&self) -> anyhow::Result<Config> {
+        let mut config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::default(),
+        };
+        if let Some(addr) = &self.addr {
+            config.server.addr = addr.clone();
+        }
+        if let Some(port) = self.port {
+            config.server.port = port;
+        }
+        if let Some(resolver) = &self.resolver {
+            config.upstream.resolver = Some(resolver.clone());
+        }
+        if let Some(loglevel) = &self.loglevel {
+            config.log.level = LogLevel::try_from(loglevel.as_str())?;
+        }
+        config.validate()?;
+        Ok(config)
+    }
 }
 
 fn setup_logger(log_level: LevelFilter) -> Result<(), fern::InitError> {
@@ -35,27 +77,49 @@ fn setup_logger(log_level: LevelFilter) -> Result<(), fern::InitError> {
 }
 
 #[tokio::main]
-async fn main() {
-    let args = Cli::parse();
-
-    let log_level = match args.loglevel.to_lowercase().as_str() {
-        "error" => LevelFilter::Error,
-        "warn" => LevelFilter::Warn,
-        "info" => LevelFilter::Info,
-        "debug" => LevelFilter::Debug,
-        "trace" => LevelFilter::Trace,
-        _ => {
-            println!(
-                "Invalid log level '{}', defaulting to 'info'",
-                args.loglevel
-            );
-            LevelFilter::Info
-        }
-    };
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = cli.to_config().context("Invalid configuration")?;
 
-    setup_logger(log_level).unwrap();
+    setup_logger(config.log.level.into())?;
+    // Cancelled once the server has stopped, so that the query log and dnstap
+    // writers get everything from the queries still finishing during shutdown.
+    let outputs_done = CancellationToken::new();
+    let dnstap = dnstap::start(&config.dnstap, outputs_done.clone());
+    let query_log = querylog::start(&config.query_log, outputs_done.clone())?;
 
-    let server = DnsServer::new(args.addr, args.port, args.resolver);
+    let state = StateHandle::new(ServerState::from_config(&config)?);
+    info!(
+        "Middleware chain: {}",
+        state.load().chain.names().join(" -> ")
+    );
+    for group in &state.load().groups {
+        info!("Policy group '{}' covers {:?}", group.name, group.networks);
+    }
+    let server = DnsServer::from_config(&config, Arc::new(state.clone()))?;
 
-    server.run().await
+    let reloader = Arc::new(Reloader::new(
+        config.clone(),
+        Box::new(move || cli.to_config()),
+        state,
+    ));
+    tokio::spawn(reload::reload_on_sighup(reloader.clone()));
+    if let Some(addr) = config.metrics_addr()? {
+        tokio::spawn(exporter::serve(addr));
+    }
+    if let Some(path) = config.control.socket {
+        tokio::spawn(control::serve(path, reloader));
+    }
+
+    let shutdown = CancellationToken::new();
+    tokio::spawn(shutdown::shutdown_on_signal(shutdown.clone()));
+
+    let result = server.run(shutdown).await;
+    outputs_done.cancel();
+    let writers_timeout = Duration::from_secs(config.server.shutdown_timeout);
+    for writer in [dnstap, query_log].into_iter().flatten() {
+        let _ = tokio::time::timeout(writers_timeout, writer).await;
+    }
+    result?;
+    Ok(())
 }
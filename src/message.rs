@@ -0,0 +1,298 @@
+use crate::{
+    answer::Answer,
+    edns::{Edns, EdnsOption, ExtendedErrorCode},
+    header::{Header, Opcode, Rcode},
+    parser::UdpPacket,
+    question::Question,
+};
+
+/// Fluent builder for [`UdpPacket`]s.
+///
+/// Section counts are never set by hand: [`Message::build`] derives them from
+/// the sections that were added, and RDLENGTH is computed when an [`Answer`]
+/// is encoded.
+///
+/// ```
+/// use rust_dns::{Answer, Message, Question};
+///
+/// let query = Message::query(1234, Question::new("codecrafters.io".to_string(), 1, 1)).build();
+/// let response = Message::response_to(&query)
+///     .answer(Answer::new("codecrafters.io".to_string(), 1, 1, 60, "8.8.8.8".to_string()))
+///     .build();
+///
+/// assert_eq!(response.header.id, 1234);
+/// assert_eq!(response.header.ancount, 1);
+/// ```
+#[derive(Debug, PartialEq, Clone)]
+pub struct Message {
+    header: Header,
+    question: Vec<Question>,
+    answer: Vec<Answer>,
+    edns: Option<Edns>,
+}
+
+impl Message {
+    /// Starts a recursive query for `question`.
+    pub fn query(id: u16, question: Question) -> Self {
+        Message {
+            header: Header::new(
+                id,
+                0,
+                0,
+                0,
+                0,
+                false,
+                Opcode::Query,
+                false,
+                false,
+                true,
+                false,
+                false,
+                false,
+                Rcode::NoError,
+            ),
+            question: vec![question],
+            answer: Vec::new(),
+            edns: None,
+        }
+    }
+
+    /// Starts a response to `request`, echoing its id, opcode, RD and CD flags
+    /// and questions. If the request used EDNS the response carries an OPT
+    /// record too, as RFC 6891 asks.
+    pub fn response_to(request: &UdpPacket) -> Self {
+        Message {
+            header: Header::new(
+                request.header.id,
+                0,
+                0,
+                0,
+                0,
+                true,
+                request.header.opcode,
+                false,
+                false,
+                request.header.rd,
+                false,
+                false,
+                request.header.cd,
+                Rcode::NoError,
+            ),
+            question: request.question.clone(),
+            answer: Vec::new(),
+            edns: request.edns.as_ref().map(|_| Edns::default()),
+        }
+    }
+
+    pub fn question(mut self, question: Question) -> Self {
+        self.question.push(question);
+        self
+    }
+
+    /// Drops the questions, e.g. those copied by [`Message::response_to`].
+    pub fn without_question(mut self) -> Self {
+        self.question.clear();
+        self
+    }
+
+    pub fn answer(mut self, record: Answer) -> Self {
+        self.answer.push(record);
+        self
+    }
+
+    pub fn answers(mut self, records: impl IntoIterator<Item = Answer>) -> Self {
+        self.answer.extend(records);
+        self
+    }
+
+    pub fn edns(mut self, edns: Edns) -> Self {
+        self.edns = Some(edns);
+        self
+    }
+
+    /// Explains the RCODE with an Extended DNS Error. It is only added when
+    /// the message carries an OPT record, since clients without EDNS could
+    /// not read it.
+    pub fn extended_error(mut self, code: ExtendedErrorCode, text: impl Into<String>) -> Self {
+        if let Some(edns) = &mut self.edns {
+            edns.options.push(EdnsOption::ExtendedError {
+                code,
+                text: text.into(),
+            });
+        }
+        self
+    }
+
+    pub fn opcode(mut self, opcode: Opcode) -> Self {
+        self.header.opcode = opcode;
+        self
+    }
+
+    pub fn rcode(mut self, rcode: Rcode) -> Self {
+        self.header.rcode = rcode;
+        self
+    }
+
+    pub fn authoritative(mut self, aa: bool) -> Self {
+        self.header.aa = aa;
+        self
+    }
+
+    pub fn truncated(mut self, tc: bool) -> Self {
+        self.header.tc = tc;
+        self
+    }
+
+    pub fn recursion_desired(mut self, rd: bool) -> Self {
+        self.header.rd = rd;
+        self
+    }
+
+    pub fn recursion_available(mut self, ra: bool) -> Self {
+        self.header.ra = ra;
+        self
+    }
+
+    pub fn authentic_data(mut self, ad: bool) -> Self {
+        self.header.ad = ad;
+        self
+    }
+
+    pub fn checking_disabled(mut self, cd: bool) -> Self {
+        self.header.cd = cd;
+        self
+    }
+
+    /// Finishes the message, filling in the header section counts.
+    pub fn build(self) -> UdpPacket {
+        let mut packet = UdpPacket {
+            header: self.header,
+            question: self.question,
+            answer: Some(self.answer),
+            edns: self.edns,
+        };
+        packet.update_counts();
+        packet
+    }
+}
+
+impl From<Message> for UdpPacket {
+    fn from(value: Message) -> Self {
+        value.build()
+    }
+}
+
+#[cfg(test)]
+mod message_tests {
+    use super::*;
+
+    #[test]
+    fn test_query() {
+        let query =
+            Message::query(1234, Question::new("codecrafters.io".to_string(), 1, 1)).build();
+        assert_eq!(
+            query.header,
+            Header::new(
+                1234,
+                1,
+                0,
+                0,
+                0,
+                false,
+                Opcode::Query,
+                false,
+                false,
+                true,
+                false,
+                false,
+                false,
+                Rcode::NoError
+            )
+        );
+        assert_eq!(
+            query.question,
+            vec![Question::new("codecrafters.io".to_string(), 1, 1)]
+        );
+    }
+
+    #[test]
+    fn test_response_to() {
+        let query = Message::query(1234, Question::new("codecrafters.io".to_string(), 1, 1))
+            .recursion_desired(false)
+            .build();
+        let answer = Answer::new(
+            "codecrafters.io".to_string(),
+            1,
+            1,
+            60,
+            "8.8.8.8".to_string(),
+        );
+        let response = Message::response_to(&query)
+            .answer(answer.clone())
+            .answers(vec![answer.clone()])
+            .authoritative(true)
+            .rcode(Rcode::NoError)
+            .build();
+        assert_eq!(
+            response.header,
+            Header::new(
+                1234,
+                1,
+                2,
+                0,
+                0,
+                true,
+                Opcode::Query,
+                true,
+                false,
+                false,
+                false,
+                false,
+                false,
+                Rcode::NoError
+            )
+        );
+        assert_eq!(response.question, query.question);
+        assert_eq!(response.answer, Some(vec![answer.clone(), answer]));
+    }
+
+    #[test]
+    fn test_response_to_copies_checking_disabled() {
+        let query = Message::query(1, Question::new("codecrafters.io".to_string(), 1, 1))
+            .checking_disabled(true)
+            .authentic_data(true)
+            .build();
+        let response = Message::response_to(&query).build();
+        assert!(response.header.cd);
+        assert!(!response.header.ad);
+    }
+
+    #[test]
+    fn test_without_question() {
+        let query = Message::query(1, Question::new("codecrafters.io".to_string(), 1, 1)).build();
+        let response = Message::response_to(&query).without_question().build();
+        assert!(response.question.is_empty());
+        assert_eq!(response.header.qdcount, 0);
+    }
+
+    #[test]
+    fn test_response_to_echoes_edns() {
+        let query = Message::query(1, Question::new("codecrafters.io".to_string(), 1, 1)).build();
+        let response = Message::response_to(&query)
+            .extended_error(ExtendedErrorCode::Blocked, "blocked")
+            .build();
+        assert_eq!(response.edns, None);
+
+        let query = Message::query(1, Question::new("codecrafters.io".to_string(), 1, 1))
+            .edns(Edns::default())
+            .build();
+        let response = Message::response_to(&query)
+            .extended_error(ExtendedErrorCode::Blocked, "blocked")
+            .build();
+        assert_eq!(response.header.arcount, 1);
+        assert_eq!(
+            response.edns.unwrap().extended_error(),
+            Some((ExtendedErrorCode::Blocked, "blocked"))
+        );
+    }
+}
@@ -0,0 +1,431 @@
+use log::trace;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A monotonically increasing counter, exported under `name`.
+#[derive(Debug)]
+pub(crate) struct Counter {
+    name: &'static str,
+    value: AtomicU64,
+}
+
+impl Counter {
+    pub(crate) const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            value: AtomicU64::new(0),
+        }
+    }
+
+    /// Counts one, logging the new total so that the log lines about the
+    /// event itself need not.
+    pub(crate) fn inc(&self) {
+        let value = self.value.fetch_add(1, Ordering::Relaxed) + 1;
+        trace!("{} is now {}", self.name, value);
+    }
+
+    pub(crate) fn get(&self) -> u64 {
+        self.value.load(Ordering::Relaxed)
+    }
+}
+
+/// A value that goes up and down.
+#[derive(Debug, Default)]
+pub(crate) struct Gauge(AtomicI64);
+
+impl Gauge {
+    pub(crate) const fn new() -> Self {
+        Self(AtomicI64::new(0))
+    }
+
+    pub(crate) fn inc(&self) {
+        self.0.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn dec(&self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn get(&self) -> i64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Increments the gauge until the returned guard is dropped, so that it
+    /// comes back down even if the task holding the guard panics.
+    pub(crate) fn track(&self) -> GaugeGuard<'_> {
+        self.inc();
+        GaugeGuard(self)
+    }
+}
+
+/// Decrements its [`Gauge`] when dropped, see [`Gauge::track`].
+pub(crate) struct GaugeGuard<'a>(&'a Gauge);
+
+impl Drop for GaugeGuard<'_> {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
+
+/// Upper bounds in seconds of the latency histogram buckets.
+const LATENCY_BUCKETS: [f64; 12] = [
+    0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0,
+];
+
+/// Durations counted into [`LATENCY_BUCKETS`].
+#[derive(Debug)]
+pub(crate) struct Histogram {
+    /// Observations per bucket, not cumulative. The last one is `+Inf`.
+    buckets: [AtomicU64; LATENCY_BUCKETS.len() + 1],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    pub(crate) const fn new() -> Self {
+        Self {
+            buckets: [const { AtomicU64::new(0) }; LATENCY_BUCKETS.len() + 1],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub(crate) fn observe(&self, duration: Duration) {
+        let secs = duration.as_secs_f64();
+        let bucket = LATENCY_BUCKETS
+            .iter()
+            .position(|bound| secs <= *bound)
+            .unwrap_or(LATENCY_BUCKETS.len());
+        self.buckets[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros
+            .fetch_add(duration.as_micros() as u64, Ordering::Relaxed);
+    }
+}
+
+/// Counters keyed by the values of a fixed set of labels.
+#[derive(Debug)]
+pub(crate) struct LabeledCounter {
+    labels: &'static [&'static str],
+    values: Mutex<BTreeMap<Vec<String>, u64>>,
+}
+
+impl LabeledCounter {
+    pub(crate) const fn new(labels: &'static [&'static str]) -> Self {
+        Self {
+            labels,
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Counts one for `values`, given in the order of the labels.
+    pub(crate) fn inc(&self, values: &[&str]) {
+        let key = values.iter().map(|value| value.to_string()).collect();
+        *self.values.lock().unwrap().entry(key).or_insert(0) += 1;
+    }
+}
+
+/// Process wide counters, see [`METRICS`].
+///
+/// There are no cache hit or miss counters because the server does not cache
+/// answers yet; they belong with the cache when it is added.
+#[derive(Debug)]
+pub(crate) struct Metrics {
+    /// Queries taken up by the server, by question type and protocol.
+    pub(crate) queries: LabeledCounter,
+    /// Responses sent, by response code.
+    pub(crate) responses: LabeledCounter,
+    /// Queries being answered right now.
+    pub(crate) queries_in_flight: Gauge,
+    /// Datagrams on the query socket that are not a DNS message.
+    pub(crate) inbound_malformed: Counter,
+    /// Round trips of queries the upstream resolver answered.
+    pub(crate) upstream_latency: Histogram,
+    /// Datagrams with QR set that arrived on the query socket.
+    pub(crate) inbound_responses_dropped: Counter,
+    /// Forwarded queries answered with SERVFAIL, one counter per reason.
+    pub(crate) forward_socket_errors: Counter,
+    pub(crate) forward_encode_errors: Counter,
+    pub(crate) forward_send_errors: Counter,
+    pub(crate) forward_receive_errors: Counter,
+    pub(crate) forward_timeouts: Counter,
+    pub(crate) forward_malformed_responses: Counter,
+    /// Datagrams on an upstream socket that did not answer the query sent
+    /// on it, and were ignored.
+    pub(crate) forward_mismatched_responses: Counter,
+    /// Queries answered by the blocklist.
+    pub(crate) blocked_queries: Counter,
+    /// Queries from clients an access list keeps out.
+    pub(crate) acl_refused: Counter,
+    pub(crate) acl_dropped: Counter,
+    /// Responses over the rate limit: dropped, sent truncated, or only
+    /// logged in log-only mode.
+    pub(crate) rrl_dropped: Counter,
+    pub(crate) rrl_slipped: Counter,
+    pub(crate) rrl_would_limit: Counter,
+    /// ANY queries answered with less than everything, per RFC 8482.
+    pub(crate) any_queries_minimized: Counter,
+    /// Upstream answers with private addresses for names not allowed them.
+    pub(crate) rebind_filtered: Counter,
+    /// dnstap frames lost because the writer fell behind or was disconnected.
+    pub(crate) dnstap_dropped: Counter,
+    /// Query log lines lost because the writer fell behind.
+    pub(crate) query_log_dropped: Counter,
+}
+
+impl Metrics {
+    pub(crate) const fn new() -> Self {
+        Self {
+            queries: LabeledCounter::new(&["type", "protocol"]),
+            responses: LabeledCounter::new(&["rcode"]),
+            queries_in_flight: Gauge::new(),
+            inbound_malformed: Counter::new("dns_inbound_malformed_total"),
+            upstream_latency: Histogram::new(),
+            inbound_responses_dropped: Counter::new("dns_inbound_responses_dropped_total"),
+            forward_socket_errors: Counter::new("dns_forward_socket_errors_total"),
+            forward_encode_errors: Counter::new("dns_forward_encode_errors_total"),
+            forward_send_errors: Counter::new("dns_forward_send_errors_total"),
+            forward_receive_errors: Counter::new("dns_forward_receive_errors_total"),
+            forward_timeouts: Counter::new("dns_forward_timeouts_total"),
+            forward_malformed_responses: Counter::new("dns_forward_malformed_responses_total"),
+            forward_mismatched_responses: Counter::new("dns_forward_mismatched_responses_total"),
+            blocked_queries: Counter::new("dns_blocked_queries_total"),
+            acl_refused: Counter::new("dns_acl_refused_total"),
+            acl_dropped: Counter::new("dns_acl_dropped_total"),
+            rrl_dropped: Counter::new("dns_rrl_dropped_total"),
+            rrl_slipped: Counter::new("dns_rrl_slipped_total"),
+            rrl_would_limit: Counter::new("dns_rrl_would_limit_total"),
+            any_queries_minimized: Counter::new("dns_any_queries_minimized_total"),
+            rebind_filtered: Counter::new("dns_rebind_filtered_total"),
+            dnstap_dropped: Counter::new("dns_dnstap_dropped_total"),
+            query_log_dropped: Counter::new("dns_query_log_dropped_total"),
+        }
+    }
+}
+
+impl Metrics {
+    /// The plain counters with their help texts.
+    fn counters(&self) -> [(&'static str, &Counter); 19] {
+        [
+            (
+                "Datagrams on the query socket that are not a DNS message.",
+                &self.inbound_malformed,
+            ),
+            (
+                "Datagrams with QR set that arrived on the query socket.",
+                &self.inbound_responses_dropped,
+            ),
+            (
+                "Forwarded queries failed binding a socket.",
+                &self.forward_socket_errors,
+            ),
+            (
+                "Forwarded queries that could not be encoded.",
+                &self.forward_encode_errors,
+            ),
+            (
+                "Forwarded queries failed sending to the upstream resolver.",
+                &self.forward_send_errors,
+            ),
+            (
+                "Forwarded queries failed receiving from the upstream resolver.",
+                &self.forward_receive_errors,
+            ),
+            (
+                "Forwarded queries the upstream resolver did not answer in time.",
+                &self.forward_timeouts,
+            ),
+            (
+                "Malformed responses from the upstream resolver.",
+                &self.forward_malformed_responses,
+            ),
+            (
+                "Upstream datagrams ignored for not answering the query.",
+                &self.forward_mismatched_responses,
+            ),
+            ("Queries answered by the blocklist.", &self.blocked_queries),
+            ("Queries refused by an access list.", &self.acl_refused),
+            ("Queries dropped by an access list.", &self.acl_dropped),
+            ("Responses dropped by the rate limit.", &self.rrl_dropped),
+            (
+                "Responses sent truncated by the rate limit.",
+                &self.rrl_slipped,
+            ),
+            (
+                "Responses the rate limit would withhold outside log-only mode.",
+                &self.rrl_would_limit,
+            ),
+            (
+                "ANY queries answered with less than everything.",
+                &self.any_queries_minimized,
+            ),
+            (
+                "Upstream answers with private addresses for public names.",
+                &self.rebind_filtered,
+            ),
+            (
+                "dnstap frames lost because the writer fell behind.",
+                &self.dnstap_dropped,
+            ),
+            (
+                "Query log lines lost because the writer fell behind.",
+                &self.query_log_dropped,
+            ),
+        ]
+    }
+
+    /// Renders every metric in the Prometheus text exposition format.
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, help, labeled) in [
+            (
+                "dns_queries_total",
+                "Queries received, by question type and protocol.",
+                &self.queries,
+            ),
+            (
+                "dns_responses_total",
+                "Responses sent, by response code.",
+                &self.responses,
+            ),
+        ] {
+            let _ = writeln!(out, "# HELP {} {}\n# TYPE {} counter", name, help, name);
+            for (values, count) in labeled.values.lock().unwrap().iter() {
+                let labels: Vec<String> = labeled
+                    .labels
+                    .iter()
+                    .zip(values)
+                    .map(|(label, value)| format!("{}=\"{}\"", label, escape(value)))
+                    .collect();
+                let _ = writeln!(out, "{}{{{}}} {}", name, labels.join(","), count);
+            }
+        }
+
+        let name = "dns_queries_in_flight";
+        let _ = writeln!(
+            out,
+            "# HELP {} Queries being answered right now.\n# TYPE {} gauge\n{} {}",
+            name,
+            name,
+            name,
+            self.queries_in_flight.get()
+        );
+
+        let name = "dns_upstream_latency_seconds";
+        let _ = writeln!(
+            out,
+            "# HELP {} Round trips of queries the upstream resolver answered.\n# TYPE {} histogram",
+            name, name
+        );
+        let mut cumulative = 0;
+        for (i, bucket) in self.upstream_latency.buckets.iter().enumerate() {
+            cumulative += bucket.load(Ordering::Relaxed);
+            let bound = LATENCY_BUCKETS
+                .get(i)
+                .map_or("+Inf".to_string(), |bound| bound.to_string());
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let sum = self.upstream_latency.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}\n{}_count {}", name, sum, name, cumulative);
+
+        for (help, counter) in self.counters() {
+            let name = counter.name;
+            let _ = writeln!(
+                out,
+                "# HELP {} {}\n# TYPE {} counter\n{} {}",
+                name,
+                help,
+                name,
+                name,
+                counter.get()
+            );
+        }
+        out
+    }
+}
+
+/// Escapes a label value as the text format requires.
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The counters of this process. They survive config reloads.
+pub(crate) static METRICS: Metrics = Metrics::new();
+
+#[cfg(test)]
+mod metrics_tests {
+    use super::*;
+
+    #[test]
+    fn test_counter() {
+        let counter = Counter::new("dns_test_total");
+        counter.inc();
+        counter.inc();
+        assert_eq!(counter.get(), 2);
+    }
+
+    #[test]
+    fn test_gauge_and_histogram() {
+        let gauge = Gauge::new();
+        gauge.inc();
+        gauge.inc();
+        gauge.dec();
+        assert_eq!(gauge.get(), 1);
+        let panicked = std::panic::catch_unwind(|| {
+            let _tracked = gauge.track();
+            assert_eq!(gauge.get(), 2);
+            panic!("handler failed");
+        });
+        assert!(panicked.is_err());
+        assert_eq!(gauge.get(), 1);
+
+        let histogram = Histogram::new();
+        histogram.observe(Duration::from_micros(500));
+        histogram.observe(Duration::from_millis(30));
+        histogram.observe(Duration::from_secs(10));
+        assert_eq!(histogram.buckets[0].load(Ordering::Relaxed), 1);
+        assert_eq!(histogram.buckets[5].load(Ordering::Relaxed), 1);
+        assert_eq!(histogram.buckets[12].load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new();
+        metrics.queries.inc(&["A", "udp"]);
+        metrics.queries.inc(&["A", "udp"]);
+        metrics.queries.inc(&["AAAA", "udp"]);
+        metrics.responses.inc(&["NoError"]);
+        metrics.upstream_latency.observe(Duration::from_millis(20));
+        metrics.upstream_latency.observe(Duration::from_millis(200));
+        metrics.blocked_queries.inc();
+
+        let text = metrics.render();
+        for line in [
+            "# TYPE dns_queries_total counter",
+            "dns_queries_total{type=\"A\",protocol=\"udp\"} 2",
+            "dns_queries_total{type=\"AAAA\",protocol=\"udp\"} 1",
+            "dns_responses_total{rcode=\"NoError\"} 1",
+            "dns_queries_in_flight 0",
+            "# TYPE dns_upstream_latency_seconds histogram",
+            "dns_upstream_latency_seconds_bucket{le=\"0.01\"} 0",
+            "dns_upstream_latency_seconds_bucket{le=\"0.025\"} 1",
+            "dns_upstream_latency_seconds_bucket{le=\"0.25\"} 2",
+            "dns_upstream_latency_seconds_bucket{le=\"+Inf\"} 2",
+            "dns_upstream_latency_seconds_sum 0.22",
+            "dns_upstream_latency_seconds_count 2",
+            "dns_blocked_queries_total 1",
+            "dns_rebind_filtered_total 0",
+        ] {
+            assert!(
+                text.lines().any(|l| l == line),
+                "missing {:?} in\n{}",
+                line,
+                text
+            );
+        }
+        assert_eq!(escape("a\"b\\"), "a\\\"b\\\\");
+    }
+}
@@ -0,0 +1,184 @@
+use crate::config::{AclAction, AclConfig, AclRule};
+use crate::handler::{ClientInfo, Response};
+use crate::metrics::METRICS;
+use ipnet::IpNet;
+use log::debug;
+use rust_dns::{ExtendedErrorCode, Message, Rcode, UdpPacket};
+use std::net::IpAddr;
+
+/// Which clients may use one kind of service.
+///
+/// A client is let in unless a `deny` network contains it, and, when there
+/// are `allow` networks, one of them does. The default lets everyone in.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub(crate) struct Acl {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    action: AclAction,
+    /// What the list guards, for log messages.
+    scope: &'static str,
+}
+
+impl Acl {
+    pub(crate) fn new(rule: &AclRule, action: AclAction, scope: &'static str) -> Self {
+        Self {
+            allow: rule.allow.clone(),
+            deny: rule.deny.clone(),
+            action,
+            scope,
+        }
+    }
+
+    /// The list guarding recursion, which here means forwarding upstream.
+    pub(crate) fn recursion(config: &AclConfig) -> Self {
+        Self::new(&config.recursion, config.action, "recursion")
+    }
+
+    /// The list guarding answers we are authoritative for.
+    pub(crate) fn authoritative(config: &AclConfig) -> Self {
+        Self::new(&config.authoritative, config.action, "authoritative")
+    }
+
+    pub(crate) fn permits(&self, addr: IpAddr) -> bool {
+        let addr = addr.to_canonical();
+        let contains = |nets: &[IpNet]| nets.iter().any(|net| net.contains(&addr));
+        !contains(&self.deny) && (self.allow.is_empty() || contains(&self.allow))
+    }
+
+    /// The answer to a client that is not let in: REFUSED, or nothing at all.
+    pub(crate) fn reject(&self, request: &UdpPacket, client: ClientInfo) -> Option<Response> {
+        reject(self.action, self.scope, request, client)
+    }
+}
+
+/// Which clients may use the server at all: those let in for recursion or
+/// for authoritative answers. [`Chain`](super::Chain) checks this before any
+/// layer runs, whatever the opcode, so a client kept out of both never gets
+/// an answer from layers that do not check an [`Acl`] of their own.
+#[derive(Debug, Default, PartialEq, Clone)]
+pub(crate) struct Access {
+    recursion: Acl,
+    authoritative: Acl,
+    action: AclAction,
+}
+
+impl Access {
+    pub(crate) fn from_config(config: &AclConfig) -> Self {
+        Self {
+            recursion: Acl::recursion(config),
+            authoritative: Acl::authoritative(config),
+            action: config.action,
+        }
+    }
+
+    pub(crate) fn permits(&self, addr: IpAddr) -> bool {
+        self.recursion.permits(addr) || self.authoritative.permits(addr)
+    }
+
+    /// See [`Acl::reject`].
+    pub(crate) fn reject(&self, request: &UdpPacket, client: ClientInfo) -> Option<Response> {
+        reject(self.action, "service", request, client)
+    }
+}
+
+fn reject(
+    action: AclAction,
+    scope: &str,
+    request: &UdpPacket,
+    client: ClientInfo,
+) -> Option<Response> {
+    match action {
+        AclAction::Refuse => {
+            METRICS.acl_refused.inc();
+            debug!("Refusing {} to {}", scope, client.addr);
+            Some(
+                Message::response_to(request)
+                    .rcode(Rcode::Refused)
+                    .extended_error(ExtendedErrorCode::Prohibited, "")
+                    .build()
+                    .into(),
+            )
+        }
+        AclAction::Drop => {
+            METRICS.acl_dropped.inc();
+            debug!("Dropping {} query from {}", scope, client.addr);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod acl_tests {
+    use super::*;
+    use crate::testutil::client_from;
+    use rust_dns::{Edns, Question};
+
+    fn rule(allow: &[&str], deny: &[&str]) -> AclRule {
+        AclRule {
+            allow: allow.iter().map(|net| net.parse().unwrap()).collect(),
+            deny: deny.iter().map(|net| net.parse().unwrap()).collect(),
+        }
+    }
+
+    fn permits(acl: &Acl, addr: &str) -> bool {
+        acl.permits(addr.parse().unwrap())
+    }
+
+    #[test]
+    fn test_permits() {
+        let open = Acl::default();
+        assert!(permits(&open, "203.0.113.1"));
+        assert!(permits(&open, "2001:db8::1"));
+
+        let acl = Acl::new(
+            &rule(&["10.0.0.0/8", "fd00::/8"], &["10.0.66.0/24"]),
+            AclAction::Refuse,
+            "recursion",
+        );
+        assert!(permits(&acl, "10.1.2.3"));
+        assert!(permits(&acl, "::ffff:10.1.2.3"));
+        assert!(permits(&acl, "fd00::1"));
+        assert!(!permits(&acl, "10.0.66.1"));
+        assert!(!permits(&acl, "203.0.113.1"));
+
+        let deny_only = Acl::new(&rule(&[], &["203.0.113.0/24"]), AclAction::Drop, "");
+        assert!(!permits(&deny_only, "203.0.113.1"));
+        assert!(permits(&deny_only, "198.51.100.1"));
+    }
+
+    #[test]
+    fn test_reject() {
+        let request = Message::query(3, Question::new("example.com".to_string(), 1, 1))
+            .edns(Edns::default())
+            .build();
+        let client = client_from("203.0.113.1:5353");
+        let response = Acl::default()
+            .reject(&request, client)
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.id, 3);
+        assert_eq!(response.header.rcode, Rcode::Refused);
+        assert_eq!(
+            response.edns.unwrap().extended_error(),
+            Some((ExtendedErrorCode::Prohibited, ""))
+        );
+
+        let dropped = METRICS.acl_dropped.get();
+        let acl = Acl::new(&AclRule::default(), AclAction::Drop, "recursion");
+        assert!(acl.reject(&request, client).is_none());
+        assert!(METRICS.acl_dropped.get() > dropped);
+    }
+
+    #[test]
+    fn test_access() {
+        let access = Access::from_config(&AclConfig {
+            recursion: rule(&["10.0.0.0/8"], &[]),
+            authoritative: rule(&["192.168.0.0/16"], &[]),
+            action: AclAction::Drop,
+        });
+        assert!(access.permits("10.1.2.3".parse().unwrap()));
+        assert!(access.permits("192.168.1.1".parse().unwrap()));
+        assert!(!access.permits("203.0.113.1".parse().unwrap()));
+        assert!(Access::default().permits("203.0.113.1".parse().unwrap()));
+    }
+}
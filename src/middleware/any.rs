@@ -0,0 +1,210 @@
+use super::{Middleware, Next};
+use crate::config::{AnyConfig, AnyMode, ConfigError};
+use crate::handler::{ClientInfo, Response};
+use crate::metrics::METRICS;
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::debug;
+use rust_dns::{
+    Answer, DomainName, ExtendedErrorCode, Message, QuestionClass, QuestionType, RData, Rcode,
+    UdpPacket,
+};
+use std::collections::HashMap;
+
+/// Keeps ANY queries from being answered with every record there is, as
+/// RFC 8482 recommends, since the large answers make them a favourite for
+/// amplification attacks.
+///
+/// The mode is picked by the closest enclosing name in `any.zones`, and by
+/// `any.forwarded` for names outside all of them.
+pub(crate) struct Any {
+    forwarded: AnyMode,
+    zones: HashMap<DomainName, AnyMode>,
+    ttl: u32,
+}
+
+impl Any {
+    pub(crate) fn from_config(config: &AnyConfig) -> Result<Self, ConfigError> {
+        Ok(Self {
+            forwarded: config.forwarded,
+            zones: config.zones()?,
+            ttl: config.ttl,
+        })
+    }
+
+    fn mode_for(&self, name: &DomainName) -> AnyMode {
+        std::iter::successors(Some(name.clone()), DomainName::parent)
+            .find_map(|name| self.zones.get(&name).copied())
+            .unwrap_or(self.forwarded)
+    }
+
+    /// The single synthesized HINFO record of RFC 8482 section 4.2.
+    fn hinfo(&self, request: &UdpPacket) -> UdpPacket {
+        let answers = request
+            .question
+            .iter()
+            .filter(|question| question.qtype == QuestionType::ANY)
+            .map(|question| Answer {
+                name: question.qname.clone(),
+                typ: QuestionType::HINFO,
+                class: QuestionClass::IN,
+                ttl: self.ttl,
+                data: RData::HINFO {
+                    cpu: Bytes::from_static(b"RFC8482"),
+                    os: Bytes::new(),
+                },
+            });
+        Message::response_to(request).answers(answers).build()
+    }
+
+    /// Asks the rest of the chain for the A records only, answering the
+    /// ANY question with that one RRset. A relayed answer is left as it is
+    /// apart from the question types.
+    async fn rrset(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        let question = request.question.clone();
+        let mut narrowed = request;
+        for question in &mut narrowed.question {
+            if question.qtype == QuestionType::ANY {
+                question.qtype = QuestionType::A;
+            }
+        }
+        match next.run(narrowed, client).await? {
+            Response::Packet(mut packet) => {
+                packet.question = question;
+                Some(packet.into())
+            }
+            Response::Relayed(view) => {
+                match view.with_qtypes(question.iter().map(|question| u16::from(question.qtype))) {
+                    Ok(view) => Some(Response::Relayed(view)),
+                    Err(e) => {
+                        debug!("Dropping the answer to {:?}: {}", question, e);
+                        None
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for Any {
+    fn name(&self) -> &'static str {
+        "any"
+    }
+
+    async fn handle(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        let Some(question) = request
+            .question
+            .iter()
+            .find(|question| question.qtype == QuestionType::ANY)
+        else {
+            return next.run(request, client).await;
+        };
+        let mode = self.mode_for(&question.qname);
+        if mode != AnyMode::Pass {
+            METRICS.any_queries_minimized.inc();
+            debug!(
+                "Answering ANY query for {} in {:?} mode",
+                question.qname, mode
+            );
+        }
+        match mode {
+            AnyMode::Pass => next.run(request, client).await,
+            AnyMode::Hinfo => Some(self.hinfo(&request).into()),
+            AnyMode::Rrset => self.rrset(request, client, next).await,
+            AnyMode::Refuse => Some(
+                Message::response_to(&request)
+                    .rcode(Rcode::Refused)
+                    .extended_error(ExtendedErrorCode::NotSupported, "ANY queries")
+                    .build()
+                    .into(),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod any_tests {
+    use super::*;
+    use crate::middleware::Fallback;
+    use crate::testutil::client;
+    use rust_dns::{Edns, Question};
+    use std::collections::BTreeMap;
+    use std::net::Ipv4Addr;
+    use std::sync::Arc;
+
+    fn any() -> Any {
+        Any::from_config(&AnyConfig {
+            zones: BTreeMap::from([
+                ("lan".to_string(), AnyMode::Rrset),
+                ("guest.lan".to_string(), AnyMode::Refuse),
+                ("legacy.example".to_string(), AnyMode::Pass),
+            ]),
+            ..AnyConfig::default()
+        })
+        .unwrap()
+    }
+
+    async fn ask(name: &str, qtype: u16) -> UdpPacket {
+        let layers: Vec<Arc<dyn Middleware>> = vec![Arc::new(Fallback)];
+        let client = client();
+        let request = Message::query(1, Question::new(name.to_string(), qtype, 1))
+            .edns(Edns::default())
+            .build();
+        any()
+            .handle(request, client, Next { rest: &layers })
+            .await
+            .unwrap()
+            .into_packet()
+    }
+
+    #[test]
+    fn test_mode_for() {
+        let any = any();
+        let mode = |name: &str| any.mode_for(&name.parse().unwrap());
+        assert_eq!(mode("example.com"), AnyMode::Hinfo);
+        assert_eq!(mode("nas.lan"), AnyMode::Rrset);
+        assert_eq!(mode("lan"), AnyMode::Rrset);
+        assert_eq!(mode("phone.guest.LAN"), AnyMode::Refuse);
+        assert_eq!(mode("www.legacy.example"), AnyMode::Pass);
+    }
+
+    #[tokio::test]
+    async fn test_answers() {
+        let response = ask("example.com", 255).await;
+        assert_eq!(response.header.ancount, 1);
+        let answer = &response.answer.unwrap()[0];
+        assert_eq!(answer.typ, QuestionType::HINFO);
+        assert_eq!(answer.ttl, 3600);
+
+        let response = ask("nas.lan", 255).await;
+        assert_eq!(response.question[0].qtype, QuestionType::ANY);
+        assert_eq!(
+            response.answer.unwrap()[0].data,
+            RData::A(Ipv4Addr::new(8, 8, 8, 8))
+        );
+
+        let response = ask("phone.guest.lan", 255).await;
+        assert_eq!(response.header.rcode, Rcode::Refused);
+        assert_eq!(
+            response.edns.unwrap().extended_error(),
+            Some((ExtendedErrorCode::NotSupported, "ANY queries"))
+        );
+
+        // Other types and pass mode go through untouched.
+        let response = ask("example.com", 1).await;
+        assert_eq!(response.answer.unwrap()[0].typ, QuestionType::A);
+        let response = ask("www.legacy.example", 255).await;
+        assert_eq!(response.answer.unwrap()[0].typ, QuestionType::A);
+    }
+}
@@ -0,0 +1,394 @@
+use super::watch::{self, Watched};
+use super::{Middleware, Next};
+use crate::config::{BlockMode, BlocklistConfig, ConfigError};
+use crate::handler::{ClientInfo, Response};
+use crate::metrics::METRICS;
+use async_trait::async_trait;
+use log::debug;
+use rust_dns::{
+    Answer, DomainName, ExtendedErrorCode, Message, QuestionClass, QuestionType, RData, Rcode,
+    UdpPacket,
+};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Names that hosts-format lists map to themselves and must never be blocked.
+const HOSTS_BOILERPLATE: &[&str] = &[
+    "localhost",
+    "localhost.localdomain",
+    "local",
+    "broadcasthost",
+    "ip6-localhost",
+    "ip6-loopback",
+    "0.0.0.0",
+];
+
+/// One parsed line of a list.
+#[derive(Debug, PartialEq)]
+enum Entry {
+    Block(DomainName),
+    /// An adblock exception rule, `@@||domain^`.
+    Allow(DomainName),
+}
+
+/// Parses a line in any of the supported formats: hosts (`0.0.0.0 domain`),
+/// a plain domain per line, or adblock `||domain^` rules. Comments, cosmetic
+/// adblock rules and rules with paths or options yield nothing.
+fn parse_line(line: &str) -> Vec<Entry> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(['#', '!', '[']) || line.contains("##") {
+        return Vec::new();
+    }
+    if let Some(rule) = line.strip_prefix("@@||") {
+        return adblock_domain(rule).map(Entry::Allow).into_iter().collect();
+    }
+    if let Some(rule) = line.strip_prefix("||") {
+        return adblock_domain(rule).map(Entry::Block).into_iter().collect();
+    }
+    let line = line.split('#').next().unwrap_or_default();
+    let mut fields = line.split_whitespace();
+    let Some(first) = fields.next() else {
+        return Vec::new();
+    };
+    if first.parse::<IpAddr>().is_ok() {
+        return fields
+            .filter(|name| !HOSTS_BOILERPLATE.contains(name))
+            .filter_map(domain)
+            .map(Entry::Block)
+            .collect();
+    }
+    if fields.next().is_some() {
+        return Vec::new();
+    }
+    domain(first).map(Entry::Block).into_iter().collect()
+}
+
+/// The domain of an adblock rule with the leading `||` removed. Only rules
+/// that end right after the `^` separator block whole domains.
+fn adblock_domain(rule: &str) -> Option<DomainName> {
+    domain(rule.strip_suffix('^')?)
+}
+
+fn domain(name: &str) -> Option<DomainName> {
+    // Subdomains always match, so a leading wildcard adds nothing.
+    let name = name.strip_prefix("*.").unwrap_or(name);
+    if name.is_empty() || name.contains(['/', '*', '$', '^', '|']) {
+        return None;
+    }
+    name.parse::<DomainName>()
+        .ok()
+        .filter(|name| !name.is_root())
+}
+
+/// Blocked and allowed domains. Both match the listed name and everything
+/// below it, and allowed names win.
+#[derive(Debug, Default)]
+pub(crate) struct BlockRules {
+    blocked: HashSet<DomainName>,
+    allowed: HashSet<DomainName>,
+}
+
+impl BlockRules {
+    /// Loads `lists`, then `allowlists`. Every entry of an allowlist allows
+    /// its domain, whatever syntax it is written in.
+    pub(crate) fn load(lists: &[PathBuf], allowlists: &[PathBuf]) -> Result<Self, ConfigError> {
+        let mut rules = BlockRules::default();
+        for path in lists {
+            rules.add(&watch::read(path)?);
+        }
+        for path in allowlists {
+            rules.allow(&watch::read(path)?);
+        }
+        debug!(
+            "Loaded {} blocked and {} allowed domains",
+            rules.blocked.len(),
+            rules.allowed.len()
+        );
+        Ok(rules)
+    }
+
+    pub(crate) fn add(&mut self, content: &str) {
+        for entry in content.lines().flat_map(parse_line) {
+            match entry {
+                Entry::Block(name) => self.blocked.insert(name),
+                Entry::Allow(name) => self.allowed.insert(name),
+            };
+        }
+    }
+
+    pub(crate) fn allow(&mut self, content: &str) {
+        for entry in content.lines().flat_map(parse_line) {
+            match entry {
+                Entry::Block(name) | Entry::Allow(name) => self.allowed.insert(name),
+            };
+        }
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.blocked.is_empty()
+    }
+
+    pub(crate) fn is_blocked(&self, name: &DomainName) -> bool {
+        let matches = |set: &HashSet<DomainName>| {
+            std::iter::successors(Some(name.clone()), DomainName::parent)
+                .take_while(|name| !name.is_root())
+                .any(|name| set.contains(&name))
+        };
+        matches(&self.blocked) && !matches(&self.allowed)
+    }
+}
+
+/// How blocked names are answered.
+#[derive(Debug, PartialEq, Clone)]
+pub(crate) enum BlockAction {
+    NxDomain,
+    /// Answer A and AAAA queries with these addresses. A missing address
+    /// gives an empty answer for that type.
+    Address {
+        v4: Option<Ipv4Addr>,
+        v6: Option<Ipv6Addr>,
+    },
+}
+
+impl BlockAction {
+    pub(crate) fn from_config(config: &BlocklistConfig) -> Self {
+        match config.mode {
+            BlockMode::Nxdomain => BlockAction::NxDomain,
+            BlockMode::Null => BlockAction::Address {
+                v4: Some(Ipv4Addr::UNSPECIFIED),
+                v6: Some(Ipv6Addr::UNSPECIFIED),
+            },
+            BlockMode::Sinkhole => BlockAction::Address {
+                v4: config.sinkhole_ipv4,
+                v6: config.sinkhole_ipv6,
+            },
+        }
+    }
+
+    /// The response to a blocked `request`, explained with an Extended DNS
+    /// Error when the client speaks EDNS.
+    pub(crate) fn respond(&self, request: &UdpPacket, ttl: u32) -> UdpPacket {
+        METRICS.blocked_queries.inc();
+        debug!("Blocked {:?}", request.question);
+        let response = Message::response_to(request).extended_error(ExtendedErrorCode::Blocked, "");
+        match self {
+            BlockAction::NxDomain => response.rcode(Rcode::NXDomain).build(),
+            BlockAction::Address { v4, v6 } => {
+                let answers = request.question.iter().filter_map(|question| {
+                    let data = match question.qtype {
+                        QuestionType::A => RData::A((*v4)?),
+                        QuestionType::AAAA => RData::AAAA((*v6)?),
+                        _ => return None,
+                    };
+                    Some(Answer {
+                        name: question.qname.clone(),
+                        typ: question.qtype,
+                        class: QuestionClass::IN,
+                        ttl,
+                        data,
+                    })
+                });
+                response.answers(answers).build()
+            }
+        }
+    }
+}
+
+/// Answers queries for blocked domains itself instead of passing them on.
+///
+/// Edits to the lists are picked up within `blocklist.refresh` seconds.
+pub(crate) struct Blocklist {
+    rules: Watched<BlockRules>,
+    action: BlockAction,
+    ttl: u32,
+}
+
+impl Blocklist {
+    pub(crate) fn from_config(config: &BlocklistConfig) -> Result<Self, ConfigError> {
+        let lists = config.lists.len();
+        let files = [config.lists.clone(), config.allowlists.clone()].concat();
+        Ok(Self {
+            rules: Watched::new(
+                files,
+                Duration::from_secs(config.refresh),
+                Box::new(move |files| BlockRules::load(&files[..lists], &files[lists..])),
+            )?,
+            action: BlockAction::from_config(config),
+            ttl: config.ttl,
+        })
+    }
+
+    pub(crate) fn is_blocked(&self, request: &UdpPacket) -> bool {
+        let rules = self.rules.get();
+        !rules.is_empty()
+            && request
+                .question
+                .iter()
+                .any(|question| rules.is_blocked(&question.qname))
+    }
+
+    pub(crate) fn block(&self, request: &UdpPacket) -> UdpPacket {
+        self.action.respond(request, self.ttl)
+    }
+}
+
+#[async_trait]
+impl Middleware for Blocklist {
+    fn name(&self) -> &'static str {
+        "blocklist"
+    }
+
+    async fn handle(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        if self.is_blocked(&request) {
+            return Some(self.block(&request).into());
+        }
+        next.run(request, client).await
+    }
+}
+
+#[cfg(test)]
+mod blocklist_tests {
+    use super::*;
+    use crate::testutil::{client, temp_path};
+    use rust_dns::{Edns, Question};
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    fn request(qname: &str, qtype: u16) -> UdpPacket {
+        Message::query(1, Question::new(qname.to_string(), qtype, 1))
+            .edns(Edns::default())
+            .build()
+    }
+
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            parse_line("0.0.0.0 ads.example.com tracker.example.com # comment"),
+            vec![
+                Entry::Block(name("ads.example.com")),
+                Entry::Block(name("tracker.example.com"))
+            ]
+        );
+        assert_eq!(parse_line("127.0.0.1 localhost"), vec![]);
+        assert_eq!(
+            parse_line("ads.example.com"),
+            vec![Entry::Block(name("ads.example.com"))]
+        );
+        assert_eq!(
+            parse_line("*.ads.example.com"),
+            vec![Entry::Block(name("ads.example.com"))]
+        );
+        assert_eq!(
+            parse_line("||ads.example.com^"),
+            vec![Entry::Block(name("ads.example.com"))]
+        );
+        assert_eq!(
+            parse_line("@@||good.example.com^"),
+            vec![Entry::Allow(name("good.example.com"))]
+        );
+        for ignored in [
+            "# comment",
+            "! adblock comment",
+            "[Adblock Plus 2.0]",
+            "example.com##.banner",
+            "||example.com/ads^",
+            "||example.com^$third-party",
+            "two words",
+            "",
+        ] {
+            assert_eq!(parse_line(ignored), vec![], "{}", ignored);
+        }
+    }
+
+    #[test]
+    fn test_subdomains_match_and_allowlist_wins() {
+        let mut rules = BlockRules::default();
+        rules.add("example.com\n||ads.net^\n@@||cdn.ads.net^\n");
+        rules.allow("good.example.com\n");
+        assert!(rules.is_blocked(&name("example.com")));
+        assert!(rules.is_blocked(&name("WWW.Example.com")));
+        assert!(rules.is_blocked(&name("x.ads.net")));
+        assert!(!rules.is_blocked(&name("cdn.ads.net")));
+        assert!(!rules.is_blocked(&name("img.cdn.ads.net")));
+        assert!(!rules.is_blocked(&name("good.example.com")));
+        assert!(!rules.is_blocked(&name("notexample.com")));
+        assert!(!rules.is_blocked(&name("com")));
+    }
+
+    #[test]
+    fn test_block_actions() {
+        let blocked = METRICS.blocked_queries.get();
+        let response = BlockAction::NxDomain.respond(&request("ads.example.com", 1), 60);
+        assert_eq!(response.header.rcode, Rcode::NXDomain);
+        assert_eq!(response.header.ancount, 0);
+        assert_eq!(
+            response.edns.unwrap().extended_error(),
+            Some((ExtendedErrorCode::Blocked, ""))
+        );
+        assert!(METRICS.blocked_queries.get() > blocked);
+
+        let null = BlockAction::Address {
+            v4: Some(Ipv4Addr::UNSPECIFIED),
+            v6: Some(Ipv6Addr::UNSPECIFIED),
+        };
+        let response = null.respond(&request("ads.example.com", 28), 60);
+        assert_eq!(response.header.rcode, Rcode::NoError);
+        assert_eq!(
+            response.answer.unwrap()[0].data,
+            RData::AAAA(Ipv6Addr::UNSPECIFIED)
+        );
+
+        let sinkhole = BlockAction::Address {
+            v4: Some(Ipv4Addr::new(10, 0, 0, 53)),
+            v6: None,
+        };
+        let response = sinkhole.respond(&request("ads.example.com", 1), 60);
+        assert_eq!(
+            response.answer.unwrap()[0].data,
+            RData::A(Ipv4Addr::new(10, 0, 0, 53))
+        );
+        let response = sinkhole.respond(&request("ads.example.com", 28), 60);
+        assert_eq!(response.header.ancount, 0);
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        let list = temp_path("blocklist");
+        let allow = temp_path("allowlist");
+        std::fs::write(&list, "0.0.0.0 ads.example.com\n").unwrap();
+        std::fs::write(&allow, "ok.ads.example.com\n").unwrap();
+        let blocklist = Blocklist::from_config(&BlocklistConfig {
+            lists: vec![list.clone()],
+            allowlists: vec![allow.clone()],
+            ..BlocklistConfig::default()
+        })
+        .unwrap();
+        let client = client();
+
+        let response = blocklist
+            .handle(request("x.ads.example.com", 1), client, Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.rcode, Rcode::NXDomain);
+
+        // Not blocked, so the end of the chain refuses it.
+        let response = blocklist
+            .handle(request("ok.ads.example.com", 1), client, Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.rcode, Rcode::Refused);
+        let _ = std::fs::remove_file(&list);
+        let _ = std::fs::remove_file(&allow);
+    }
+}
@@ -0,0 +1,38 @@
+use super::{Middleware, Next};
+use crate::handler::{ClientInfo, Response};
+use async_trait::async_trait;
+use rust_dns::{Answer, Message, QuestionClass, QuestionType, RData, UdpPacket};
+use std::net::Ipv4Addr;
+
+/// Answers every question with a fixed A record. It never calls the rest of
+/// the chain, so it belongs at the end.
+pub(crate) struct Fallback;
+
+#[async_trait]
+impl Middleware for Fallback {
+    fn name(&self) -> &'static str {
+        "fallback"
+    }
+
+    async fn handle(
+        &self,
+        request: UdpPacket,
+        _client: ClientInfo,
+        _next: Next<'_>,
+    ) -> Option<Response> {
+        let answers = request.question.iter().map(|q| Answer {
+            name: q.qname.clone(),
+            typ: QuestionType::A,
+            class: QuestionClass::IN,
+            ttl: 3600,
+            data: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
+        });
+
+        Some(
+            Message::response_to(&request)
+                .answers(answers)
+                .build()
+                .into(),
+        )
+    }
+}
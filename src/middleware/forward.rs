@@ -0,0 +1,546 @@
+use super::{Acl, Middleware, Next};
+use crate::dnstap::{self, MessageType};
+use crate::handler::{ClientInfo, Response};
+use crate::metrics::{Counter, METRICS};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
+use log::{debug, warn};
+use rust_dns::{
+    DecodeError, ExtendedErrorCode, Message, MessageView, Parser, QuestionClass, QuestionType,
+    Rcode, UdpPacket,
+};
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use tokio::net::UdpSocket;
+use tokio::time::timeout;
+use tokio_util::codec::Encoder;
+
+/// Largest response accepted from the upstream resolver.
+const MAX_RESPONSE_LEN: usize = 4096;
+
+/// Why a query could not be forwarded. Each reason has its own counter.
+#[derive(Debug, Error)]
+enum ForwardError {
+    #[error("failed to bind a socket: {0}")]
+    Socket(std::io::Error),
+    #[error("failed to encode the query: {0}")]
+    Encode(std::io::Error),
+    #[error("failed to send the query: {0}")]
+    Send(std::io::Error),
+    #[error("failed to receive the response: {0}")]
+    Receive(std::io::Error),
+    #[error("no response within {0:?}")]
+    Timeout(Duration),
+    #[error("malformed response: {0}")]
+    Malformed(#[from] DecodeError),
+}
+
+impl ForwardError {
+    fn counter(&self) -> &'static Counter {
+        match self {
+            ForwardError::Socket(_) => &METRICS.forward_socket_errors,
+            ForwardError::Encode(_) => &METRICS.forward_encode_errors,
+            ForwardError::Send(_) => &METRICS.forward_send_errors,
+            ForwardError::Receive(_) => &METRICS.forward_receive_errors,
+            ForwardError::Timeout(_) => &METRICS.forward_timeouts,
+            ForwardError::Malformed(_) => &METRICS.forward_malformed_responses,
+        }
+    }
+
+    fn extended_error(&self) -> ExtendedErrorCode {
+        match self {
+            ForwardError::Socket(_) | ForwardError::Encode(_) => ExtendedErrorCode::Other,
+            ForwardError::Timeout(_) => ExtendedErrorCode::NoReachableAuthority,
+            _ => ExtendedErrorCode::NetworkError,
+        }
+    }
+}
+
+/// A fresh ID for a query sent upstream, so that an off-path attacker cannot
+/// guess it from the client's. `RandomState` is seeded from the OS and gives
+/// every instance its own keys.
+fn random_id() -> u16 {
+    RandomState::new().build_hasher().finish() as u16
+}
+
+/// Whether `response` answers the query sent upstream: it carries `id` and
+/// repeats the questions of `request`, the names compared ignoring case.
+fn is_answer(response: &Bytes, id: u16, request: &UdpPacket) -> bool {
+    let Ok(view) = MessageView::new(response.clone()) else {
+        return false;
+    };
+    let mut questions = view.questions();
+    view.id() == id
+        && request.question.iter().all(|question| {
+            questions.next().is_some_and(|asked| {
+                asked.is_ok_and(|asked| {
+                    asked.qname == question.qname
+                        && QuestionType::from(asked.qtype) == question.qtype
+                        && QuestionClass::from(asked.qclass) == question.qclass
+                })
+            })
+        })
+        && questions.next().is_none()
+}
+
+/// Forwards queries to the upstream resolver and relays its response byte for
+/// byte, apart from the ID. Without a resolver the query is passed on to the next layer. If
+/// forwarding fails the query is answered with SERVFAIL.
+///
+/// Clients the recursion access list keeps out are never forwarded for.
+pub(crate) struct Forward {
+    upstream: Option<SocketAddr>,
+    timeout: Duration,
+    acl: Acl,
+}
+
+impl Forward {
+    pub(crate) fn new(upstream: Option<SocketAddr>, timeout: Duration, acl: Acl) -> Self {
+        Self {
+            upstream,
+            timeout,
+            acl,
+        }
+    }
+
+    /// Sends `request` upstream under a random ID and returns the response to
+    /// it as it was received. Its sections are walked to check that they are
+    /// well formed, but nothing is decoded.
+    ///
+    /// The socket is connected, so datagrams from anywhere but `upstream` are
+    /// filtered out by the kernel. Datagrams that carry another ID or ask
+    /// another question are ignored until the timeout, so a stray or spoofed
+    /// one cannot cut the exchange short.
+    async fn forward(
+        &self,
+        request: &UdpPacket,
+        upstream: SocketAddr,
+    ) -> Result<MessageView, ForwardError> {
+        let local = if upstream.is_ipv6() {
+            "[::]:0"
+        } else {
+            "0.0.0.0:0"
+        };
+        let resolver_udp_socket = UdpSocket::bind(local).await.map_err(ForwardError::Socket)?;
+        resolver_udp_socket
+            .connect(upstream)
+            .await
+            .map_err(ForwardError::Socket)?;
+        debug!(
+            "Forwarding {:?} packet to the upstream server {}",
+            request, upstream
+        );
+        let id = random_id();
+        let mut query = request.clone();
+        query.header.id = id;
+        let mut out = BytesMut::new();
+        Parser::new()
+            .encode(query, &mut out)
+            .map_err(ForwardError::Encode)?;
+
+        let out = out.freeze();
+        let query = dnstap::enabled().then(|| {
+            let query = dnstap::Message::query(
+                MessageType::ForwarderQuery,
+                resolver_udp_socket.local_addr().ok(),
+                Some(upstream),
+                out.clone(),
+            );
+            dnstap::log(&query);
+            query
+        });
+
+        let sent = Instant::now();
+        let exchange = async {
+            resolver_udp_socket
+                .send(&out)
+                .await
+                .map_err(ForwardError::Send)?;
+            loop {
+                let mut buf = BytesMut::zeroed(MAX_RESPONSE_LEN);
+                let len = resolver_udp_socket
+                    .recv(&mut buf)
+                    .await
+                    .map_err(ForwardError::Receive)?;
+                buf.truncate(len);
+                let buf = buf.freeze();
+                if is_answer(&buf, id, request) {
+                    return Ok::<_, ForwardError>(buf);
+                }
+                METRICS.forward_mismatched_responses.inc();
+                debug!(
+                    "Ignoring a {} byte datagram from {} that does not answer query {}",
+                    len, upstream, id
+                );
+            }
+        };
+        let buf = timeout(self.timeout, exchange)
+            .await
+            .map_err(|_| ForwardError::Timeout(self.timeout))??;
+        METRICS.upstream_latency.observe(sent.elapsed());
+        debug!(
+            "Received {} byte response from the upstream server {}",
+            buf.len(),
+            upstream
+        );
+
+        if let Some(query) = &query {
+            dnstap::log(&dnstap::Message::response(
+                query,
+                MessageType::ForwarderResponse,
+                buf.clone(),
+            ));
+        }
+        let response = MessageView::new(buf)?;
+        // Walking to the end of the last section checks the others on the way.
+        for record in response.additionals()? {
+            record?;
+        }
+        Ok(response)
+    }
+
+    /// Counts the failure and builds the SERVFAIL for it, explained with an
+    /// Extended DNS Error when the client speaks EDNS.
+    fn servfail(request: &UdpPacket, upstream: SocketAddr, error: ForwardError) -> UdpPacket {
+        error.counter().inc();
+        warn!(
+            "Forwarding to {} failed, answering SERVFAIL: {}",
+            upstream, error
+        );
+        Message::response_to(request)
+            .rcode(Rcode::ServFail)
+            .extended_error(
+                error.extended_error(),
+                format!("upstream {}: {}", upstream, error),
+            )
+            .build()
+    }
+}
+
+#[async_trait]
+impl Middleware for Forward {
+    fn name(&self) -> &'static str {
+        "forward"
+    }
+
+    async fn handle(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        let Some(upstream) = self.upstream else {
+            return next.run(request, client).await;
+        };
+        if !self.acl.permits(client.addr.ip()) {
+            return self.acl.reject(&request, client);
+        }
+        match self.forward(&request, upstream).await {
+            Ok(response) => Some(Response::Relayed(response.with_id(request.header.id))),
+            Err(e) => Some(Self::servfail(&request, upstream, e).into()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod forward_tests {
+    use super::*;
+    use crate::config::{AclAction, AclRule};
+    use crate::testutil::client;
+    use rust_dns::{Answer, Edns, Question, RData};
+    use tokio_util::codec::Decoder;
+
+    fn request() -> UdpPacket {
+        Message::query(7, Question::new("codecrafters.io".to_string(), 1, 1))
+            .edns(Edns::default())
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_forward_propagates_dnssec_flags() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let request = Parser::new()
+                .decode(&mut BytesMut::from(&buf[..len]))
+                .unwrap()
+                .unwrap();
+            assert!(request.header.cd);
+            let response = Message::response_to(&request)
+                .authentic_data(true)
+                .answer(Answer::new(
+                    "codecrafters.io".to_string(),
+                    1,
+                    1,
+                    60,
+                    "1.2.3.4".to_string(),
+                ))
+                .build();
+            let mut out = BytesMut::new();
+            Parser::new().encode(response, &mut out).unwrap();
+            upstream.send_to(&out, from).await.unwrap();
+        });
+
+        let request = Message::query(7, Question::new("codecrafters.io".to_string(), 1, 1))
+            .checking_disabled(true)
+            .build();
+        let client = client();
+        let response = Forward::new(Some(upstream_addr), Duration::from_secs(2), Acl::default())
+            .handle(request, client, Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert!(response.header.ad);
+        assert!(response.header.cd);
+        assert_eq!(response.header.ancount, 1);
+    }
+
+    #[tokio::test]
+    async fn test_timeout_answers_servfail() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let timeouts = METRICS.forward_timeouts.get();
+        let response = Forward::new(
+            Some(upstream.local_addr().unwrap()),
+            Duration::from_millis(50),
+            Acl::default(),
+        )
+        .handle(request(), client(), Next { rest: &[] })
+        .await
+        .unwrap()
+        .into_packet();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.rcode, Rcode::ServFail);
+        assert_eq!(response.header.ancount, 0);
+        let (code, _) = response.edns.as_ref().unwrap().extended_error().unwrap();
+        assert_eq!(code, ExtendedErrorCode::NoReachableAuthority);
+        assert!(METRICS.forward_timeouts.get() > timeouts);
+    }
+
+    #[tokio::test]
+    async fn test_malformed_response_answers_servfail() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            // Repeats the question and claims one answer, but ends right
+            // after the question.
+            buf[2] = 0x81;
+            buf[3] = 0x80;
+            buf[7] = 1;
+            upstream.send_to(&buf[..len], from).await.unwrap();
+        });
+
+        let malformed = METRICS.forward_malformed_responses.get();
+        let mut request = request();
+        request.edns = None;
+        let response = Forward::new(Some(upstream_addr), Duration::from_secs(2), Acl::default())
+            .handle(request, client(), Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.rcode, Rcode::ServFail);
+        assert_eq!(response.header.qdcount, 1);
+        // Without EDNS in the query there is nowhere to put the extended error.
+        assert_eq!(response.edns, None);
+        assert_eq!(response.header.arcount, 0);
+        assert!(METRICS.forward_malformed_responses.get() > malformed);
+    }
+
+    #[tokio::test]
+    async fn test_mismatched_responses_are_ignored() {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let request = Parser::new()
+                .decode(&mut BytesMut::from(&buf[..len]))
+                .unwrap()
+                .unwrap();
+            let mut out = BytesMut::new();
+            let mut spoofed = Message::response_to(&request).rcode(Rcode::Refused).build();
+            spoofed.header.id = request.header.id.wrapping_add(1);
+            Parser::new().encode(spoofed, &mut out).unwrap();
+            upstream.send_to(&out.split(), from).await.unwrap();
+            for question in [
+                Question::new("example.com".to_string(), 1, 1),
+                Question::new("codecrafters.io".to_string(), 28, 1),
+                Question::new("codecrafters.io".to_string(), 1, 3),
+            ] {
+                let spoofed = Message::response_to(&request)
+                    .without_question()
+                    .question(question)
+                    .rcode(Rcode::Refused)
+                    .build();
+                Parser::new().encode(spoofed, &mut out).unwrap();
+                upstream.send_to(&out.split(), from).await.unwrap();
+            }
+            // The name may come back in another case. An empty answer is
+            // passed back as it is.
+            let response = Message::response_to(&request)
+                .without_question()
+                .question(Question::new("CodeCrafters.IO".to_string(), 1, 1))
+                .rcode(Rcode::NXDomain)
+                .build();
+            Parser::new().encode(response, &mut out).unwrap();
+            upstream.send_to(&out, from).await.unwrap();
+        });
+
+        let mismatched = METRICS.forward_mismatched_responses.get();
+        let response = Forward::new(Some(upstream_addr), Duration::from_secs(2), Acl::default())
+            .handle(request(), client(), Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.rcode, Rcode::NXDomain);
+        assert_eq!(response.header.ancount, 0);
+        assert!(METRICS.forward_mismatched_responses.get() >= mismatched + 4);
+    }
+
+    #[tokio::test]
+    async fn test_forward_to_ipv6_upstream() {
+        let upstream = UdpSocket::bind("[::1]:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let request = Parser::new()
+                .decode(&mut BytesMut::from(&buf[..len]))
+                .unwrap()
+                .unwrap();
+            let mut out = BytesMut::new();
+            let response = Message::response_to(&request).build();
+            Parser::new().encode(response, &mut out).unwrap();
+            upstream.send_to(&out, from).await.unwrap();
+        });
+
+        let response = Forward::new(Some(upstream_addr), Duration::from_secs(2), Acl::default())
+            .handle(request(), client(), Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.id, 7);
+        assert_eq!(response.header.rcode, Rcode::NoError);
+    }
+
+    /// Answers the first query with its own header and question, the given
+    /// flags and counts, and `records` appended as they are. Returns the
+    /// bytes that were sent and the bytes the client got back.
+    async fn relay(qtype: u16, flags: u16, counts: [u16; 3], records: &[u8]) -> (Bytes, Bytes) {
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let upstream_addr = upstream.local_addr().unwrap();
+        let records = records.to_vec();
+        let sent = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, from) = upstream.recv_from(&mut buf).await.unwrap();
+            let mut end = 12;
+            while buf[end] != 0 {
+                end += buf[end] as usize + 1;
+            }
+            end += 5;
+            assert!(end <= len);
+            let mut out = BytesMut::from(&buf[..end]);
+            out[2..4].copy_from_slice(&flags.to_be_bytes());
+            for (i, count) in counts.iter().enumerate() {
+                out[6 + 2 * i..8 + 2 * i].copy_from_slice(&count.to_be_bytes());
+            }
+            out.extend_from_slice(&records);
+            upstream.send_to(&out, from).await.unwrap();
+            out.freeze()
+        });
+
+        let request = Message::query(7, Question::new("codecrafters.io".to_string(), qtype, 1))
+            .edns(Edns::default())
+            .build();
+        let received = Forward::new(Some(upstream_addr), Duration::from_secs(2), Acl::default())
+            .handle(request, client(), Next { rest: &[] })
+            .await
+            .unwrap()
+            .encode()
+            .unwrap();
+        (sent.await.unwrap(), received)
+    }
+
+    #[tokio::test]
+    async fn test_nxdomain_authority_is_relayed_intact() {
+        #[rustfmt::skip]
+        let soa = [
+            0xc0, 0x0c, 0, 6, 0, 1, 0, 0, 0x0e, 0x10, 0, 33,
+            3, b'n', b's', b'1', 0xc0, 0x0c,
+            4, b'h', b'o', b's', b't', 0xc0, 0x0c,
+            0, 0, 0, 1, 0, 0, 0x1c, 0x20, 0, 0, 0x0e, 0x10, 0, 0x09, 0x3a, 0x80, 0, 0, 0x01, 0x2c,
+        ];
+        let (sent, received) = relay(1, 0x8183, [0, 1, 0], &soa).await;
+        assert_eq!(&received[..2], &7u16.to_be_bytes());
+        assert_eq!(received[2..], sent[2..]);
+
+        let response = MessageView::new(received).unwrap();
+        assert_eq!(response.rcode(), Rcode::NXDomain);
+        let soa = response.authorities().unwrap().next().unwrap().unwrap();
+        assert_eq!(
+            soa.rdata().unwrap(),
+            RData::SOA {
+                mname: "ns1.codecrafters.io".parse().unwrap(),
+                rname: "host.codecrafters.io".parse().unwrap(),
+                serial: 1,
+                refresh: 7200,
+                retry: 3600,
+                expire: 604800,
+                minimum: 300,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_compressed_mx_is_relayed_intact() {
+        #[rustfmt::skip]
+        let mx = [
+            0xc0, 0x0c, 0, 15, 0, 1, 0, 0, 0x01, 0x2c, 0, 9,
+            0, 10, 4, b'm', b'a', b'i', b'l', 0xc0, 0x0c,
+        ];
+        let (sent, received) = relay(15, 0x8180, [1, 0, 0], &mx).await;
+        assert_eq!(&received[..2], &7u16.to_be_bytes());
+        assert_eq!(received[2..], sent[2..]);
+
+        let response = MessageView::new(received).unwrap();
+        let mx = response.answers().unwrap().next().unwrap().unwrap();
+        assert_eq!(
+            mx.rdata().unwrap(),
+            RData::MX {
+                preference: 10,
+                exchange: "mail.codecrafters.io".parse().unwrap(),
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn test_acl_refuses_before_forwarding() {
+        // Nothing listens here, so forwarding would time out.
+        let upstream = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let acl = Acl::new(
+            &AclRule {
+                allow: vec!["10.0.0.0/8".parse().unwrap()],
+                deny: Vec::new(),
+            },
+            AclAction::Refuse,
+            "recursion",
+        );
+        let response = Forward::new(
+            Some(upstream.local_addr().unwrap()),
+            Duration::from_secs(5),
+            acl,
+        )
+        .handle(request(), client(), Next { rest: &[] })
+        .await
+        .unwrap()
+        .into_packet();
+        assert_eq!(response.header.rcode, Rcode::Refused);
+    }
+}
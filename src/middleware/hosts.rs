@@ -0,0 +1,290 @@
+use super::watch::{self, Watched};
+use super::{Acl, Middleware, Next};
+use crate::config::{ConfigError, HostsConfig};
+use crate::handler::{ClientInfo, Response};
+use async_trait::async_trait;
+use log::{debug, warn};
+use rust_dns::{
+    Answer, DomainName, Message, Question, QuestionClass, QuestionType, RData, UdpPacket,
+};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Names and addresses read from `/etc/hosts`-style files.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct HostsTable {
+    addrs: HashMap<DomainName, Vec<IpAddr>>,
+    /// Reverse names such as `1.0.0.127.in-addr.arpa` to the canonical name
+    /// of the first line that listed the address.
+    names: HashMap<DomainName, DomainName>,
+}
+
+impl HostsTable {
+    pub(crate) fn load(files: &[PathBuf]) -> Result<Self, ConfigError> {
+        let mut table = HostsTable::default();
+        for path in files {
+            table.parse(&watch::read(path)?, path);
+        }
+        Ok(table)
+    }
+
+    /// Adds every line of `content`: an address followed by a canonical name
+    /// and any number of aliases. Comments start with `#`; lines that cannot
+    /// be parsed are skipped with a warning.
+    pub(crate) fn parse(&mut self, content: &str, source: &Path) {
+        for (number, line) in content.lines().enumerate() {
+            let line = line.split('#').next().unwrap_or_default();
+            let mut fields = line.split_whitespace();
+            let Some(addr) = fields.next() else {
+                continue;
+            };
+            let Ok(addr) = addr.parse::<IpAddr>() else {
+                warn!("{:?}:{}: invalid address '{}'", source, number + 1, addr);
+                continue;
+            };
+            let mut canonical = None;
+            for name in fields {
+                let name = match name.parse::<DomainName>() {
+                    Ok(name) if !name.is_root() => name,
+                    _ => {
+                        warn!("{:?}:{}: invalid name '{}'", source, number + 1, name);
+                        continue;
+                    }
+                };
+                let addrs = self.addrs.entry(name.clone()).or_default();
+                if !addrs.contains(&addr) {
+                    addrs.push(addr);
+                }
+                canonical.get_or_insert(name);
+            }
+            if let Some(canonical) = canonical {
+                self.names
+                    .entry(DomainName::reverse(addr))
+                    .or_insert(canonical);
+            }
+        }
+    }
+
+    /// The records answering `question`, or `None` if the name is not in
+    /// the table. A known name without records of the asked type gets an
+    /// empty answer.
+    pub(crate) fn lookup(&self, question: &Question) -> Option<Vec<RData>> {
+        if question.qclass != QuestionClass::IN {
+            return None;
+        }
+        if let Some(name) = self.names.get(&question.qname) {
+            return Some(match question.qtype {
+                QuestionType::PTR => vec![RData::PTR(name.clone())],
+                _ => Vec::new(),
+            });
+        }
+        let addrs = self.addrs.get(&question.qname)?;
+        Some(
+            addrs
+                .iter()
+                .filter_map(|addr| match (question.qtype, addr) {
+                    (QuestionType::A, IpAddr::V4(ip)) => Some(RData::A(*ip)),
+                    (QuestionType::AAAA, IpAddr::V6(ip)) => Some(RData::AAAA(*ip)),
+                    _ => None,
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Answers from hosts files before anything else gets a chance to. Names
+/// that are not in the files are passed on to the next layer.
+///
+/// Edits to the files are picked up within `hosts.refresh` seconds. Clients
+/// the authoritative access list keeps out get no answers from the files.
+pub(crate) struct Hosts {
+    table: Watched<HostsTable>,
+    ttl: u32,
+    acl: Acl,
+}
+
+impl Hosts {
+    pub(crate) fn from_config(config: &HostsConfig, acl: Acl) -> Result<Self, ConfigError> {
+        Ok(Self {
+            table: Watched::new(
+                config.files.clone(),
+                Duration::from_secs(config.refresh),
+                Box::new(HostsTable::load),
+            )?,
+            ttl: config.ttl,
+            acl,
+        })
+    }
+}
+
+#[async_trait]
+impl Middleware for Hosts {
+    fn name(&self) -> &'static str {
+        "hosts"
+    }
+
+    async fn handle(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        if self.table.files().is_empty() {
+            return next.run(request, client).await;
+        }
+        let table = self.table.get();
+        let mut answers = Vec::new();
+        for question in &request.question {
+            let Some(records) = table.lookup(question) else {
+                return next.run(request, client).await;
+            };
+            answers.extend(records.into_iter().map(|data| Answer {
+                name: question.qname.clone(),
+                typ: question.qtype,
+                class: QuestionClass::IN,
+                ttl: self.ttl,
+                data,
+            }));
+        }
+        if !self.acl.permits(client.addr.ip()) {
+            return self.acl.reject(&request, client);
+        }
+        debug!("Answering {:?} from hosts files", request.question);
+        Some(
+            Message::response_to(&request)
+                .authoritative(true)
+                .answers(answers)
+                .build()
+                .into(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod hosts_tests {
+    use super::*;
+    use crate::testutil::{client, temp_path};
+    use rust_dns::Rcode;
+    use std::net::{Ipv4Addr, Ipv6Addr};
+
+    const HOSTS: &str = "\
+        # comment\n\
+        127.0.0.1   localhost\n\
+        192.168.1.10  nas.lan nas   # trailing comment\n\
+        192.168.1.11  printer.lan\n\
+        192.168.1.11  printer-alias.lan\n\
+        fd00::10      nas.lan\n\
+        not-an-ip     broken.lan\n";
+
+    fn table() -> HostsTable {
+        let mut table = HostsTable::default();
+        table.parse(HOSTS, Path::new("hosts"));
+        table
+    }
+
+    fn question(name: &str, qtype: u16) -> Question {
+        Question::new(name.to_string(), qtype, 1)
+    }
+
+    #[test]
+    fn test_lookup() {
+        let table = table();
+        assert_eq!(
+            table.lookup(&question("NAS.lan", 1)),
+            Some(vec![RData::A(Ipv4Addr::new(192, 168, 1, 10))])
+        );
+        assert_eq!(
+            table.lookup(&question("nas", 1)),
+            Some(vec![RData::A(Ipv4Addr::new(192, 168, 1, 10))])
+        );
+        assert_eq!(
+            table.lookup(&question("nas.lan", 28)),
+            Some(vec![RData::AAAA("fd00::10".parse::<Ipv6Addr>().unwrap())])
+        );
+        assert_eq!(table.lookup(&question("printer.lan", 28)), Some(vec![]));
+        assert_eq!(table.lookup(&question("broken.lan", 1)), None);
+        assert_eq!(table.lookup(&question("example.com", 1)), None);
+    }
+
+    #[test]
+    fn test_lookup_ptr() {
+        let table = table();
+        assert_eq!(
+            table.lookup(&question("10.1.168.192.in-addr.arpa", 12)),
+            Some(vec![RData::PTR("nas.lan".parse().unwrap())])
+        );
+        // The first line listing an address wins.
+        assert_eq!(
+            table.lookup(&question("11.1.168.192.in-addr.arpa", 12)),
+            Some(vec![RData::PTR("printer.lan".parse().unwrap())])
+        );
+        let v6 = DomainName::reverse("fd00::10".parse().unwrap());
+        assert_eq!(
+            table.lookup(&question(&v6.to_string(), 12)),
+            Some(vec![RData::PTR("nas.lan".parse().unwrap())])
+        );
+    }
+
+    #[tokio::test]
+    async fn test_answers_known_names_and_passes_on_others() {
+        let path = temp_path("hosts");
+        std::fs::write(&path, HOSTS).unwrap();
+        let hosts = Hosts::from_config(
+            &HostsConfig {
+                files: vec![path.clone()],
+                ttl: 60,
+                refresh: 0,
+            },
+            Acl::default(),
+        )
+        .unwrap();
+
+        let request = Message::query(1, question("nas.lan", 1)).build();
+        let response = hosts
+            .handle(request, client(), Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert!(response.header.aa);
+        assert_eq!(response.header.rcode, Rcode::NoError);
+        assert_eq!(response.answer.as_ref().unwrap()[0].ttl, 60);
+        assert_eq!(response.header.ancount, 1);
+
+        let request = Message::query(1, question("example.com", 1)).build();
+        let response = hosts
+            .handle(request, client(), Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.rcode, Rcode::Refused);
+
+        // Edits are picked up without a restart.
+        std::fs::write(&path, "10.0.0.1 example.com\n").unwrap();
+        hosts.table.refresh();
+        let request = Message::query(1, question("example.com", 1)).build();
+        let response = hosts
+            .handle(request, client(), Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(
+            response.answer.unwrap()[0].data,
+            RData::A(Ipv4Addr::new(10, 0, 0, 1))
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_missing_file_is_an_error() {
+        let config = HostsConfig {
+            files: vec![PathBuf::from("/nonexistent/hosts")],
+            ..HostsConfig::default()
+        };
+        assert!(matches!(
+            Hosts::from_config(&config, Acl::default()),
+            Err(ConfigError::Read { .. })
+        ));
+    }
+}
@@ -0,0 +1,533 @@
+mod acl;
+mod any;
+mod blocklist;
+mod fallback;
+mod forward;
+mod hosts;
+mod rebind;
+mod rrl;
+mod watch;
+
+pub(crate) use acl::{Access, Acl};
+pub(crate) use any::Any;
+pub(crate) use blocklist::Blocklist;
+pub(crate) use fallback::Fallback;
+pub(crate) use forward::Forward;
+pub(crate) use hosts::Hosts;
+pub(crate) use rebind::Rebind;
+pub(crate) use rrl::Rrl;
+
+use crate::config::{Config, ConfigError};
+use crate::handler::{ClientInfo, RequestHandler, Response};
+use async_trait::async_trait;
+use log::debug;
+use rust_dns::{Message, Opcode, Rcode, UdpPacket};
+use std::cell::Cell;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// A single layer of the request pipeline.
+///
+/// A layer can answer the request itself, pass it on with `next.run(..)`,
+/// modify the request before passing it on, modify the response that comes
+/// back, or return `None` to drop the request without replying.
+///
+/// Standard queries go to [`Middleware::handle`]. NOTIFY and STATUS messages
+/// have their own hooks, and queries without a question and every other
+/// opcode go to [`Middleware::unsupported`]. Those hooks pass the message on untouched
+/// unless a layer overrides them, so layers that police clients rather than
+/// answer queries must override them too.
+#[async_trait]
+pub(crate) trait Middleware: Send + Sync {
+    /// Name used to reference the layer from `server.middleware`.
+    fn name(&self) -> &'static str;
+
+    async fn handle(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response>;
+
+    /// Handles a NOTIFY (RFC 1996) telling us that a zone has changed.
+    async fn notify(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        next.notify(request, client).await
+    }
+
+    /// Handles a STATUS request.
+    async fn status(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        next.status(request, client).await
+    }
+
+    /// Handles a message the server cannot serve: a query without a question,
+    /// or an opcode it does not implement.
+    async fn unsupported(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        next.unsupported(request, client).await
+    }
+}
+
+tokio::task_local! {
+    /// Name of the deepest layer a request reached, see [`Chain::handle_traced`].
+    static ANSWERED_BY: Cell<&'static str>;
+}
+
+/// Notes that `layer` is about to see the request.
+fn trace(layer: &dyn Middleware) {
+    let _ = ANSWERED_BY.try_with(|answered_by| answered_by.set(layer.name()));
+}
+
+/// The layers that come after the current one.
+#[derive(Clone, Copy)]
+pub(crate) struct Next<'a> {
+    rest: &'a [Arc<dyn Middleware>],
+}
+
+impl Next<'_> {
+    /// Passes `request` to the next layer. Falling off the end of the chain
+    /// answers REFUSED, since no layer was willing to handle the query.
+    pub(crate) async fn run(self, request: UdpPacket, client: ClientInfo) -> Option<Response> {
+        match self.rest.split_first() {
+            Some((layer, rest)) => {
+                trace(layer.as_ref());
+                layer.handle(request, client, Next { rest }).await
+            }
+            None => {
+                debug!("No middleware answered the query, refusing it");
+                Some(
+                    Message::response_to(&request)
+                        .rcode(Rcode::Refused)
+                        .build()
+                        .into(),
+                )
+            }
+        }
+    }
+
+    /// Passes a NOTIFY to the next layer. Nobody handling it means we are not
+    /// a secondary for any zone, so the end of the chain answers NOTIMP.
+    pub(crate) async fn notify(self, request: UdpPacket, client: ClientInfo) -> Option<Response> {
+        match self.rest.split_first() {
+            Some((layer, rest)) => {
+                trace(layer.as_ref());
+                layer.notify(request, client, Next { rest }).await
+            }
+            None => Some(not_implemented(&request)),
+        }
+    }
+
+    /// Passes a STATUS request to the next layer, answering NOTIMP at the end.
+    pub(crate) async fn status(self, request: UdpPacket, client: ClientInfo) -> Option<Response> {
+        match self.rest.split_first() {
+            Some((layer, rest)) => {
+                trace(layer.as_ref());
+                layer.status(request, client, Next { rest }).await
+            }
+            None => Some(not_implemented(&request)),
+        }
+    }
+
+    /// Passes a message the server cannot serve to the next layer. The end
+    /// of the chain answers FORMERR to a query without a question and NOTIMP
+    /// to anything else.
+    pub(crate) async fn unsupported(
+        self,
+        request: UdpPacket,
+        client: ClientInfo,
+    ) -> Option<Response> {
+        match self.rest.split_first() {
+            Some((layer, rest)) => {
+                trace(layer.as_ref());
+                layer.unsupported(request, client, Next { rest }).await
+            }
+            None if request.header.opcode == Opcode::Query => {
+                debug!("Query without a question, answering FORMERR");
+                Some(
+                    Message::response_to(&request)
+                        .rcode(Rcode::FormErr)
+                        .build()
+                        .into(),
+                )
+            }
+            None => {
+                debug!("Opcode {:?} is not implemented", request.header.opcode);
+                Some(not_implemented(&request))
+            }
+        }
+    }
+}
+
+/// A NOTIMP response with every section left empty.
+pub(crate) fn not_implemented(request: &UdpPacket) -> Response {
+    Message::response_to(request)
+        .without_question()
+        .rcode(Rcode::NotImp)
+        .build()
+        .into()
+}
+
+/// An ordered middleware pipeline, built from `server.middleware`.
+///
+/// Clients that [`Access`] keeps out are turned away before the first layer.
+#[derive(Clone, Default)]
+pub(crate) struct Chain {
+    layers: Vec<Arc<dyn Middleware>>,
+    access: Access,
+}
+
+impl Chain {
+    pub(crate) fn new(layers: Vec<Arc<dyn Middleware>>) -> Self {
+        Self {
+            layers,
+            access: Access::default(),
+        }
+    }
+
+    pub(crate) fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut layers = Vec::new();
+        for name in &config.server.middleware {
+            layers.push(build(name, config)?);
+        }
+        Ok(Self::new(layers).with_access(Access::from_config(&config.acl)))
+    }
+
+    /// Sets which clients may use the chain at all.
+    pub(crate) fn with_access(mut self, access: Access) -> Self {
+        self.access = access;
+        self
+    }
+
+    pub(crate) fn names(&self) -> Vec<&'static str> {
+        self.layers.iter().map(|layer| layer.name()).collect()
+    }
+
+    /// Handles `request` like [`RequestHandler::handle`], also returning the
+    /// name of the layer that answered it. That is the deepest layer the
+    /// request got to, even if layers before it changed the response on the
+    /// way back. `"chain"` means no layer saw it.
+    pub(crate) async fn handle_traced(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+    ) -> (Option<Response>, &'static str) {
+        ANSWERED_BY
+            .scope(Cell::new("chain"), async {
+                let response = self.handle(request, client).await;
+                (response, ANSWERED_BY.with(Cell::get))
+            })
+            .await
+    }
+}
+
+/// Names accepted in `server.middleware`.
+pub(crate) const NAMES: &[&str] = &[
+    "rrl",
+    "any",
+    "hosts",
+    "blocklist",
+    "rebind",
+    "forward",
+    "fallback",
+];
+
+/// Checks that every name is known and used once, without building anything.
+pub(crate) fn validate_names(names: &[String]) -> Result<(), ConfigError> {
+    for (i, name) in names.iter().enumerate() {
+        if !NAMES.contains(&name.as_str()) {
+            return Err(ConfigError::Invalid {
+                field: "server.middleware",
+                reason: format!("unknown middleware '{}'", name),
+            });
+        }
+        if names[..i].contains(name) {
+            return Err(ConfigError::Invalid {
+                field: "server.middleware",
+                reason: format!("'{}' is listed more than once", name),
+            });
+        }
+    }
+    Ok(())
+}
+
+fn build(name: &str, config: &Config) -> Result<Arc<dyn Middleware>, ConfigError> {
+    match name {
+        "fallback" => Ok(Arc::new(Fallback)),
+        "rrl" => Ok(Arc::new(Rrl::from_config(&config.rrl))),
+        "any" => Ok(Arc::new(Any::from_config(&config.any)?)),
+        "rebind" => Ok(Arc::new(Rebind::from_config(&config.rebind)?)),
+        "hosts" => Ok(Arc::new(Hosts::from_config(
+            &config.hosts,
+            Acl::authoritative(&config.acl),
+        )?)),
+        "blocklist" => Ok(Arc::new(Blocklist::from_config(&config.blocklist)?)),
+        "forward" => Ok(Arc::new(Forward::new(
+            config.resolver_addr()?,
+            Duration::from_secs(config.upstream.timeout),
+            Acl::recursion(&config.acl),
+        ))),
+        _ => Err(ConfigError::Invalid {
+            field: "server.middleware",
+            reason: format!("unknown middleware '{}'", name),
+        }),
+    }
+}
+
+#[async_trait]
+impl RequestHandler for Chain {
+    async fn handle(&self, request: UdpPacket, client: ClientInfo) -> Option<Response> {
+        if !self.access.permits(client.addr.ip()) {
+            return self.access.reject(&request, client);
+        }
+        let next = Next { rest: &self.layers };
+        match request.header.opcode {
+            Opcode::Query if !request.question.is_empty() => next.run(request, client).await,
+            Opcode::Notify => next.notify(request, client).await,
+            Opcode::Status => next.status(request, client).await,
+            _ => next.unsupported(request, client).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod middleware_tests {
+    use super::*;
+    use crate::testutil::client;
+    use rust_dns::Question;
+
+    /// Appends its name to the request id so tests can see the order layers ran in.
+    struct Tag(&'static str, u16);
+
+    #[async_trait]
+    impl Middleware for Tag {
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        async fn handle(
+            &self,
+            mut request: UdpPacket,
+            client: ClientInfo,
+            next: Next<'_>,
+        ) -> Option<Response> {
+            request.header.id = request.header.id * 10 + self.1;
+            next.run(request, client).await
+        }
+    }
+
+    /// Drops every request without answering.
+    struct Silent;
+
+    #[async_trait]
+    impl Middleware for Silent {
+        fn name(&self) -> &'static str {
+            "silent"
+        }
+
+        async fn handle(&self, _: UdpPacket, _: ClientInfo, _: Next<'_>) -> Option<Response> {
+            None
+        }
+    }
+
+    /// Acknowledges NOTIFY messages the way a secondary would.
+    struct Secondary;
+
+    #[async_trait]
+    impl Middleware for Secondary {
+        fn name(&self) -> &'static str {
+            "secondary"
+        }
+
+        async fn handle(&self, _: UdpPacket, _: ClientInfo, _: Next<'_>) -> Option<Response> {
+            None
+        }
+
+        async fn notify(&self, request: UdpPacket, _: ClientInfo, _: Next<'_>) -> Option<Response> {
+            Some(
+                Message::response_to(&request)
+                    .authoritative(true)
+                    .build()
+                    .into(),
+            )
+        }
+    }
+
+    fn request() -> UdpPacket {
+        Message::query(0, Question::new("codecrafters.io".to_string(), 1, 1)).build()
+    }
+
+    #[tokio::test]
+    async fn test_layers_run_in_order() {
+        let chain = Chain::new(vec![Arc::new(Tag("one", 1)), Arc::new(Tag("two", 2))]);
+        let response = chain
+            .handle(request(), client())
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.id, 12);
+    }
+
+    #[tokio::test]
+    async fn test_end_of_chain_is_refused() {
+        let response = Chain::default()
+            .handle(request(), client())
+            .await
+            .unwrap()
+            .into_packet();
+        assert!(response.header.qr);
+        assert_eq!(response.header.rcode, Rcode::Refused);
+        assert_eq!(response.header.ancount, 0);
+    }
+
+    #[tokio::test]
+    async fn test_unsupported_opcode_is_not_implemented() {
+        let chain = Chain::new(vec![Arc::new(Tag("never", 1))]);
+        for opcode in [Opcode::IQuery, Opcode::Update, Opcode::Unknown(9)] {
+            let mut request = request();
+            request.header.opcode = opcode;
+            let response = chain.handle(request, client()).await.unwrap().into_packet();
+            assert_eq!(response.header.id, 0);
+            assert_eq!(response.header.opcode, opcode);
+            assert_eq!(response.header.rcode, Rcode::NotImp);
+            assert_eq!(response.header.qdcount, 0);
+            assert_eq!(response.header.ancount, 0);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_query_without_question_is_formerr() {
+        let chain = Chain::new(vec![Arc::new(Tag("never", 1))]);
+        let mut empty = request();
+        empty.question.clear();
+        let response = chain.handle(empty, client()).await.unwrap().into_packet();
+        assert_eq!(response.header.id, 0);
+        assert_eq!(response.header.rcode, Rcode::FormErr);
+        assert!(response.header.qr);
+        assert_eq!(response.header.qdcount, 0);
+    }
+
+    #[tokio::test]
+    async fn test_notify_and_status_hooks() {
+        let mut notify = request();
+        notify.header.opcode = Opcode::Notify;
+        let mut status = request();
+        status.header.opcode = Opcode::Status;
+
+        // Without a layer that overrides the hooks they fall through to NOTIMP.
+        let chain = Chain::new(vec![Arc::new(Tag("tag", 1))]);
+        let response = chain
+            .handle(notify.clone(), client())
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.rcode, Rcode::NotImp);
+        assert_eq!(response.header.id, 0);
+        let response = chain
+            .handle(status.clone(), client())
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.rcode, Rcode::NotImp);
+
+        let chain = Chain::new(vec![Arc::new(Tag("tag", 1)), Arc::new(Secondary)]);
+        let response = chain.handle(notify, client()).await.unwrap().into_packet();
+        assert_eq!(response.header.opcode, Opcode::Notify);
+        assert_eq!(response.header.rcode, Rcode::NoError);
+        assert!(response.header.aa);
+        assert_eq!(response.header.qdcount, 1);
+        assert!(chain.handle(request(), client()).await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_access_is_checked_for_every_opcode() {
+        let config = Config::parse(
+            "[acl]\nrecursion = { allow = [\"10.0.0.0/8\"] }\nauthoritative = { allow = [\"10.0.0.0/8\"] }\n",
+        )
+        .unwrap();
+        let chain =
+            Chain::new(vec![Arc::new(Secondary)]).with_access(Access::from_config(&config.acl));
+        for opcode in [
+            Opcode::Query,
+            Opcode::Notify,
+            Opcode::Status,
+            Opcode::Update,
+        ] {
+            let mut request = request();
+            request.header.opcode = opcode;
+            let response = chain.handle(request, client()).await.unwrap().into_packet();
+            assert_eq!(response.header.rcode, Rcode::Refused, "{:?}", opcode);
+        }
+        let mut empty = request();
+        empty.question.clear();
+        let response = chain.handle(empty, client()).await.unwrap().into_packet();
+        assert_eq!(response.header.rcode, Rcode::Refused);
+    }
+
+    #[tokio::test]
+    async fn test_handle_traced() {
+        let chain = Chain::new(vec![Arc::new(Tag("one", 1)), Arc::new(Secondary)]);
+        let (response, answered_by) = chain.handle_traced(request(), client()).await;
+        assert!(response.is_none());
+        assert_eq!(answered_by, "secondary");
+
+        let chain = Chain::new(vec![Arc::new(Tag("one", 1)), Arc::new(Tag("two", 2))]);
+        let (response, answered_by) = chain.handle_traced(request(), client()).await;
+        assert_eq!(response.unwrap().into_packet().header.rcode, Rcode::Refused);
+        assert_eq!(answered_by, "two");
+
+        let mut update = request();
+        update.header.opcode = Opcode::Update;
+        let (response, answered_by) = chain.handle_traced(update, client()).await;
+        assert_eq!(response.unwrap().into_packet().header.rcode, Rcode::NotImp);
+        assert_eq!(answered_by, "two");
+
+        let (_, answered_by) = Chain::default().handle_traced(request(), client()).await;
+        assert_eq!(answered_by, "chain");
+    }
+
+    #[tokio::test]
+    async fn test_layer_can_drop_request() {
+        let chain = Chain::new(vec![Arc::new(Silent), Arc::new(Tag("never", 1))]);
+        assert!(chain.handle(request(), client()).await.is_none());
+    }
+
+    #[test]
+    fn test_chain_from_config() {
+        let config = Config::parse("[server]\nmiddleware = [\"fallback\"]\n").unwrap();
+        assert_eq!(
+            Chain::from_config(&config).unwrap().names(),
+            vec!["fallback"]
+        );
+        assert_eq!(
+            Chain::from_config(&Config::default()).unwrap().names(),
+            vec![
+                "rrl",
+                "any",
+                "hosts",
+                "blocklist",
+                "rebind",
+                "forward",
+                "fallback"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_invalid_chain_is_rejected() {
+        assert!(Config::parse("[server]\nmiddleware = [\"cache\"]\n").is_err());
+        assert!(Config::parse("[server]\nmiddleware = [\"forward\", \"forward\"]\n").is_err());
+    }
+}
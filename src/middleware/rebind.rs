@@ -0,0 +1,324 @@
+use super::{Middleware, Next};
+use crate::config::{ConfigError, RebindConfig, RebindMode};
+use crate::handler::{ClientInfo, Response};
+use crate::metrics::METRICS;
+use async_trait::async_trait;
+use log::debug;
+use rust_dns::{
+    DomainName, ExtendedErrorCode, Message, QuestionType, RData, Rcode, RecordRef, UdpPacket,
+};
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+/// Whether `addr` belongs to a network that a public name has no business
+/// pointing at: RFC 1918 and unique local ranges, loopback, link-local and
+/// the unspecified address.
+fn is_private(addr: IpAddr) -> bool {
+    match addr.to_canonical() {
+        IpAddr::V4(v4) => is_private_v4(v4),
+        IpAddr::V6(v6) => is_private_v6(v6),
+    }
+}
+
+fn is_private_v4(addr: Ipv4Addr) -> bool {
+    addr.is_private()
+        || addr.is_loopback()
+        || addr.is_link_local()
+        || addr.is_unspecified()
+        || addr.octets()[0] == 0
+}
+
+fn is_private_v6(addr: Ipv6Addr) -> bool {
+    let first = addr.segments()[0];
+    addr.is_loopback()
+        || addr.is_unspecified()
+        || first & 0xfe00 == 0xfc00
+        || first & 0xffc0 == 0xfe80
+}
+
+/// Whether `data` is a private address, see [`is_private`].
+fn is_private_data(data: &RData) -> bool {
+    match data {
+        RData::A(ip) => is_private(IpAddr::V4(*ip)),
+        RData::AAAA(ip) => is_private(IpAddr::V6(*ip)),
+        _ => false,
+    }
+}
+
+/// Like [`is_private_data`], decoding only the data of address records.
+fn is_private_record(record: &RecordRef<'_>) -> bool {
+    matches!(
+        QuestionType::from(record.typ),
+        QuestionType::A | QuestionType::AAAA
+    ) && record.rdata().is_ok_and(|data| is_private_data(&data))
+}
+
+/// DNS rebinding protection: keeps upstream answers from pointing public
+/// names at addresses on our side of the network.
+///
+/// It belongs right before `forward`, so that answers from local data such
+/// as hosts files never pass through it. Names at or below an entry of
+/// `rebind.allow` may resolve to private addresses.
+pub(crate) struct Rebind {
+    mode: RebindMode,
+    allow: HashSet<DomainName>,
+}
+
+impl Rebind {
+    pub(crate) fn from_config(config: &RebindConfig) -> Result<Self, ConfigError> {
+        Ok(Self {
+            mode: config.mode,
+            allow: config.allowed()?,
+        })
+    }
+
+    fn is_allowed(&self, name: &DomainName) -> bool {
+        std::iter::successors(Some(name.clone()), DomainName::parent)
+            .any(|name| self.allow.contains(&name))
+    }
+
+    /// Applies the mode to `response`, or returns it as is if it has no
+    /// private addresses. A relayed answer that cannot be rewritten is
+    /// rejected.
+    fn filter(&self, request: &UdpPacket, response: Response) -> Response {
+        let found = match &response {
+            Response::Packet(packet) => packet
+                .answer
+                .iter()
+                .flatten()
+                .any(|answer| is_private_data(&answer.data)),
+            Response::Relayed(view) => view
+                .answers()
+                .into_iter()
+                .flatten()
+                .any(|record| record.is_ok_and(|record| is_private_record(&record))),
+        };
+        if !found {
+            return response;
+        }
+        METRICS.rebind_filtered.inc();
+        debug!(
+            "Upstream answered {:?} with private addresses, {:?}",
+            request.question, self.mode
+        );
+        let reject = || {
+            Message::response_to(request)
+                .rcode(Rcode::Refused)
+                .extended_error(ExtendedErrorCode::Filtered, "private address")
+                .build()
+                .into()
+        };
+        match (self.mode, response) {
+            (RebindMode::Off, response) => response,
+            (RebindMode::Strip, Response::Packet(mut packet)) => {
+                if let Some(answers) = &mut packet.answer {
+                    answers.retain(|answer| !is_private_data(&answer.data));
+                }
+                packet.into()
+            }
+            (RebindMode::Strip, Response::Relayed(view)) => {
+                match view.retain_answers(|record| !is_private_record(record)) {
+                    Ok(view) => Response::Relayed(view),
+                    Err(e) => {
+                        debug!(
+                            "Failed to strip the answer to {:?}: {}",
+                            request.question, e
+                        );
+                        reject()
+                    }
+                }
+            }
+            (RebindMode::Reject, _) => reject(),
+        }
+    }
+}
+
+#[async_trait]
+impl Middleware for Rebind {
+    fn name(&self) -> &'static str {
+        "rebind"
+    }
+
+    async fn handle(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        if self.mode == RebindMode::Off
+            || request
+                .question
+                .iter()
+                .all(|question| self.is_allowed(&question.qname))
+        {
+            return next.run(request, client).await;
+        }
+        let response = next.run(request.clone(), client).await?;
+        Some(self.filter(&request, response))
+    }
+}
+
+#[cfg(test)]
+mod rebind_tests {
+    use super::*;
+    use crate::testutil::client;
+    use async_trait::async_trait;
+    use rust_dns::{Answer, Edns, MessageView, Question, QuestionClass, QuestionType};
+    use std::sync::Arc;
+
+    /// Answers every query with the addresses it was made with.
+    struct Upstream(Vec<&'static str>);
+
+    #[async_trait]
+    impl Middleware for Upstream {
+        fn name(&self) -> &'static str {
+            "upstream"
+        }
+
+        async fn handle(&self, request: UdpPacket, _: ClientInfo, _: Next<'_>) -> Option<Response> {
+            let answers = self.0.iter().map(|addr| {
+                let (typ, data) = match addr.parse::<IpAddr>().unwrap() {
+                    IpAddr::V4(ip) => (QuestionType::A, RData::A(ip)),
+                    IpAddr::V6(ip) => (QuestionType::AAAA, RData::AAAA(ip)),
+                };
+                Answer {
+                    name: request.question[0].qname.clone(),
+                    typ,
+                    class: QuestionClass::IN,
+                    ttl: 60,
+                    data,
+                }
+            });
+            Some(
+                Message::response_to(&request)
+                    .answers(answers)
+                    .build()
+                    .into(),
+            )
+        }
+    }
+
+    /// Passes the upstream answer on as relayed bytes, the way forward does.
+    struct Relayed(Upstream);
+
+    #[async_trait]
+    impl Middleware for Relayed {
+        fn name(&self) -> &'static str {
+            "relayed"
+        }
+
+        async fn handle(
+            &self,
+            request: UdpPacket,
+            client: ClientInfo,
+            next: Next<'_>,
+        ) -> Option<Response> {
+            let bytes = self
+                .0
+                .handle(request, client, next)
+                .await?
+                .encode()
+                .unwrap();
+            Some(Response::Relayed(MessageView::new(bytes).unwrap()))
+        }
+    }
+
+    async fn ask(mode: RebindMode, name: &str, addrs: Vec<&'static str>) -> UdpPacket {
+        ask_through(mode, name, Arc::new(Upstream(addrs))).await
+    }
+
+    async fn ask_through(mode: RebindMode, name: &str, upstream: Arc<dyn Middleware>) -> UdpPacket {
+        let rebind = Rebind::from_config(&RebindConfig {
+            mode,
+            allow: vec!["lan".to_string(), "plex.direct".to_string()],
+        })
+        .unwrap();
+        let layers = vec![upstream];
+        let client = client();
+        let request = Message::query(1, Question::new(name.to_string(), 1, 1))
+            .edns(Edns::default())
+            .build();
+        rebind
+            .handle(request, client, Next { rest: &layers })
+            .await
+            .unwrap()
+            .into_packet()
+    }
+
+    #[test]
+    fn test_is_private() {
+        for private in [
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "127.0.0.1",
+            "169.254.1.1",
+            "0.0.0.0",
+            "::1",
+            "::",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:192.168.1.1",
+        ] {
+            assert!(is_private(private.parse().unwrap()), "{}", private);
+        }
+        for public in ["8.8.8.8", "172.32.0.1", "2001:db8::1", "::ffff:1.1.1.1"] {
+            assert!(!is_private(public.parse().unwrap()), "{}", public);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_strip() {
+        let response = ask(
+            RebindMode::Strip,
+            "evil.example",
+            vec!["1.2.3.4", "192.168.1.1"],
+        )
+        .await;
+        let answers = response.answer.unwrap();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].data, RData::A(Ipv4Addr::new(1, 2, 3, 4)));
+
+        let response = ask(RebindMode::Strip, "evil.example", vec!["fd00::1"]).await;
+        assert_eq!(response.header.rcode, Rcode::NoError);
+        assert_eq!(response.answer, Some(Vec::new()));
+    }
+
+    #[tokio::test]
+    async fn test_strip_relayed() {
+        let upstream = Relayed(Upstream(vec!["192.168.1.1", "1.2.3.4", "10.0.0.1"]));
+        let response = ask_through(RebindMode::Strip, "evil.example", Arc::new(upstream)).await;
+        assert_eq!(response.header.ancount, 1);
+        assert_eq!(
+            response.answer.unwrap()[0].data,
+            RData::A(Ipv4Addr::new(1, 2, 3, 4))
+        );
+
+        let upstream = Relayed(Upstream(vec!["1.2.3.4"]));
+        let response = ask_through(RebindMode::Strip, "good.example", Arc::new(upstream)).await;
+        assert_eq!(response.header.ancount, 1);
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let response = ask(RebindMode::Reject, "evil.example", vec!["127.0.0.1"]).await;
+        assert_eq!(response.header.rcode, Rcode::Refused);
+        assert_eq!(
+            response.edns.unwrap().extended_error(),
+            Some((ExtendedErrorCode::Filtered, "private address"))
+        );
+
+        let response = ask(RebindMode::Reject, "good.example", vec!["8.8.8.8"]).await;
+        assert_eq!(response.header.ancount, 1);
+    }
+
+    #[tokio::test]
+    async fn test_allowlist_and_off() {
+        let response = ask(RebindMode::Reject, "nas.LAN", vec!["192.168.1.10"]).await;
+        assert_eq!(response.header.ancount, 1);
+        let response = ask(RebindMode::Strip, "x.plex.direct", vec!["10.0.0.5"]).await;
+        assert_eq!(response.header.ancount, 1);
+        let response = ask(RebindMode::Off, "evil.example", vec!["10.0.0.5"]).await;
+        assert_eq!(response.header.ancount, 1);
+    }
+}
@@ -0,0 +1,480 @@
+use super::{Middleware, Next};
+use crate::config::RrlConfig;
+use crate::handler::{ClientInfo, Protocol, Response};
+use crate::metrics::METRICS;
+use async_trait::async_trait;
+use ipnet::IpNet;
+use log::{debug, info};
+use rust_dns::{DomainName, Message, QuestionType, Rcode, UdpPacket};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::{Duration, Instant};
+use tokio::runtime::Handle;
+
+/// Most entries the table holds. Responses for clients that would need a
+/// new entry once it is full are sent without being limited, until the next
+/// sweep makes room.
+const MAX_ENTRIES: usize = 65536;
+
+type Buckets = Mutex<HashMap<(IpNet, Identity), Bucket>>;
+
+/// What a response says, so that clients asking the same thing share a
+/// budget however they spell it.
+#[derive(Debug, PartialEq, Eq, Hash, Clone)]
+enum Identity {
+    /// A positive answer, or an empty one, for a name and type.
+    Answer(DomainName, QuestionType),
+    /// An error. The name is left out so that random subdomains of a victim
+    /// zone all count against the same limit.
+    Error(u16),
+}
+
+impl Identity {
+    /// The identity of a response to `request` with `rcode`.
+    fn of(request: &UdpPacket, rcode: Rcode) -> Self {
+        match (rcode, request.question.first()) {
+            (Rcode::NoError, Some(question)) => {
+                Identity::Answer(question.qname.clone(), question.qtype)
+            }
+            (rcode, _) => Identity::Error(u16::from(rcode)),
+        }
+    }
+}
+
+/// Responses sent to one client prefix for one identity.
+struct Bucket {
+    /// Responses that may still be sent. Negative while the client is over
+    /// the limit, down to `-rate * window`.
+    balance: f64,
+    updated: Instant,
+    /// Responses withheld so far, to pick which ones slip through.
+    limited: u64,
+}
+
+/// What to do with a response that is over the limit.
+#[derive(Debug, PartialEq)]
+enum Verdict {
+    Send,
+    Drop,
+    /// Send an empty truncated answer, so a real client retries over TCP.
+    Slip,
+}
+
+/// BIND-style response rate limiting.
+///
+/// Every client prefix gets `responses_per_second` responses per identity,
+/// with up to one second's worth saved up. Over the limit responses are
+/// dropped, except that every `slip`th one is replaced by a truncated answer.
+/// There is no TCP listener yet for such a client to retry on, so `slip`
+/// defaults to zero.
+/// A client that keeps going over stays limited for up to `window` seconds.
+/// In log-only mode nothing is withheld; the responses that would have been
+/// are only counted and logged.
+///
+/// Only UDP responses are limited, since TCP cannot be spoofed.
+pub(crate) struct Rrl {
+    rate: f64,
+    window: Duration,
+    slip: u64,
+    log_only: bool,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+    buckets: Arc<Buckets>,
+}
+
+impl Rrl {
+    /// Builds the limiter, and on a Tokio runtime the task that sweeps idle
+    /// entries out of its table once per window.
+    pub(crate) fn from_config(config: &RrlConfig) -> Self {
+        let rrl = Self {
+            rate: config.responses_per_second as f64,
+            window: Duration::from_secs(config.window),
+            slip: config.slip,
+            log_only: config.log_only,
+            ipv4_prefix: config.ipv4_prefix_length,
+            ipv6_prefix: config.ipv6_prefix_length,
+            buckets: Arc::new(Mutex::new(HashMap::new())),
+        };
+        if rrl.is_enabled() {
+            if let Ok(runtime) = Handle::try_current() {
+                runtime.spawn(sweep_every(Arc::downgrade(&rrl.buckets), rrl.idle()));
+            }
+        }
+        rrl
+    }
+
+    /// How long an entry may go unused before it is swept out.
+    fn idle(&self) -> Duration {
+        self.window.max(Duration::from_secs(1))
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.rate > 0.0
+    }
+
+    /// The network of `addr` that shares a budget.
+    fn prefix(&self, addr: IpAddr) -> IpNet {
+        let addr = addr.to_canonical();
+        let len = match addr {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        // Prefix lengths are checked when the config is loaded.
+        IpNet::new(addr, len)
+            .map(|net| net.trunc())
+            .unwrap_or(addr.into())
+    }
+
+    /// Whether responses to `client` are limited at all.
+    fn applies_to(&self, client: ClientInfo) -> bool {
+        self.is_enabled() && client.protocol == Protocol::Udp
+    }
+
+    /// Charges `response`, the answer to `request`, to `client` and decides
+    /// whether it is sent, dropped or replaced by a truncated answer. Every
+    /// opcode goes through here, so NOTIFY and STATUS cannot be used to get
+    /// around the limit.
+    fn limit(
+        &self,
+        client: ClientInfo,
+        request: &UdpPacket,
+        response: Response,
+    ) -> Option<Response> {
+        let prefix = self.prefix(client.addr.ip());
+        let identity = Identity::of(request, response.rcode());
+        let verdict = self.check(prefix, identity.clone(), Instant::now());
+        if verdict == Verdict::Send {
+            return Some(response);
+        }
+        if self.log_only {
+            METRICS.rrl_would_limit.inc();
+            info!(
+                "Rate limit exceeded by {} for {:?}, would {:?}",
+                prefix, identity, verdict
+            );
+            return Some(response);
+        }
+        match verdict {
+            Verdict::Slip => {
+                METRICS.rrl_slipped.inc();
+                debug!(
+                    "Rate limiting {} for {:?}, sending a truncated answer",
+                    prefix, identity
+                );
+                Some(
+                    Message::response_to(request)
+                        .rcode(response.rcode())
+                        .truncated(true)
+                        .build()
+                        .into(),
+                )
+            }
+            _ => {
+                METRICS.rrl_dropped.inc();
+                debug!(
+                    "Rate limiting {} for {:?}, dropping the response",
+                    prefix, identity
+                );
+                None
+            }
+        }
+    }
+
+    /// Charges one response to `prefix` and `identity` at `now`.
+    fn check(&self, prefix: IpNet, identity: Identity, now: Instant) -> Verdict {
+        let mut buckets = self.buckets.lock().unwrap();
+        let key = (prefix, identity);
+        if buckets.len() >= MAX_ENTRIES && !buckets.contains_key(&key) {
+            debug!("Rate limit table is full, not limiting {}", key.0);
+            return Verdict::Send;
+        }
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            balance: self.rate,
+            updated: now,
+            limited: 0,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.updated = now;
+        let floor = -self.rate * self.window.as_secs_f64();
+        bucket.balance = (bucket.balance + elapsed * self.rate).min(self.rate) - 1.0;
+        if bucket.balance >= 0.0 {
+            return Verdict::Send;
+        }
+        bucket.balance = bucket.balance.max(floor);
+        bucket.limited += 1;
+        if self.slip > 0 && bucket.limited % self.slip == 0 {
+            Verdict::Slip
+        } else {
+            Verdict::Drop
+        }
+    }
+}
+
+/// Removes the entries unused for `idle` from `buckets`.
+fn sweep(buckets: &Buckets, idle: Duration, now: Instant) {
+    buckets
+        .lock()
+        .unwrap()
+        .retain(|_, bucket| now.duration_since(bucket.updated) < idle);
+}
+
+/// Sweeps `buckets` every `idle` until the [`Rrl`] owning them is dropped.
+async fn sweep_every(buckets: Weak<Buckets>, idle: Duration) {
+    let mut interval = tokio::time::interval(idle);
+    loop {
+        interval.tick().await;
+        let Some(buckets) = buckets.upgrade() else {
+            return;
+        };
+        sweep(&buckets, idle, Instant::now());
+    }
+}
+
+#[async_trait]
+impl Middleware for Rrl {
+    fn name(&self) -> &'static str {
+        "rrl"
+    }
+
+    async fn handle(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        if !self.applies_to(client) {
+            return next.run(request, client).await;
+        }
+        let response = next.run(request.clone(), client).await?;
+        self.limit(client, &request, response)
+    }
+
+    async fn notify(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        if !self.applies_to(client) {
+            return next.notify(request, client).await;
+        }
+        let response = next.notify(request.clone(), client).await?;
+        self.limit(client, &request, response)
+    }
+
+    async fn status(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        if !self.applies_to(client) {
+            return next.status(request, client).await;
+        }
+        let response = next.status(request.clone(), client).await?;
+        self.limit(client, &request, response)
+    }
+
+    async fn unsupported(
+        &self,
+        request: UdpPacket,
+        client: ClientInfo,
+        next: Next<'_>,
+    ) -> Option<Response> {
+        if !self.applies_to(client) {
+            return next.unsupported(request, client).await;
+        }
+        let response = next.unsupported(request.clone(), client).await?;
+        self.limit(client, &request, response)
+    }
+}
+
+#[cfg(test)]
+mod rrl_tests {
+    use super::*;
+    use crate::middleware::Fallback;
+    use crate::testutil::client_from;
+    use rust_dns::{Opcode, Question};
+    use std::net::Ipv4Addr;
+
+    fn rrl(rate: u32, slip: u64) -> Rrl {
+        Rrl::from_config(&RrlConfig {
+            responses_per_second: rate,
+            slip,
+            ..RrlConfig::default()
+        })
+    }
+
+    fn net(s: &str) -> IpNet {
+        s.parse().unwrap()
+    }
+
+    fn answer(name: &str) -> Identity {
+        Identity::Answer(name.parse().unwrap(), QuestionType::A)
+    }
+
+    #[test]
+    fn test_prefix() {
+        let rrl = rrl(5, 2);
+        assert_eq!(
+            rrl.prefix("192.0.2.77".parse().unwrap()),
+            net("192.0.2.0/24")
+        );
+        assert_eq!(
+            rrl.prefix("::ffff:192.0.2.77".parse().unwrap()),
+            net("192.0.2.0/24")
+        );
+        assert_eq!(
+            rrl.prefix("2001:db8:1:2:3::1".parse().unwrap()),
+            net("2001:db8:1::/56")
+        );
+    }
+
+    #[test]
+    fn test_identity() {
+        let query = Message::query(1, Question::new("Example.com".to_string(), 1, 1)).build();
+        assert_eq!(Identity::of(&query, Rcode::NoError), answer("example.com"));
+        assert_eq!(Identity::of(&query, Rcode::NXDomain), Identity::Error(3));
+    }
+
+    #[test]
+    fn test_limits_and_slips() {
+        let rrl = rrl(2, 2);
+        let start = Instant::now();
+        let check =
+            |identity: Identity, at: Duration| rrl.check(net("192.0.2.0/24"), identity, start + at);
+        assert_eq!(check(answer("a.example"), Duration::ZERO), Verdict::Send);
+        assert_eq!(check(answer("a.example"), Duration::ZERO), Verdict::Send);
+        assert_eq!(check(answer("a.example"), Duration::ZERO), Verdict::Drop);
+        assert_eq!(check(answer("a.example"), Duration::ZERO), Verdict::Slip);
+        assert_eq!(check(answer("a.example"), Duration::ZERO), Verdict::Drop);
+        // Other identities have their own budget.
+        assert_eq!(check(answer("b.example"), Duration::ZERO), Verdict::Send);
+        assert_eq!(
+            rrl.check(net("198.51.100.0/24"), answer("a.example"), start),
+            Verdict::Send
+        );
+        // The debt is paid off at the configured rate.
+        assert_ne!(
+            check(answer("a.example"), Duration::from_millis(1500)),
+            Verdict::Send
+        );
+        assert_eq!(
+            check(answer("a.example"), Duration::from_secs(4)),
+            Verdict::Send
+        );
+    }
+
+    #[test]
+    fn test_debt_is_capped_by_window() {
+        let rrl = Rrl::from_config(&RrlConfig {
+            responses_per_second: 1,
+            window: 2,
+            ..RrlConfig::default()
+        });
+        let start = Instant::now();
+        for _ in 0..100 {
+            rrl.check(net("192.0.2.0/24"), answer("a.example"), start);
+        }
+        assert_eq!(
+            rrl.check(
+                net("192.0.2.0/24"),
+                answer("a.example"),
+                start + Duration::from_secs(3)
+            ),
+            Verdict::Send
+        );
+    }
+
+    #[test]
+    fn test_table_is_capped_and_swept() {
+        let rrl = rrl(1, 0);
+        let start = Instant::now();
+        let error = || Identity::Error(u16::from(Rcode::Refused));
+        for i in 0..MAX_ENTRIES as u32 {
+            let prefix = IpNet::new(Ipv4Addr::from(i << 8).into(), 24).unwrap();
+            rrl.check(prefix, error(), start);
+        }
+        // Known clients are still limited, new ones are let through untracked.
+        let known = net("0.0.0.0/24");
+        assert_eq!(rrl.check(known, error(), start), Verdict::Drop);
+        let new = net("192.0.2.0/24");
+        for _ in 0..3 {
+            assert_eq!(rrl.check(new, error(), start), Verdict::Send);
+        }
+        assert_eq!(rrl.buckets.lock().unwrap().len(), MAX_ENTRIES);
+
+        sweep(&rrl.buckets, rrl.idle(), start + rrl.idle());
+        assert!(rrl.buckets.lock().unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_middleware() {
+        let rrl = rrl(1, 2);
+        let client = client_from("192.0.2.1:5353");
+        let layers: Vec<Arc<dyn Middleware>> = vec![Arc::new(Fallback)];
+        let query = || Message::query(1, Question::new("a.example".to_string(), 1, 1)).build();
+
+        let response = rrl
+            .handle(query(), client, Next { rest: &layers })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.ancount, 1);
+        let dropped = METRICS.rrl_dropped.get();
+        assert!(rrl
+            .handle(query(), client, Next { rest: &layers })
+            .await
+            .is_none());
+        assert!(METRICS.rrl_dropped.get() > dropped);
+        let response = rrl
+            .handle(query(), client, Next { rest: &layers })
+            .await
+            .unwrap()
+            .into_packet();
+        assert!(response.header.tc);
+        assert_eq!(response.header.ancount, 0);
+        assert_eq!(response.header.qdcount, 1);
+
+        let log_only = Rrl::from_config(&RrlConfig {
+            responses_per_second: 1,
+            log_only: true,
+            ..RrlConfig::default()
+        });
+        for _ in 0..3 {
+            let response = log_only
+                .handle(query(), client, Next { rest: &layers })
+                .await
+                .unwrap()
+                .into_packet();
+            assert_eq!(response.header.ancount, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_other_opcodes_are_limited() {
+        let rrl = rrl(1, 0);
+        let client = client_from("192.0.2.1:5353");
+        let mut notify = Message::query(1, Question::new("a.example".to_string(), 1, 1)).build();
+        notify.header.opcode = Opcode::Notify;
+        let mut update = notify.clone();
+        update.header.opcode = Opcode::Update;
+
+        // Both end up as NOTIMP, which shares one error budget.
+        let response = rrl
+            .notify(notify.clone(), client, Next { rest: &[] })
+            .await
+            .unwrap()
+            .into_packet();
+        assert_eq!(response.header.rcode, Rcode::NotImp);
+        assert!(rrl
+            .notify(notify, client, Next { rest: &[] })
+            .await
+            .is_none());
+        assert!(rrl
+            .unsupported(update, client, Next { rest: &[] })
+            .await
+            .is_none());
+    }
+}
@@ -0,0 +1,177 @@
+use crate::config::ConfigError;
+use log::{info, warn};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, RwLock, Weak};
+use std::time::{Duration, SystemTime};
+use tokio::runtime::Handle;
+use tokio::task::spawn_blocking;
+use tokio::time::{Instant, MissedTickBehavior};
+
+/// Parses a set of files into `T`.
+pub(crate) type Loader<T> = Box<dyn Fn(&[PathBuf]) -> Result<T, ConfigError> + Send + Sync>;
+
+/// Size and modification time of each file, to notice edits.
+type Stamps = Vec<Option<(SystemTime, u64)>>;
+
+fn stamps(files: &[PathBuf]) -> Stamps {
+    files
+        .iter()
+        .map(|path| {
+            let meta = std::fs::metadata(path).ok()?;
+            Some((meta.modified().ok()?, meta.len()))
+        })
+        .collect()
+}
+
+/// Shortest interval between checks, even when `refresh` is zero.
+const MIN_REFRESH: Duration = Duration::from_secs(1);
+
+struct Shared<T> {
+    files: Vec<PathBuf>,
+    loader: Loader<T>,
+    value: RwLock<Arc<T>>,
+    /// Also serialises refreshes, so an older read never replaces a newer one.
+    stamps: Mutex<Stamps>,
+}
+
+impl<T> Shared<T> {
+    fn refresh(&self) {
+        let mut stamps = self.stamps.lock().unwrap();
+        let current = self::stamps(&self.files);
+        if current == *stamps {
+            return;
+        }
+        *stamps = current;
+        match (self.loader)(&self.files) {
+            Ok(value) => {
+                info!("Reloaded {:?} after a change", self.files);
+                *self.value.write().unwrap() = Arc::new(value);
+            }
+            Err(e) => warn!(
+                "Failed to reload {:?}, keeping the old data: {}",
+                self.files, e
+            ),
+        }
+    }
+}
+
+/// Data loaded from files that is re-read when one of them changes.
+///
+/// A background task checks the files every refresh interval and re-reads
+/// them on the blocking thread pool, so [`Watched::get`] never waits on the
+/// file system. The task stops once the `Watched` is dropped, e.g. when a
+/// config reload replaces it. If re-reading fails the old data is kept.
+pub(crate) struct Watched<T> {
+    shared: Arc<Shared<T>>,
+}
+
+impl<T: Send + Sync + 'static> Watched<T> {
+    /// Loads the files right away, failing if any of them cannot be read.
+    ///
+    /// Outside a Tokio runtime no task is started and the files are never
+    /// re-read.
+    pub(crate) fn new(
+        files: Vec<PathBuf>,
+        refresh: Duration,
+        loader: Loader<T>,
+    ) -> Result<Self, ConfigError> {
+        let shared = Arc::new(Shared {
+            stamps: Mutex::new(stamps(&files)),
+            value: RwLock::new(Arc::new(loader(&files)?)),
+            files,
+            loader,
+        });
+        if let Ok(runtime) = Handle::try_current() {
+            runtime.spawn(watch(Arc::downgrade(&shared), refresh.max(MIN_REFRESH)));
+        }
+        Ok(Self { shared })
+    }
+
+    pub(crate) fn files(&self) -> &[PathBuf] {
+        &self.shared.files
+    }
+
+    /// The current data.
+    pub(crate) fn get(&self) -> Arc<T> {
+        self.shared.value.read().unwrap().clone()
+    }
+
+    /// Re-reads the files now if one of them changed, like the task does.
+    #[cfg(test)]
+    pub(crate) fn refresh(&self) {
+        self.shared.refresh();
+    }
+}
+
+/// Refreshes `shared` every `refresh` until it is dropped.
+async fn watch<T: Send + Sync + 'static>(shared: Weak<Shared<T>>, refresh: Duration) {
+    let mut interval = tokio::time::interval_at(Instant::now() + refresh, refresh);
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        interval.tick().await;
+        let Some(shared) = shared.upgrade() else {
+            return;
+        };
+        if spawn_blocking(move || shared.refresh()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// Reads a file for a [`Loader`], reporting which file failed.
+pub(crate) fn read(path: &PathBuf) -> Result<String, ConfigError> {
+    std::fs::read_to_string(path).map_err(|source| ConfigError::Read {
+        path: path.clone(),
+        source,
+    })
+}
+
+#[cfg(test)]
+mod watch_tests {
+    use super::*;
+    use crate::testutil::temp_path;
+
+    #[test]
+    fn test_reloads_changed_files() {
+        let path = temp_path("watch");
+        std::fs::write(&path, "one").unwrap();
+        let watched = Watched::new(
+            vec![path.clone()],
+            Duration::ZERO,
+            Box::new(|files| read(&files[0])),
+        )
+        .unwrap();
+        assert_eq!(*watched.get(), "one");
+
+        std::fs::write(&path, "three").unwrap();
+        assert_eq!(*watched.get(), "one");
+        watched.refresh();
+        assert_eq!(*watched.get(), "three");
+
+        // A broken file keeps the last good data.
+        std::fs::remove_file(&path).unwrap();
+        watched.refresh();
+        assert_eq!(*watched.get(), "three");
+    }
+
+    #[tokio::test]
+    async fn test_refreshes_in_the_background() {
+        let path = temp_path("watch-task");
+        std::fs::write(&path, "one").unwrap();
+        let watched = Watched::new(
+            vec![path.clone()],
+            Duration::ZERO,
+            Box::new(|files| read(&files[0])),
+        )
+        .unwrap();
+        std::fs::write(&path, "three").unwrap();
+        for _ in 0..30 {
+            if *watched.get() == "three" {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        assert_eq!(*watched.get(), "three");
+        let _ = std::fs::remove_file(&path);
+    }
+}
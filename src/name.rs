@@ -0,0 +1,530 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::cmp::Ordering;
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::net::IpAddr;
+use std::str::FromStr;
+use thiserror::Error;
+
+/// Longest label allowed on the wire, in octets.
+pub const MAX_LABEL_LEN: usize = 63;
+/// Longest name allowed on the wire, in octets, including the length bytes
+/// and the terminating root label.
+pub const MAX_NAME_LEN: usize = 255;
+
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+pub enum NameError {
+    #[error("Empty label in domain name '{0}'")]
+    EmptyLabel(String),
+    #[error("Label is {0} octets long, the limit is 63")]
+    LabelTooLong(usize),
+    #[error("Name is {0} octets long, the limit is 255")]
+    NameTooLong(usize),
+    #[error("Invalid escape sequence in domain name '{0}'")]
+    InvalidEscape(String),
+    #[error("Invalid compression pointer")]
+    InvalidPointer,
+    #[error("Invalid label type {0:#04x}")]
+    InvalidLabelType(u8),
+    #[error("Domain name runs past the end of the message")]
+    Truncated,
+}
+
+/// A domain name, stored as its labels without the root.
+///
+/// Labels are arbitrary octets, as DNS allows, and are written in the
+/// presentation format of RFC 1035 section 5.1 when displayed: `.` and `\`
+/// are escaped with a backslash and anything outside printable ASCII as
+/// `\DDD`. Comparison and hashing ignore ASCII case, so `WWW.Example.COM` and
+/// `www.example.com` are the same key, while the original spelling is kept for
+/// display and for the wire. [`Ord`] is the canonical DNS ordering from
+/// RFC 4034 section 6.1.
+#[derive(Debug, Clone, Default)]
+pub struct DomainName {
+    labels: Vec<Vec<u8>>,
+}
+
+impl DomainName {
+    /// The root name `.`.
+    pub fn root() -> Self {
+        DomainName { labels: Vec::new() }
+    }
+
+    /// Builds a name from its labels, leftmost first.
+    pub fn from_labels<I, S>(labels: I) -> Result<Self, NameError>
+    where
+        I: IntoIterator<Item = S>,
+        S: Into<Vec<u8>>,
+    {
+        let name = DomainName {
+            labels: labels.into_iter().map(Into::into).collect(),
+        };
+        for label in &name.labels {
+            if label.is_empty() {
+                return Err(NameError::EmptyLabel(name.to_string()));
+            }
+            if label.len() > MAX_LABEL_LEN {
+                return Err(NameError::LabelTooLong(label.len()));
+            }
+        }
+        if name.wire_len() > MAX_NAME_LEN {
+            return Err(NameError::NameTooLong(name.wire_len()));
+        }
+        Ok(name)
+    }
+
+    /// The name PTR queries use for `ip`: `4.3.2.1.in-addr.arpa` for
+    /// 1.2.3.4, or the reversed nibbles under `ip6.arpa` for IPv6.
+    pub fn reverse(ip: IpAddr) -> Self {
+        let labels = match ip {
+            IpAddr::V4(ip) => ip
+                .octets()
+                .iter()
+                .rev()
+                .map(|octet| octet.to_string().into_bytes())
+                .chain(["in-addr".into(), "arpa".into()])
+                .collect(),
+            IpAddr::V6(ip) => ip
+                .octets()
+                .iter()
+                .rev()
+                .flat_map(|octet| [octet & 0x0f, octet >> 4])
+                .map(|nibble| format!("{:x}", nibble).into_bytes())
+                .chain(["ip6".into(), "arpa".into()])
+                .collect(),
+        };
+        DomainName { labels }
+    }
+
+    pub fn labels(&self) -> &[Vec<u8>] {
+        &self.labels
+    }
+
+    pub fn label_count(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_root(&self) -> bool {
+        self.labels.is_empty()
+    }
+
+    /// Length of the uncompressed wire form.
+    pub fn wire_len(&self) -> usize {
+        self.labels
+            .iter()
+            .map(|label| label.len() + 1)
+            .sum::<usize>()
+            + 1
+    }
+
+    /// The name with its leftmost label removed, or `None` for the root.
+    pub fn parent(&self) -> Option<DomainName> {
+        if self.is_root() {
+            return None;
+        }
+        Some(DomainName {
+            labels: self.labels[1..].to_vec(),
+        })
+    }
+
+    /// Prepends `label` to this name.
+    pub fn child(&self, label: impl Into<Vec<u8>>) -> Result<DomainName, NameError> {
+        let mut labels = Vec::with_capacity(self.labels.len() + 1);
+        labels.push(label.into());
+        labels.extend(self.labels.iter().cloned());
+        DomainName::from_labels(labels)
+    }
+
+    /// Whether the rightmost labels of this name are `suffix`. Every name ends
+    /// with the root and with itself.
+    pub fn ends_with(&self, suffix: &DomainName) -> bool {
+        self.strip_suffix(suffix).is_some()
+    }
+
+    /// Whether this name is `parent` or lies below it.
+    pub fn is_subdomain_of(&self, parent: &DomainName) -> bool {
+        self.ends_with(parent)
+    }
+
+    /// Whether this name is exactly one label below `parent`.
+    pub fn is_child_of(&self, parent: &DomainName) -> bool {
+        self.strip_suffix(parent)
+            .is_some_and(|rest| rest.len() == 1)
+    }
+
+    /// The labels left over after removing `suffix` from the right.
+    pub fn strip_suffix(&self, suffix: &DomainName) -> Option<&[Vec<u8>]> {
+        let split = self.labels.len().checked_sub(suffix.labels.len())?;
+        let (rest, tail) = self.labels.split_at(split);
+        tail.iter()
+            .zip(&suffix.labels)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+            .then_some(rest)
+    }
+
+    /// A copy of the name with every label in lower case.
+    pub fn to_lowercase(&self) -> DomainName {
+        DomainName {
+            labels: self
+                .labels
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Reads a possibly compressed name from the front of `buf`.
+    ///
+    /// `buf` must be a suffix of `msg`, the whole message, which is what
+    /// compression pointers are resolved against. See [`walk`].
+    pub fn read<B: Buf>(buf: &mut B, msg: &[u8]) -> Result<Self, NameError> {
+        let start = msg.len() - buf.remaining();
+        let mut labels = Vec::new();
+        let end = walk(msg, start, |label| labels.push(label.to_vec()))?;
+        buf.advance(end - start);
+        Ok(DomainName { labels })
+    }
+
+    /// Appends the uncompressed wire form of the name to `dst`.
+    pub fn write(&self, dst: &mut BytesMut) {
+        for label in &self.labels {
+            dst.put_u8(label.len() as u8);
+            dst.put_slice(label);
+        }
+        dst.put_u8(0);
+    }
+}
+
+/// Walks the possibly compressed name at offset `pos` of `msg`, calling
+/// `label` for every label, and returns the offset just past the name.
+///
+/// Pointers may only point backwards, so a malicious message cannot make this
+/// loop, and the total length is checked against [`MAX_NAME_LEN`].
+pub(crate) fn walk(
+    msg: &[u8],
+    mut pos: usize,
+    mut label: impl FnMut(&[u8]),
+) -> Result<usize, NameError> {
+    let mut consumed = None;
+    let mut len = 1;
+    loop {
+        let first_byte = *msg.get(pos).ok_or(NameError::Truncated)?;
+        match first_byte >> 6 {
+            0 => {
+                let end = pos + 1 + first_byte as usize;
+                if end > msg.len() {
+                    return Err(NameError::Truncated);
+                }
+                if first_byte == 0 {
+                    return Ok(consumed.unwrap_or(end));
+                }
+                len += 1 + first_byte as usize;
+                if len > MAX_NAME_LEN {
+                    return Err(NameError::NameTooLong(len));
+                }
+                label(&msg[pos + 1..end]);
+                pos = end;
+            }
+            3 => {
+                let low = *msg.get(pos + 1).ok_or(NameError::Truncated)?;
+                let pointer = ((first_byte & 0b0011_1111) as usize) << 8 | low as usize;
+                if pointer >= pos {
+                    return Err(NameError::InvalidPointer);
+                }
+                consumed.get_or_insert(pos + 2);
+                pos = pointer;
+            }
+            _ => return Err(NameError::InvalidLabelType(first_byte)),
+        }
+    }
+}
+
+impl From<&DomainName> for Bytes {
+    fn from(value: &DomainName) -> Self {
+        let mut bytes = BytesMut::new();
+        value.write(&mut bytes);
+        bytes.freeze()
+    }
+}
+
+impl FromStr for DomainName {
+    type Err = NameError;
+
+    /// Parses a name in presentation format. A trailing dot is optional and
+    /// `""` or `"."` is the root. `\X` stands for the character `X` and
+    /// `\DDD` for the octet with decimal value `DDD`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s == "." {
+            return Ok(DomainName::root());
+        }
+        let invalid = || NameError::InvalidEscape(s.to_string());
+        let mut labels = Vec::new();
+        let mut label = Vec::new();
+        let mut bytes = s.bytes();
+        let mut trailing_dot = false;
+        while let Some(byte) = bytes.next() {
+            trailing_dot = byte == b'.';
+            match byte {
+                b'.' => labels.push(std::mem::take(&mut label)),
+                b'\\' => match bytes.next().ok_or_else(invalid)? {
+                    digit @ b'0'..=b'9' => {
+                        let mut value = (digit - b'0') as u16;
+                        for _ in 0..2 {
+                            match bytes.next() {
+                                Some(digit @ b'0'..=b'9') => {
+                                    value = value * 10 + (digit - b'0') as u16
+                                }
+                                _ => return Err(invalid()),
+                            }
+                        }
+                        label.push(u8::try_from(value).map_err(|_| invalid())?);
+                    }
+                    other => label.push(other),
+                },
+                other => label.push(other),
+            }
+        }
+        // A trailing dot leaves an empty last label, which is the root.
+        if !trailing_dot {
+            labels.push(label);
+        }
+        DomainName::from_labels(labels)
+    }
+}
+
+impl fmt::Display for DomainName {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_root() {
+            return f.write_str(".");
+        }
+        for (i, label) in self.labels.iter().enumerate() {
+            if i > 0 {
+                f.write_str(".")?;
+            }
+            for &byte in label {
+                match byte {
+                    b'.' | b'\\' => write!(f, "\\{}", byte as char)?,
+                    0x21..=0x7e => write!(f, "{}", byte as char)?,
+                    _ => write!(f, "\\{:03}", byte)?,
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl PartialEq for DomainName {
+    fn eq(&self, other: &Self) -> bool {
+        self.labels.len() == other.labels.len()
+            && self
+                .labels
+                .iter()
+                .zip(&other.labels)
+                .all(|(a, b)| a.eq_ignore_ascii_case(b))
+    }
+}
+
+impl Eq for DomainName {}
+
+impl Hash for DomainName {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        for label in &self.labels {
+            state.write_usize(label.len());
+            for byte in label {
+                state.write_u8(byte.to_ascii_lowercase());
+            }
+        }
+    }
+}
+
+impl Ord for DomainName {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let lower = |label: &Vec<u8>| label.to_ascii_lowercase();
+        self.labels
+            .iter()
+            .rev()
+            .map(lower)
+            .cmp(other.labels.iter().rev().map(lower))
+    }
+}
+
+impl PartialOrd for DomainName {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod name_tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    fn name(s: &str) -> DomainName {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_parse_and_display() {
+        assert_eq!(
+            name("www.example.com").labels(),
+            [b"www".to_vec(), b"example".to_vec(), b"com".to_vec()]
+        );
+        assert_eq!(name("www.example.com."), name("www.example.com"));
+        assert!(name(".").is_root());
+        assert!(name("").is_root());
+        assert_eq!(name("WWW.Example.com").to_string(), "WWW.Example.com");
+        assert_eq!(DomainName::root().to_string(), ".");
+        assert!("www..com".parse::<DomainName>().is_err());
+    }
+
+    #[test]
+    fn test_case_insensitive_eq_and_hash() {
+        assert_eq!(name("WWW.Example.COM"), name("www.example.com"));
+        assert_ne!(name("www.example.com"), name("example.com"));
+        let set: HashSet<DomainName> = [name("WWW.Example.COM"), name("www.example.com")]
+            .into_iter()
+            .collect();
+        assert_eq!(set.len(), 1);
+    }
+
+    #[test]
+    fn test_canonical_ordering() {
+        // Example from RFC 4034 section 6.1.
+        let expected = [
+            "example",
+            "a.example",
+            "yljkjljk.a.example",
+            "Z.a.example",
+            "zABC.a.EXAMPLE",
+            "z.example",
+            "\\001.z.example",
+            "*.z.example",
+        ];
+        let mut names: Vec<DomainName> = expected.iter().rev().map(|s| name(s)).collect();
+        names.sort();
+        let sorted: Vec<String> = names.iter().map(|n| n.to_string()).collect();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_parent_child_and_suffix() {
+        let www = name("www.Example.com");
+        assert_eq!(www.parent(), Some(name("example.com")));
+        assert_eq!(DomainName::root().parent(), None);
+        assert_eq!(name("example.com").child("www").unwrap(), www);
+        assert!(www.ends_with(&name("EXAMPLE.com")));
+        assert!(www.ends_with(&DomainName::root()));
+        assert!(www.is_subdomain_of(&www));
+        assert!(www.is_child_of(&name("example.com")));
+        assert!(!www.is_child_of(&name("com")));
+        assert!(!name("notexample.com").is_subdomain_of(&name("example.com")));
+        assert_eq!(
+            www.strip_suffix(&name("com")),
+            Some(&[b"www".to_vec(), b"Example".to_vec()][..])
+        );
+    }
+
+    #[test]
+    fn test_reverse() {
+        assert_eq!(
+            DomainName::reverse("192.0.2.1".parse().unwrap()),
+            name("1.2.0.192.in-addr.arpa")
+        );
+        assert_eq!(
+            DomainName::reverse("2001:db8::567:89ab".parse().unwrap()),
+            name("b.a.9.8.7.6.5.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa")
+        );
+    }
+
+    #[test]
+    fn test_read_and_write() {
+        // "www.test.com" followed by "ftp" + a pointer to "test.com" at offset 4.
+        let msg: &[u8] = &[
+            3,
+            b'w',
+            b'w',
+            b'w',
+            4,
+            b't',
+            b'e',
+            b's',
+            b't',
+            3,
+            b'c',
+            b'o',
+            b'm',
+            0,
+            3,
+            b'f',
+            b't',
+            b'p',
+            0b1100_0000,
+            4,
+            0xff,
+        ];
+        let mut buf = Bytes::copy_from_slice(msg);
+        assert_eq!(DomainName::read(&mut buf, msg), Ok(name("www.test.com")));
+        assert_eq!(DomainName::read(&mut buf, msg), Ok(name("ftp.test.com")));
+        assert_eq!(buf.as_ref(), &[0xff]);
+
+        assert_eq!(Bytes::from(&name("www.test.com")).as_ref(), &msg[..14]);
+        assert_eq!(Bytes::from(&DomainName::root()).as_ref(), &[0]);
+    }
+
+    #[test]
+    fn test_read_rejects_malformed_names() {
+        let read = |msg: &[u8]| DomainName::read(&mut Bytes::copy_from_slice(msg), msg);
+        assert_eq!(read(&[0b1100_0000, 0]), Err(NameError::InvalidPointer));
+        assert_eq!(read(&[0b0100_0000]), Err(NameError::InvalidLabelType(0x40)));
+        assert_eq!(read(&[3, b'w', b'w']), Err(NameError::Truncated));
+        assert_eq!(read(&[3, b'w', b'w', b'w']), Err(NameError::Truncated));
+
+        // 5 labels of 63 octets do not fit in 255.
+        let mut long = Vec::new();
+        for _ in 0..5 {
+            long.push(63);
+            long.extend([b'a'; 63]);
+        }
+        long.push(0);
+        assert_eq!(read(&long), Err(NameError::NameTooLong(257)));
+    }
+
+    #[test]
+    fn test_length_limits() {
+        let label = "a".repeat(63);
+        assert!(name(&label).child("b").is_ok());
+        assert_eq!(
+            format!("{}b", label).parse::<DomainName>(),
+            Err(NameError::LabelTooLong(64))
+        );
+        assert_eq!(
+            DomainName::from_labels([vec![b'a'; 64]]),
+            Err(NameError::LabelTooLong(64))
+        );
+
+        // 3 labels of 63 octets and one of 61 make exactly 255 octets.
+        let max = name(&[label.as_str(); 3].join("."))
+            .child("b".repeat(61))
+            .unwrap();
+        assert_eq!(max.wire_len(), 255);
+        assert_eq!(max.child("c"), Err(NameError::NameTooLong(257)));
+    }
+
+    #[test]
+    fn test_binary_labels_and_escapes() {
+        let msg: &[u8] = &[3, 0xff, b'.', b'\\', 3, b'c', b'o', b'm', 0];
+        let binary = DomainName::read(&mut Bytes::copy_from_slice(msg), msg).unwrap();
+        assert_eq!(binary.labels()[0], [0xff, b'.', b'\\']);
+        assert_eq!(binary.to_string(), "\\255\\.\\\\.com");
+        assert_eq!(name(&binary.to_string()), binary);
+        assert_eq!(Bytes::from(&binary).as_ref(), msg);
+
+        assert_eq!(name("\\065b\\ c").labels(), [b"Ab c".to_vec()]);
+        assert_eq!(name("a\\.b.").labels(), [b"a.b".to_vec()]);
+        assert_eq!(name("a\\\\.").labels(), [b"a\\".to_vec()]);
+        assert_eq!(name("sp ace").to_string(), "sp\\032ace");
+        assert!("\\256".parse::<DomainName>().is_err());
+        assert!("\\06".parse::<DomainName>().is_err());
+        assert!("a\\".parse::<DomainName>().is_err());
+    }
+}
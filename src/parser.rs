@@ -3,19 +3,40 @@ use log::debug;
 use tokio_util::codec::{Decoder, Encoder};
 
 use crate::{
-    answer::{self, Answer},
-    header::Header,
-    question::{self, Question},
+    answer::Answer,
+    edns::{Edns, OPT_TYPE},
+    header::{Header, Rcode},
+    question::Question,
+    view::{DecodeError, MessageView},
 };
 
+/// A complete DNS message: header, question section and answer section, plus
+/// the OPT record when the message uses EDNS.
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct UdpPacket {
-    pub(crate) header: Header,
-    pub(crate) question: Vec<Question>,
-    pub(crate) answer: Option<Vec<Answer>>,
+pub struct UdpPacket {
+    pub header: Header,
+    pub question: Vec<Question>,
+    pub answer: Option<Vec<Answer>>,
+    pub edns: Option<Edns>,
 }
 
-pub(crate) struct Parser;
+impl UdpPacket {
+    /// Sets the header section counts from the sections actually present.
+    ///
+    /// Only the question and answer sections and the OPT record are carried,
+    /// so the authority count is always zero and the additional count is one
+    /// at most.
+    pub(crate) fn update_counts(&mut self) {
+        self.header.qdcount = self.question.len() as u16;
+        self.header.ancount = self.answer.as_ref().map_or(0, |a| a.len() as u16);
+        self.header.nscount = 0;
+        self.header.arcount = self.edns.is_some() as u16;
+    }
+}
+
+/// Codec that decodes datagrams into [`UdpPacket`]s and encodes them back.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct Parser;
 
 impl Parser {
     pub fn new() -> Self {
@@ -28,47 +49,87 @@ impl Decoder for Parser {
     type Error = std::io::Error;
 
     fn decode(&mut self, src: &mut bytes::BytesMut) -> Result<Option<Self::Item>, Self::Error> {
-        if src.len() < 12 {
+        if src.is_empty() {
             return Ok(None);
-        };
-        // TODO: Return None when invalid lengths
+        }
         debug!("DNS Request Bytes: {:02X?}", src.chunk());
-        let header = Header::from(&mut src.split_to(12).freeze());
+        // Malformed datagrams, including ones too short for a header, are
+        // dropped whole. Leaving bytes behind would make `UdpFramed` fail
+        // the stream.
+        match MessageView::new(src.split().freeze()).and_then(UdpPacket::try_from) {
+            Ok(packet) => Ok(Some(packet)),
+            Err(e) => {
+                debug!("Dropping malformed message: {}", e);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl TryFrom<MessageView> for UdpPacket {
+    type Error = DecodeError;
+
+    /// Fully decodes a message. Use the view directly when only a few fields
+    /// are needed.
+    fn try_from(view: MessageView) -> Result<Self, Self::Error> {
+        let mut edns = None;
+        for record in view.additionals()? {
+            let record = record?;
+            if record.typ == OPT_TYPE {
+                edns = Some(Edns::read(&record)?);
+                break;
+            }
+        }
+
+        // Compressed names point back into the whole message, so keep it around.
+        let msg = view.into_bytes();
+        let mut buf = msg.clone();
+        let mut header = Header::read(&mut buf)?;
         let mut questions = Vec::new();
         let mut answers: Vec<Answer> = Vec::new();
 
         for _i in 0..header.qdcount {
-            questions.push(Question::from(&mut *src));
+            questions.push(Question::read(&mut buf, &msg)?);
         }
 
         for _i in 0..header.ancount {
-            answers.push(Answer::from(&mut *src));
+            answers.push(Answer::read(&mut buf, &msg)?);
         }
 
-        src.advance(src.len());
-        Ok(Some(UdpPacket {
+        let edns = edns.map(|(edns, extended_rcode)| {
+            header.rcode = Rcode::from_parts(header.rcode.low(), extended_rcode);
+            edns
+        });
+        Ok(UdpPacket {
             header,
             question: questions,
             answer: Some(answers),
-        }))
+            edns,
+        })
     }
 }
 
 impl Encoder<UdpPacket> for Parser {
     type Error = std::io::Error;
 
-    fn encode(&mut self, item: UdpPacket, dst: &mut bytes::BytesMut) -> Result<(), Self::Error> {
+    fn encode(
+        &mut self,
+        mut item: UdpPacket,
+        dst: &mut bytes::BytesMut,
+    ) -> Result<(), Self::Error> {
+        item.update_counts();
+        let extended_rcode = item.header.rcode.high();
         dst.extend_from_slice(&Bytes::from(item.header));
         for q in item.question {
             dst.extend_from_slice(&Bytes::from(q));
         }
-        match item.answer {
-            Some(answer) => {
-                for a in answer {
-                    dst.extend_from_slice(&Bytes::from(a))
-                }
+        if let Some(answer) = item.answer {
+            for a in answer {
+                dst.extend_from_slice(&Bytes::from(a))
             }
-            None => (),
+        }
+        if let Some(edns) = &item.edns {
+            edns.write(extended_rcode, dst);
         }
         debug!("DNS Response Bytes: {:02X?}", dst.chunk());
         Ok(())
@@ -77,16 +138,16 @@ impl Encoder<UdpPacket> for Parser {
 
 #[cfg(test)]
 mod parser_tests {
-    use crate::question::LabelSequence;
-
     use super::*;
+    use crate::edns::{EdnsOption, ExtendedErrorCode};
+    use crate::header::Opcode;
 
     #[test]
     fn test_parser() {
         let mut parser = Parser;
         let mut buf = bytes::BytesMut::new();
         buf.extend_from_slice(&[
-            0x04, 0xd2, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 3, 119, 119, 119, 4, 116, 101, 115, 116,
+            0x04, 0xd2, 0x80, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, 119, 119, 119, 4, 116, 101, 115, 116,
             3, 99, 111, 109, 0, 0, 1, 0, 1, 3, 99, 111, 109, 0, 0, 1, 0, 1,
         ]);
 
@@ -96,10 +157,100 @@ mod parser_tests {
         assert_eq!(
             packet.unwrap().unwrap(),
             UdpPacket {
-                header: Header::new(1234, 0, 0, 0, 0, true, 0, false, false, false, false, 0, 0),
+                header: Header::new(
+                    1234,
+                    1,
+                    0,
+                    0,
+                    0,
+                    true,
+                    Opcode::Query,
+                    false,
+                    false,
+                    false,
+                    false,
+                    false,
+                    false,
+                    Rcode::NoError
+                ),
                 question: vec![Question::new("www.test.com".to_string(), 1, 1)],
-                answer: None,
+                answer: Some(vec![]),
+                edns: None,
             }
         )
     }
+
+    #[test]
+    fn test_encode_computes_counts() {
+        let mut parser = Parser;
+        let packet = UdpPacket {
+            header: Header::new(
+                1234,
+                0,
+                7,
+                3,
+                1,
+                true,
+                Opcode::Query,
+                false,
+                false,
+                false,
+                false,
+                false,
+                false,
+                Rcode::NoError,
+            ),
+            question: vec![Question::new("www.test.com".to_string(), 1, 1)],
+            answer: Some(vec![Answer::new(
+                "www.test.com".to_string(),
+                1,
+                1,
+                60,
+                "127.0.0.1".to_string(),
+            )]),
+            edns: None,
+        };
+        let mut buf = bytes::BytesMut::new();
+        parser.encode(packet, &mut buf).unwrap();
+        assert_eq!(&buf[4..12], &[0, 1, 0, 1, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_malformed_message_is_skipped() {
+        let mut buf =
+            bytes::BytesMut::from(&[0x04, 0xd2, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 3, 119][..]);
+        assert_eq!(Parser.decode(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_short_datagram_is_skipped() {
+        let mut buf = bytes::BytesMut::from(&b"abc"[..]);
+        assert_eq!(Parser.decode_eof(&mut buf).unwrap(), None);
+        assert!(buf.is_empty());
+    }
+
+    #[test]
+    fn test_edns_round_trip() {
+        let mut packet = crate::Message::query(1, Question::new("www.test.com".to_string(), 1, 1))
+            .rcode(Rcode::BadCookie)
+            .edns(Edns::default())
+            .extended_error(ExtendedErrorCode::NetworkError, "unreachable")
+            .build();
+        let mut buf = bytes::BytesMut::new();
+        Parser.encode(packet.clone(), &mut buf).unwrap();
+        assert_eq!(&buf[10..12], &[0, 1]);
+        // Only the low bits of BADCOOKIE fit in the header.
+        assert_eq!(buf[3] & 0x0f, 7);
+
+        packet.header.arcount = 1;
+        assert_eq!(Parser.decode(&mut buf).unwrap(), Some(packet.clone()));
+        assert_eq!(
+            packet.edns.unwrap().options,
+            vec![EdnsOption::ExtendedError {
+                code: ExtendedErrorCode::NetworkError,
+                text: "unreachable".to_string()
+            }]
+        );
+    }
 }
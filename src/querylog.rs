@@ -0,0 +1,453 @@
+use crate::config::{ConfigError, QueryLogConfig};
+use crate::handler::{ClientInfo, Response};
+use crate::metrics::METRICS;
+use log::{debug, error, info};
+use rust_dns::{QuestionType, RData, UdpPacket};
+use serde::Serialize;
+use std::fs::{File, OpenOptions};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc;
+use tokio::task::{spawn_blocking, JoinHandle};
+use tokio_util::sync::CancellationToken;
+
+/// One line of the query log.
+#[derive(Debug, PartialEq, Serialize)]
+pub(crate) struct Entry {
+    /// When the query arrived, RFC 3339 in UTC.
+    pub(crate) timestamp: String,
+    pub(crate) client: String,
+    pub(crate) protocol: &'static str,
+    pub(crate) qname: String,
+    pub(crate) qtype: String,
+    /// `None` when no response was sent.
+    pub(crate) rcode: Option<String>,
+    pub(crate) answers: Vec<String>,
+    pub(crate) latency_ms: f64,
+    /// The middleware that answered.
+    pub(crate) path: &'static str,
+}
+
+impl Entry {
+    pub(crate) fn new(
+        received: SystemTime,
+        client: ClientInfo,
+        request: &UdpPacket,
+        response: Option<&Response>,
+        latency: Duration,
+        path: &'static str,
+    ) -> Self {
+        let question = request.question.first();
+        Self {
+            timestamp: timestamp(received),
+            client: client.addr.to_string(),
+            protocol: client.protocol.as_str(),
+            qname: question.map(|q| q.qname.to_string()).unwrap_or_default(),
+            qtype: question.map(|q| type_name(q.qtype)).unwrap_or_default(),
+            rcode: response.map(|response| format!("{:?}", response.rcode())),
+            answers: response
+                .map(|response| {
+                    response
+                        .answers()
+                        .iter()
+                        .map(|answer| {
+                            format!("{} {}", type_name(answer.typ), summary(&answer.data))
+                        })
+                        .collect()
+                })
+                .unwrap_or_default(),
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            path,
+        }
+    }
+}
+
+/// The mnemonic of a type, or `TYPEnn` as in RFC 3597 for unknown ones.
+pub(crate) fn type_name(typ: QuestionType) -> String {
+    match typ {
+        QuestionType::Unknown(value) => format!("TYPE{}", value),
+        typ => format!("{:?}", typ),
+    }
+}
+
+fn summary(data: &RData) -> String {
+    match data {
+        RData::A(ip) => ip.to_string(),
+        RData::AAAA(ip) => ip.to_string(),
+        RData::NS(name) | RData::CNAME(name) | RData::PTR(name) => name.to_string(),
+        RData::MX {
+            preference,
+            exchange,
+        } => format!("{} {}", preference, exchange),
+        RData::SOA {
+            mname,
+            rname,
+            serial,
+            refresh,
+            retry,
+            expire,
+            minimum,
+        } => format!(
+            "{} {} {} {} {} {} {}",
+            mname, rname, serial, refresh, retry, expire, minimum
+        ),
+        RData::HINFO { cpu, os } => format!(
+            "\"{}\" \"{}\"",
+            String::from_utf8_lossy(cpu),
+            String::from_utf8_lossy(os)
+        ),
+        RData::Unknown(data) => format!("({} bytes)", data.len()),
+    }
+}
+
+/// Formats `time` as `2024-01-31T12:34:56.789Z`.
+fn timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, rest) = (secs / 86400, secs % 86400);
+    // Civil date from days since the epoch, Howard Hinnant's algorithm.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        rest / 3600,
+        rest % 3600 / 60,
+        rest % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Lines waiting to be written. Lines beyond this are dropped.
+const QUEUE_LEN: usize = 1024;
+
+/// The file side of the query log, owned by the writer task.
+///
+/// When the next line would take the file past `max_size` bytes it is
+/// renamed to `<path>.1`, older files move up by one, and anything past
+/// `<path>.<max_files>` is deleted.
+struct Writer {
+    path: PathBuf,
+    max_size: u64,
+    max_files: u32,
+    file: BufWriter<File>,
+    size: u64,
+}
+
+impl Writer {
+    fn open(path: &Path, config: &QueryLogConfig) -> Result<Self, ConfigError> {
+        let file = append(path)?;
+        let size = file.metadata().map(|meta| meta.len()).unwrap_or(0);
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_size: config.max_size,
+            max_files: config.max_files,
+            file: BufWriter::new(file),
+            size,
+        })
+    }
+
+    /// Appends `line`. Failures are logged rather than returned, since a
+    /// broken log must not stop queries from being answered.
+    fn write(&mut self, line: &[u8]) {
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            match self.rotate() {
+                Ok(file) => {
+                    self.file = BufWriter::new(file);
+                    self.size = 0;
+                }
+                Err(e) => error!("Failed to rotate query log {:?}: {}", self.path, e),
+            }
+        }
+        match self.file.write_all(line) {
+            Ok(()) => self.size += line.len() as u64,
+            Err(e) => error!("Failed to write query log {:?}: {}", self.path, e),
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.file.flush() {
+            error!("Failed to write query log {:?}: {}", self.path, e);
+        }
+    }
+
+    fn rotated(&self, n: u32) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    /// Flushes the current file, shifts the rotated files up by one and
+    /// starts a new file.
+    fn rotate(&mut self) -> std::io::Result<File> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            std::fs::remove_file(&self.path)?;
+        } else {
+            for n in (1..self.max_files).rev() {
+                let from = self.rotated(n);
+                if from.exists() {
+                    std::fs::rename(&from, self.rotated(n + 1))?;
+                }
+            }
+            std::fs::rename(&self.path, self.rotated(1))?;
+        }
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+    }
+}
+
+fn append(path: &Path) -> Result<File, ConfigError> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|source| ConfigError::Open {
+            path: path.to_path_buf(),
+            source,
+        })
+}
+
+/// Writes whatever arrives on `rx` in batches on the blocking thread pool,
+/// flushing after each one. Once `done` is cancelled the lines already
+/// queued are written and the file is flushed one last time.
+async fn run(mut writer: Writer, mut rx: mpsc::Receiver<Vec<u8>>, done: CancellationToken) {
+    let mut batch = Vec::new();
+    loop {
+        tokio::select! {
+            received = rx.recv_many(&mut batch, QUEUE_LEN) => {
+                if received == 0 {
+                    return;
+                }
+            }
+            _ = done.cancelled(), if !rx.is_closed() => {
+                rx.close();
+                continue;
+            }
+        }
+        let lines = std::mem::take(&mut batch);
+        writer = match spawn_blocking(move || {
+            for line in &lines {
+                writer.write(line);
+            }
+            writer.flush();
+            writer
+        })
+        .await
+        {
+            Ok(writer) => writer,
+            Err(e) => {
+                error!("Query log writer failed: {}", e);
+                return;
+            }
+        };
+    }
+}
+
+/// Writes one JSON object per query to a file, apart from the debug log.
+///
+/// Logging never waits on the file: lines are queued for a single writer
+/// task, and dropped and counted when it falls behind. The writer lives for
+/// the whole process, so reloads never open the file a second time.
+pub(crate) struct QueryLog {
+    tx: mpsc::Sender<Vec<u8>>,
+}
+
+impl QueryLog {
+    fn spawn(writer: Writer, done: CancellationToken) -> (Self, JoinHandle<()>) {
+        let (tx, rx) = mpsc::channel(QUEUE_LEN);
+        (Self { tx }, tokio::spawn(run(writer, rx, done)))
+    }
+
+    pub(crate) fn write(&self, entry: &Entry) {
+        let mut line = match serde_json::to_vec(entry) {
+            Ok(line) => line,
+            Err(e) => {
+                error!("Failed to serialize query log entry: {}", e);
+                return;
+            }
+        };
+        line.push(b'\n');
+        if self.tx.try_send(line).is_err() {
+            METRICS.query_log_dropped.inc();
+            debug!("Dropped a query log entry");
+        }
+    }
+}
+
+/// The process wide query log, set up once by [`start`].
+static QUERY_LOG: OnceLock<QueryLog> = OnceLock::new();
+
+/// Opens the query log if `config` asks for one and starts its writer, which
+/// finishes once `done` is cancelled. Must be called from within the
+/// runtime; the file cannot be changed afterwards.
+pub(crate) fn start(
+    config: &QueryLogConfig,
+    done: CancellationToken,
+) -> Result<Option<JoinHandle<()>>, ConfigError> {
+    let Some(path) = &config.path else {
+        return Ok(None);
+    };
+    let (query_log, writer) = QueryLog::spawn(Writer::open(path, config)?, done);
+    info!("Logging queries to {:?}", path);
+    let _ = QUERY_LOG.set(query_log);
+    Ok(Some(writer))
+}
+
+pub(crate) fn enabled() -> bool {
+    QUERY_LOG.get().is_some()
+}
+
+/// Logs `entry` if the query log is enabled.
+pub(crate) fn log(entry: &Entry) {
+    if let Some(query_log) = QUERY_LOG.get() {
+        query_log.write(entry);
+    }
+}
+
+#[cfg(test)]
+mod querylog_tests {
+    use super::*;
+    use crate::testutil::{client, temp_path};
+    use rust_dns::{Answer, Message, Question, Rcode};
+
+    #[test]
+    fn test_timestamp() {
+        assert_eq!(timestamp(UNIX_EPOCH), "1970-01-01T00:00:00.000Z");
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_millis(1_709_210_096_123)),
+            "2024-02-29T12:34:56.123Z"
+        );
+        assert_eq!(
+            timestamp(UNIX_EPOCH + Duration::from_secs(4_102_444_799)),
+            "2099-12-31T23:59:59.000Z"
+        );
+    }
+
+    #[test]
+    fn test_entry() {
+        let request = Message::query(1, Question::new("example.com".to_string(), 1, 1)).build();
+        let response = Message::response_to(&request)
+            .answer(Answer::new(
+                "example.com".to_string(),
+                1,
+                1,
+                60,
+                "93.184.216.34".to_string(),
+            ))
+            .build();
+        let entry = Entry::new(
+            UNIX_EPOCH,
+            client(),
+            &request,
+            Some(&Response::from(response)),
+            Duration::from_micros(1500),
+            "forward",
+        );
+        let json: serde_json::Value = serde_json::to_value(&entry).unwrap();
+        assert_eq!(
+            json,
+            serde_json::json!({
+                "timestamp": "1970-01-01T00:00:00.000Z",
+                "client": "127.0.0.1:5353",
+                "protocol": "udp",
+                "qname": "example.com",
+                "qtype": "A",
+                "rcode": "NoError",
+                "answers": ["A 93.184.216.34"],
+                "latency_ms": 1.5,
+                "path": "forward",
+            })
+        );
+
+        let request = Message::query(1, Question::new("example.com".to_string(), 99, 1)).build();
+        let entry = Entry::new(UNIX_EPOCH, client(), &request, None, Duration::ZERO, "rrl");
+        assert_eq!(entry.qtype, "TYPE99");
+        assert_eq!(entry.rcode, None);
+        let response = Message::response_to(&request)
+            .rcode(Rcode::NXDomain)
+            .build();
+        let entry = Entry::new(
+            UNIX_EPOCH,
+            client(),
+            &request,
+            Some(&Response::from(response)),
+            Duration::ZERO,
+            "blocklist",
+        );
+        assert_eq!(entry.rcode.as_deref(), Some("NXDomain"));
+        assert!(entry.answers.is_empty());
+    }
+
+    #[test]
+    fn test_rotation() {
+        let dir = temp_path("querylog");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("queries.log");
+        let request = Message::query(1, Question::new("example.com".to_string(), 1, 1)).build();
+        let entry = Entry::new(UNIX_EPOCH, client(), &request, None, Duration::ZERO, "rrl");
+        let line_len = serde_json::to_vec(&entry).unwrap().len() as u64 + 1;
+
+        let mut writer = Writer::open(
+            &path,
+            &QueryLogConfig {
+                path: Some(path.clone()),
+                max_size: line_len * 2,
+                max_files: 2,
+            },
+        )
+        .unwrap();
+        let mut line = serde_json::to_vec(&entry).unwrap();
+        line.push(b'\n');
+        for _ in 0..7 {
+            writer.write(&line);
+        }
+        writer.flush();
+        let lines = |path: &Path| std::fs::read_to_string(path).unwrap().lines().count();
+        assert_eq!(lines(&path), 1);
+        assert_eq!(lines(&dir.join("queries.log.1")), 2);
+        assert_eq!(lines(&dir.join("queries.log.2")), 2);
+        assert!(!dir.join("queries.log.3").exists());
+
+        let line = std::fs::read_to_string(&path).unwrap();
+        let json: serde_json::Value = serde_json::from_str(line.trim()).unwrap();
+        assert_eq!(json["qname"], "example.com");
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_writer_drains_queue_when_done() {
+        let path = temp_path("querylog-run");
+        let _ = std::fs::remove_file(&path);
+        let config = QueryLogConfig {
+            path: Some(path.clone()),
+            ..QueryLogConfig::default()
+        };
+        let done = CancellationToken::new();
+        let (log, writer) = QueryLog::spawn(Writer::open(&path, &config).unwrap(), done.clone());
+        let request = Message::query(1, Question::new("example.com".to_string(), 1, 1)).build();
+        let entry = Entry::new(UNIX_EPOCH, client(), &request, None, Duration::ZERO, "rrl");
+        for _ in 0..3 {
+            log.write(&entry);
+        }
+        done.cancel();
+        writer.await.unwrap();
+        let content = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(content.lines().count(), 3);
+        let _ = std::fs::remove_file(&path);
+    }
+}
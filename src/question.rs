@@ -1,14 +1,24 @@
 use bytes::{Buf, Bytes, BytesMut};
-use log::debug;
 
-#[derive(Debug, PartialEq, Clone)]
-pub(crate) enum QuestionType {
+use crate::name::DomainName;
+use crate::view::DecodeError;
+
+/// Record type (TYPE/QTYPE) of a question or resource record.
+#[allow(clippy::upper_case_acronyms)]
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum QuestionType {
     A,
     AAAA,
     NS,
     CNAME,
+    SOA,
+    MX,
     SRV,
     PTR,
+    HINFO,
+    /// QTYPE 255, a request for all records (RFC 1035 section 3.2.3).
+    ANY,
+    Unknown(u16),
 }
 
 impl From<QuestionType> for Bytes {
@@ -18,12 +28,24 @@ impl From<QuestionType> for Bytes {
             QuestionType::AAAA => Bytes::from_static(&[0, 28]),
             QuestionType::NS => Bytes::from_static(&[0, 2]),
             QuestionType::CNAME => Bytes::from_static(&[0, 5]),
+            QuestionType::SOA => Bytes::from_static(&[0, 6]),
+            QuestionType::MX => Bytes::from_static(&[0, 15]),
             QuestionType::SRV => Bytes::from_static(&[0, 33]),
             QuestionType::PTR => Bytes::from_static(&[0, 12]),
+            QuestionType::HINFO => Bytes::from_static(&[0, 13]),
+            QuestionType::ANY => Bytes::from_static(&[0, 255]),
+            QuestionType::Unknown(value) => Bytes::copy_from_slice(&value.to_be_bytes()),
         }
     }
 }
 
+impl From<QuestionType> for u16 {
+    fn from(value: QuestionType) -> Self {
+        let bytes = Bytes::from(value);
+        u16::from_be_bytes([bytes[0], bytes[1]])
+    }
+}
+
 impl From<u16> for QuestionType {
     fn from(value: u16) -> Self {
         match value {
@@ -31,19 +53,25 @@ impl From<u16> for QuestionType {
             28 => QuestionType::AAAA,
             2 => QuestionType::NS,
             5 => QuestionType::CNAME,
+            6 => QuestionType::SOA,
+            15 => QuestionType::MX,
             33 => QuestionType::SRV,
             12 => QuestionType::PTR,
-            _ => panic!("Invalid QuestionType"),
+            13 => QuestionType::HINFO,
+            255 => QuestionType::ANY,
+            _ => QuestionType::Unknown(value),
         }
     }
 }
 
-#[derive(Debug, PartialEq, Clone)]
-pub(crate) enum QuestionClass {
+/// Record class (CLASS/QCLASS) of a question or resource record.
+#[derive(Debug, PartialEq, Eq, Hash, Clone, Copy)]
+pub enum QuestionClass {
     IN,
     CS,
     CH,
     HS,
+    Unknown(u16),
     // IN = 1,
     // CH = 3,
     // HS = 4,
@@ -57,6 +85,7 @@ impl From<QuestionClass> for Bytes {
             QuestionClass::CS => Bytes::from_static(&[0, 2]),
             QuestionClass::CH => Bytes::from_static(&[0, 3]),
             QuestionClass::HS => Bytes::from_static(&[0, 4]),
+            QuestionClass::Unknown(value) => Bytes::copy_from_slice(&value.to_be_bytes()),
         }
     }
 }
@@ -68,125 +97,56 @@ impl From<u16> for QuestionClass {
             2 => QuestionClass::CS,
             3 => QuestionClass::CH,
             4 => QuestionClass::HS,
-            _ => panic!("Invalid QuestionClass"),
-        }
-    }
-}
-
-#[derive(Debug, PartialEq, Clone)]
-pub(crate) enum Label {
-    Pointer(LabelPointer),
-    Sequence(LabelSequence),
-}
-
-#[derive(Debug, PartialEq, Clone)]
-pub(crate) struct LabelPointer {
-    pub(crate) pointer: u16,
-}
-
-#[derive(Debug, PartialEq, Clone)]
-pub(crate) struct LabelSequence {
-    pub(crate) content: String,
-    pub(crate) length: u8,
-}
-
-impl LabelSequence {
-    pub(crate) fn new(content: String) -> Self {
-        LabelSequence {
-            length: content.len() as u8,
-            content,
+            _ => QuestionClass::Unknown(value),
         }
     }
 }
 
+/// An entry of the question section.
 #[derive(Debug, PartialEq, Clone)]
-pub(crate) struct Question {
-    pub(crate) qname: Vec<Label>,
-    pub(crate) qtype: QuestionType,
-    pub(crate) qclass: QuestionClass,
+pub struct Question {
+    pub qname: DomainName,
+    pub qtype: QuestionType,
+    pub qclass: QuestionClass,
 }
 
 impl Question {
-    pub(crate) fn new(qname: String, qtype: u16, qclass: u16) -> Self {
-        let mut labels = Vec::new();
-        for label in qname.split('.') {
-            labels.push(Label::Sequence(LabelSequence {
-                content: label.to_string(),
-                length: label.len() as u8,
-            }));
-        }
+    /// Panics if `qname` is not a valid domain name.
+    pub fn new(qname: String, qtype: u16, qclass: u16) -> Self {
         Question {
-            qname: labels,
+            qname: qname.parse().expect("Invalid domain name"),
             qtype: QuestionType::from(qtype),
             qclass: QuestionClass::from(qclass),
         }
     }
+
+    /// Reads a question from the front of `buf`, resolving compressed names
+    /// against `msg`. See [`DomainName::read`].
+    pub fn read<B: Buf>(buf: &mut B, msg: &[u8]) -> Result<Self, DecodeError> {
+        let qname = DomainName::read(buf, msg)?;
+        if buf.remaining() < 4 {
+            return Err(DecodeError::Truncated);
+        }
+        let qtype = QuestionType::from(buf.get_u16());
+        let qclass = QuestionClass::from(buf.get_u16());
+        Ok(Question {
+            qname,
+            qtype,
+            qclass,
+        })
+    }
 }
 
 impl From<Question> for Bytes {
     fn from(value: Question) -> Self {
         let mut bytes = BytesMut::new();
-
-        for label in &value.qname {
-            match label {
-                Label::Pointer(pointer) => {
-                    bytes.extend_from_slice(&[0b1100_0000 | (pointer.pointer >> 8) as u8]);
-                    bytes.extend_from_slice(&[pointer.pointer as u8]);
-                }
-                Label::Sequence(sequence) => {
-                    bytes.extend_from_slice(&[sequence.length]);
-                    bytes.extend_from_slice(sequence.content.as_bytes());
-                }
-            }
-        }
-        if let Label::Sequence(_) = value.qname.last().unwrap() {
-            bytes.extend_from_slice(&[0])
-        }
+        value.qname.write(&mut bytes);
         bytes.extend_from_slice(&Bytes::from(value.qtype));
         bytes.extend_from_slice(&Bytes::from(value.qclass));
         bytes.freeze()
     }
 }
 
-impl<B: Buf> From<&mut B> for Question {
-    fn from(value: &mut B) -> Self {
-        let mut labels: Vec<Label> = Vec::new();
-        loop {
-            let first_byte = value.chunk()[0];
-            match (first_byte & 0b1100_0000) >> 6 {
-                0 => {
-                    if first_byte == b'\0' {
-                        value.advance(1);
-                        break;
-                    }
-                    let length = value.get_u8() as usize;
-                    let mut content = String::new();
-                    let label_bytes = value.copy_to_bytes(length).to_vec();
-                    content.push_str(String::from_utf8(label_bytes.to_vec()).unwrap().as_str()); // TODO: Handle errors here
-                    labels.push(Label::Sequence(LabelSequence {
-                        content,
-                        length: length as u8,
-                    }));
-                }
-                3 => {
-                    let pointer =
-                        ((value.get_u8() & 0b0011_1111) as u16) << 8 | value.get_u8() as u16;
-                    labels.push(Label::Pointer(LabelPointer { pointer }));
-                    break;
-                }
-                _ => panic!("Invalid Label"),
-            }
-        }
-        let qtype = QuestionType::from(value.get_u16());
-        let qclass = QuestionClass::from(value.get_u16());
-        Question {
-            qname: labels,
-            qtype,
-            qclass,
-        }
-    }
-}
-
 #[cfg(test)]
 mod question_class_tests {
     use super::*;
@@ -205,9 +165,11 @@ mod question_class_tests {
         assert_eq!(QuestionClass::CS, QuestionClass::from(2));
         assert_eq!(QuestionClass::CH, QuestionClass::from(3));
         assert_eq!(QuestionClass::HS, QuestionClass::from(4));
+        assert_eq!(QuestionClass::Unknown(254), QuestionClass::from(254));
     }
 }
 
+#[cfg(test)]
 mod question_type_tests {
     use super::*;
 
@@ -225,6 +187,14 @@ mod question_type_tests {
         );
         assert_eq!(Bytes::from(QuestionType::SRV), Bytes::from_static(&[0, 33]));
         assert_eq!(Bytes::from(QuestionType::PTR), Bytes::from_static(&[0, 12]));
+        assert_eq!(
+            Bytes::from(QuestionType::HINFO),
+            Bytes::from_static(&[0, 13])
+        );
+        assert_eq!(
+            Bytes::from(QuestionType::ANY),
+            Bytes::from_static(&[0, 255])
+        );
     }
 
     #[test]
@@ -235,9 +205,17 @@ mod question_type_tests {
         assert_eq!(QuestionType::CNAME, QuestionType::from(5));
         assert_eq!(QuestionType::SRV, QuestionType::from(33));
         assert_eq!(QuestionType::PTR, QuestionType::from(12));
+        assert_eq!(QuestionType::HINFO, QuestionType::from(13));
+        assert_eq!(QuestionType::ANY, QuestionType::from(255));
+        assert_eq!(QuestionType::Unknown(99), QuestionType::from(99));
+        assert_eq!(
+            Bytes::from(QuestionType::Unknown(99)),
+            Bytes::from_static(&[0, 99])
+        );
     }
 }
 
+#[cfg(test)]
 mod question_tests {
     use super::*;
 
@@ -245,17 +223,8 @@ mod question_tests {
     fn test_new() {
         let question = Question::new("codecrafters.io".to_string(), 1, 1);
         assert_eq!(
-            question.qname,
-            vec![
-                Label::Sequence(LabelSequence {
-                    content: "codecrafters".to_string(),
-                    length: 12,
-                }),
-                Label::Sequence(LabelSequence {
-                    content: "io".to_string(),
-                    length: 2,
-                }),
-            ]
+            question.qname.labels(),
+            [b"codecrafters".to_vec(), b"io".to_vec()]
         );
         assert_eq!(question.qtype, QuestionType::A);
         assert_eq!(question.qclass, QuestionClass::IN);
@@ -263,45 +232,12 @@ mod question_tests {
 
     #[test]
     fn test_question_to_bytes() {
-        let bytes_sample: [u8; 20] = [
-            3,
-            119,
-            119,
-            119,
-            4,
-            116,
-            101,
-            115,
-            116,
-            3,
-            99,
-            111,
-            109,
-            0b1100_0000 | 0x12,
-            0x34,
-            0,
-            0,
-            1,
-            0,
-            1,
+        let bytes_sample: [u8; 18] = [
+            3, 119, 119, 119, 4, 116, 101, 115, 116, 3, 99, 111, 109, 0, 0, 1, 0, 1,
         ];
 
         let question = Question {
-            qname: vec![
-                Label::Sequence(LabelSequence {
-                    content: "www".to_string(),
-                    length: 3,
-                }),
-                Label::Sequence(LabelSequence {
-                    content: "test".to_string(),
-                    length: 4,
-                }),
-                Label::Sequence(LabelSequence {
-                    content: "com".to_string(),
-                    length: 3,
-                }),
-                Label::Pointer(LabelPointer { pointer: 0x1234 }),
-            ],
+            qname: "www.test.com".parse().unwrap(),
             qtype: QuestionType::A,
             qclass: QuestionClass::IN,
         };
@@ -310,13 +246,12 @@ mod question_tests {
 
     #[test]
     fn test_question_from_bytes() {
-        let bytes_sample: [u8; 22] = [
+        // Two questions, the second one compressed against the first.
+        let bytes_sample: [u8; 28] = [
             3,
             119,
             119,
             119,
-            0b1100_0000 | 0x12,
-            0x34,
             4,
             116,
             101,
@@ -326,38 +261,38 @@ mod question_tests {
             99,
             111,
             109,
-            0b1100_0000 | 0x12,
-            0x34,
             0,
             0,
             1,
             0,
             1,
+            3,
+            102,
+            116,
+            112,
+            0b1100_0000,
+            4,
+            0,
+            1,
+            0,
+            1,
         ];
 
-        let question = Question {
-            qname: vec![
-                Label::Sequence(LabelSequence {
-                    content: "www".to_string(),
-                    length: 3,
-                }),
-                Label::Pointer(LabelPointer { pointer: 0x1234 }),
-                Label::Sequence(LabelSequence {
-                    content: "test".to_string(),
-                    length: 4,
-                }),
-                Label::Sequence(LabelSequence {
-                    content: "com".to_string(),
-                    length: 3,
-                }),
-                Label::Pointer(LabelPointer { pointer: 0x1234 }),
-            ],
-            qtype: QuestionType::A,
-            qclass: QuestionClass::IN,
-        };
+        let mut buf = Bytes::copy_from_slice(&bytes_sample);
+        assert_eq!(
+            Question::read(&mut buf, &bytes_sample),
+            Ok(Question::new("www.test.com".to_string(), 1, 1))
+        );
+        assert_eq!(
+            Question::read(&mut buf, &bytes_sample),
+            Ok(Question::new("ftp.test.com".to_string(), 1, 1))
+        );
+        assert!(buf.is_empty());
+
+        let mut buf = Bytes::copy_from_slice(&bytes_sample[..16]);
         assert_eq!(
-            Question::from(&mut Bytes::copy_from_slice(&bytes_sample)),
-            question
+            Question::read(&mut buf, &bytes_sample[..16]),
+            Err(DecodeError::Truncated)
         );
     }
 }
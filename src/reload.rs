@@ -0,0 +1,141 @@
+use crate::config::Config;
+use crate::server::{ServerState, StateHandle};
+use log::{error, info, warn};
+use std::sync::{Arc, Mutex};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// Produces a freshly read and validated [`Config`], including any CLI overrides.
+pub(crate) type ConfigLoader = Box<dyn Fn() -> anyhow::Result<Config> + Send + Sync>;
+
+/// Re-reads the configuration and atomically swaps it into a running server.
+pub(crate) struct Reloader {
+    loader: ConfigLoader,
+    state: StateHandle,
+    current: Mutex<Config>,
+}
+
+impl Reloader {
+    pub(crate) fn new(config: Config, loader: ConfigLoader, state: StateHandle) -> Self {
+        Self {
+            loader,
+            state,
+            current: Mutex::new(config),
+        }
+    }
+
+    /// Builds a new [`ServerState`] from the reloaded configuration and stores
+    /// it. If loading or validation fails the running state is left untouched.
+    ///
+    /// Reloads are serialised, so a slow reload can never store an older
+    /// config over the one a later reload read. Sockets are never rebound, so
+    /// changes to the listen address, the control socket, the query log, the
+    /// dnstap output, the metrics listener or the log level only take effect
+    /// after a restart.
+    ///
+    /// This blocks on file reads and name resolution; async callers should
+    /// use [`Reloader::reload_blocking`].
+    pub(crate) fn reload(&self) -> anyhow::Result<()> {
+        let mut current = self.current.lock().unwrap();
+        let config = (self.loader)()?;
+        let state = ServerState::from_config(&config)?;
+
+        if config.server != current.server {
+            warn!("Changes to the [server] section require a restart");
+        }
+        if config.control != current.control {
+            warn!("Changes to the [control] section require a restart");
+        }
+        if config.metrics != current.metrics {
+            warn!("Changes to the [metrics] section require a restart");
+        }
+        if config.query_log != current.query_log {
+            warn!("Changes to the [query_log] section require a restart");
+        }
+        if config.dnstap != current.dnstap {
+            warn!("Changes to the [dnstap] section require a restart");
+        }
+        if config.log != current.log {
+            warn!("Changes to the [log] section require a restart");
+        }
+        let chain = state.chain.names().join(" -> ");
+        self.state.store(state);
+        *current = config;
+        info!("Configuration reloaded, middleware chain: {}", chain);
+        Ok(())
+    }
+
+    /// Runs [`Reloader::reload`] on the blocking thread pool.
+    pub(crate) async fn reload_blocking(self: Arc<Self>) -> anyhow::Result<()> {
+        tokio::task::spawn_blocking(move || self.reload()).await?
+    }
+}
+
+/// Reloads the configuration every time the process receives SIGHUP.
+pub(crate) async fn reload_on_sighup(reloader: Arc<Reloader>) {
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        info!("Received SIGHUP, reloading configuration");
+        if let Err(e) = reloader.clone().reload_blocking().await {
+            error!(
+                "Failed to reload configuration, keeping the current one: {:#}",
+                e
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod reload_tests {
+    use super::*;
+
+    fn reloader(content: Arc<Mutex<String>>) -> Reloader {
+        let config = Config::parse(&content.lock().unwrap()).unwrap();
+        let state = StateHandle::new(ServerState::from_config(&config).unwrap());
+        Reloader::new(
+            config,
+            Box::new(move || Ok(Config::parse(&content.lock().unwrap())?)),
+            state,
+        )
+    }
+
+    #[test]
+    fn test_reload_swaps_state() {
+        let content = Arc::new(Mutex::new(String::new()));
+        let reloader = reloader(content.clone());
+        assert_eq!(
+            reloader.state.load().chain.names(),
+            vec![
+                "rrl",
+                "any",
+                "hosts",
+                "blocklist",
+                "rebind",
+                "forward",
+                "fallback"
+            ]
+        );
+
+        *content.lock().unwrap() = "[server]\nmiddleware = [\"fallback\"]\n".to_string();
+        reloader.reload().unwrap();
+        assert_eq!(reloader.state.load().chain.names(), vec!["fallback"]);
+    }
+
+    #[test]
+    fn test_failed_reload_keeps_state() {
+        let content = Arc::new(Mutex::new(
+            "[upstream]\nresolver = \"1.1.1.1:53\"\n".to_string(),
+        ));
+        let reloader = reloader(content.clone());
+        let before = reloader.state.load();
+
+        *content.lock().unwrap() = "[upstream]\nresolver = \"not an address\"\n".to_string();
+        assert!(reloader.reload().is_err());
+        assert!(Arc::ptr_eq(&before, &reloader.state.load()));
+    }
+}
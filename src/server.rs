@@ -1,54 +1,169 @@
-use crate::answer::{Answer, RData};
-use crate::header::Header;
-use crate::parser::{Parser, UdpPacket};
-use crate::question::{QuestionClass, QuestionType};
-use futures::stream::{SplitSink, SplitStream};
+use crate::config::{Config, ConfigError};
+use crate::dnstap::{self, MessageType};
+use crate::handler::{ClientInfo, Protocol, RequestHandler, Response};
+use crate::metrics::METRICS;
+use crate::middleware::Chain;
+use crate::querylog::{self, type_name, Entry};
+use async_trait::async_trait;
+use bytes::{Bytes, BytesMut};
 use futures::{SinkExt, StreamExt};
+use ipnet::IpNet;
 use log::{debug, error, info};
-use std::net::{Ipv4Addr, SocketAddr, ToSocketAddrs};
+use rust_dns::{MessageView, UdpPacket};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use thiserror::Error;
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+use tokio_util::codec::{Decoder, Encoder};
+use tokio_util::sync::CancellationToken;
+use tokio_util::task::TaskTracker;
 use tokio_util::udp::UdpFramed;
 
+#[derive(Debug, Error)]
+pub(crate) enum ServerError {
+    #[error("Failed to bind UDP listener: {0}")]
+    Bind(std::io::Error),
+    #[error("{0} queries were still in flight after the {1:?} shutdown timeout")]
+    ShutdownTimeout(usize, Duration),
+    #[error("Responses were still being sent after the {0:?} shutdown timeout")]
+    ResponderTimeout(Duration),
+}
+
+/// The chain used for clients in any of `networks`.
+pub(crate) struct PolicyGroup {
+    pub(crate) name: String,
+    pub(crate) networks: Vec<IpNet>,
+    pub(crate) chain: Chain,
+}
+
+/// Everything a query needs that can be replaced by a config reload.
+pub(crate) struct ServerState {
+    /// The chain for clients that are not in any group.
+    pub(crate) chain: Chain,
+    pub(crate) groups: Vec<PolicyGroup>,
+}
+
+impl ServerState {
+    pub(crate) fn from_config(config: &Config) -> Result<Self, ConfigError> {
+        let mut groups = Vec::new();
+        for group in &config.groups {
+            groups.push(PolicyGroup {
+                name: group.name.clone(),
+                networks: group.networks.clone(),
+                chain: Chain::from_config(&config.for_group(group))?,
+            });
+        }
+        Ok(Self {
+            chain: Chain::from_config(config)?,
+            groups,
+        })
+    }
+
+    /// The chain of the first group containing `addr`, or the default one.
+    pub(crate) fn chain_for(&self, addr: IpAddr) -> &Chain {
+        let addr = addr.to_canonical();
+        match self
+            .groups
+            .iter()
+            .find(|group| group.networks.iter().any(|net| net.contains(&addr)))
+        {
+            Some(group) => {
+                debug!("{} is in policy group '{}'", addr, group.name);
+                &group.chain
+            }
+            None => &self.chain,
+        }
+    }
+}
+
+/// Shared handle to the current [`ServerState`].
+///
+/// Queries take a snapshot with [`StateHandle::load`] and keep using it even if
+/// a reload swaps in a new state halfway through.
+#[derive(Clone)]
+pub(crate) struct StateHandle(Arc<RwLock<Arc<ServerState>>>);
+
+impl StateHandle {
+    pub(crate) fn new(state: ServerState) -> Self {
+        Self(Arc::new(RwLock::new(Arc::new(state))))
+    }
+
+    pub(crate) fn load(&self) -> Arc<ServerState> {
+        self.0.read().unwrap().clone()
+    }
+
+    pub(crate) fn store(&self, state: ServerState) {
+        *self.0.write().unwrap() = Arc::new(state);
+    }
+}
+
+#[async_trait]
+impl RequestHandler for StateHandle {
+    async fn handle(&self, request: UdpPacket, client: ClientInfo) -> Option<Response> {
+        let state = self.load();
+        let chain = state.chain_for(client.addr.ip());
+        if !querylog::enabled() {
+            return chain.handle(request, client).await;
+        }
+        let received = SystemTime::now();
+        let started = Instant::now();
+        let (response, path) = chain.handle_traced(request.clone(), client).await;
+        querylog::log(&Entry::new(
+            received,
+            client,
+            &request,
+            response.as_ref(),
+            started.elapsed(),
+            path,
+        ));
+        response
+    }
+}
+
+/// Receives queries on a UDP socket and sends back whatever its
+/// [`RequestHandler`] answers.
 pub(crate) struct DnsServer {
     socket: SocketAddr,
-    resolver: Option<SocketAddr>,
+    handler: Arc<dyn RequestHandler>,
+    shutdown_timeout: Duration,
 }
 
 impl DnsServer {
-    pub(crate) fn new(addr: String, port: u16, resolver: Option<String>) -> Self {
-        let resolver_socket = match resolver {
-            Some(addr) => {
-                let parts: Vec<&str> = addr.split_terminator(":").collect();
-                let (host_str, port_str) = (parts[0], parts[1]);
-                Some(
-                    format!("{host_str}:{port_str}")
-                        .to_socket_addrs()
-                        .expect("Invalid socket address")
-                        .next()
-                        .unwrap(),
-                )
-            }
-            None => None,
-        };
+    pub(crate) fn new(socket: SocketAddr, handler: Arc<dyn RequestHandler>) -> Self {
         Self {
-            socket: format!("{addr}:{port}")
-                .to_socket_addrs()
-                .expect("Invalid socket address")
-                .next()
-                .unwrap(),
-            resolver: resolver_socket,
+            socket,
+            handler,
+            shutdown_timeout: Duration::from_secs(5),
         }
     }
 
-    pub(crate) async fn run(&self) {
-        let udp_socket = match UdpSocket::bind(self.socket).await {
-            Ok(listener) => listener,
-            Err(e) => {
-                error!("Failed to bind UDP listener: {}", e);
-                return;
-            }
-        };
+    pub(crate) fn from_config(
+        config: &Config,
+        handler: Arc<dyn RequestHandler>,
+    ) -> Result<Self, ConfigError> {
+        Ok(Self::new(config.listen_addr()?, handler)
+            .with_shutdown_timeout(Duration::from_secs(config.server.shutdown_timeout)))
+    }
+
+    /// Sets how long [`DnsServer::run`] waits for in-flight queries once
+    /// shutdown has been requested.
+    pub(crate) fn with_shutdown_timeout(mut self, shutdown_timeout: Duration) -> Self {
+        self.shutdown_timeout = shutdown_timeout;
+        self
+    }
+
+    /// Serves queries until `shutdown` is cancelled or the socket fails.
+    ///
+    /// On shutdown the listener stops accepting new queries, in-flight queries
+    /// get until the shutdown timeout to finish and every response they produce
+    /// is flushed before returning.
+    pub(crate) async fn run(&self, shutdown: CancellationToken) -> Result<(), ServerError> {
+        let udp_socket = UdpSocket::bind(self.socket)
+            .await
+            .map_err(ServerError::Bind)?;
 
         info!(
             "DNS Server is running on {}:{}",
@@ -56,130 +171,279 @@ impl DnsServer {
             self.socket.port(),
         );
 
-        let framed = UdpFramed::new(udp_socket, Parser::new());
+        let framed = UdpFramed::new(udp_socket, Codec);
         let (mut sink, mut stream) = framed.split();
 
-        let (tx, mut rx) = mpsc::channel::<(UdpPacket, SocketAddr)>(100);
+        // Responses travel with the dnstap CLIENT_QUERY they answer, if any, so
+        // that the CLIENT_RESPONSE carries the bytes actually sent.
+        let (tx, mut rx) = mpsc::channel::<(Response, SocketAddr, Option<dnstap::Message>)>(100);
 
-        tokio::spawn(async move {
-            while let Some((response, addr)) = rx.recv().await {
-                debug!("Responding with {:?} packet to {}", response, addr);
-                if let Err(er) = sink.send((response, addr)).await {
+        let responder = tokio::spawn(async move {
+            while let Some((response, addr, query)) = rx.recv().await {
+                debug!("Responding with {:?} to {}", response, addr);
+                METRICS.responses.inc(&[&format!("{:?}", response.rcode())]);
+                let out = match response.encode() {
+                    Ok(out) => out,
+                    Err(er) => {
+                        error!("Error encoding response: {}", er);
+                        continue;
+                    }
+                };
+                if let Some(query) = &query {
+                    dnstap::log(&dnstap::Message::response(
+                        query,
+                        MessageType::ClientResponse,
+                        out.clone(),
+                    ));
+                }
+                if let Err(er) = sink.send((out, addr)).await {
                     error!("Error sending data: {}", er);
                 }
             }
         });
 
+        let tracker = TaskTracker::new();
         loop {
-            match stream.next().await {
-                Some(val) => {
-                    match val {
-                        Ok((packet, source)) => {
-                            let tx_clone = tx.clone();
-                            let resolver_clone = self.resolver.clone();
-                            tokio::spawn(async move {
-                                debug!("Received {:?} packet from {}", packet, source);
-                                let rcode = match packet.header.opcode {
-                                    0 => 0,
-                                    _ => 4,
-                                };
-                                let mut header = Header::new(
-                                    packet.header.id,
-                                    packet.header.qdcount,
-                                    packet.header.qdcount,
-                                    0,
-                                    0,
-                                    true,
-                                    packet.header.opcode,
-                                    false,
-                                    false,
-                                    packet.header.rd,
-                                    false,
-                                    0,
-                                    rcode,
-                                );
-                                let mut answers = Vec::new();
-                                match resolver_clone {
-                                    Some(addr) => {
-                                        let resolver_udp_socket =
-                                            match UdpSocket::bind("0.0.0.0:0").await {
-                                                Ok(listener) => listener,
-                                                Err(e) => {
-                                                    error!("Failed to bind UDP listener: {}", e);
-                                                    return;
-                                                }
-                                            };
-                                        let resolver_framed =
-                                            UdpFramed::new(resolver_udp_socket, Parser::new());
-                                        let (mut r_sink, mut r_stream) = resolver_framed.split();
-                                        debug!(
-                                            "Forwarding {:?} packet to the upstream server {}",
-                                            packet, addr
-                                        );
-                                        if let Err(_) = r_sink.send((packet.clone(), addr)).await {
-                                            error!("Failed to forward UDP request to the upstream server")
-                                        }
-
-                                        if let Some(upstream_response) = r_stream.next().await {
-                                            match upstream_response {
-                                            Ok((upstream_packet, _)) => {
-                                                debug!("Received {:?} packet from the upstream server {}", upstream_packet, addr);
-                                                if let Some(ans) = upstream_packet.answer {
-                                                    answers = ans;
-                                                }
-                                                header = upstream_packet.header;
-                                                // Note: This portion is added because YC9 (?) codecraftors tests were failing after resolver enable.
-                                                if header.ancount == 0 {
-                                                    header.qdcount = packet.header.qdcount;
-                                                    header.ancount = packet.header.qdcount;
-                                                    for q in &packet.question {
-                                                        answers.push(Answer {
-                                                            name: q.qname.clone(),
-                                                            typ: QuestionType::A,
-                                                            class: QuestionClass::IN,
-                                                            ttl: 3600,
-                                                            length: 4,
-                                                            data: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
-                                                        });
-                                                    }
-                                                }
-                                            },
-                                            Err(_) => error!("Failed to parse response from the upstream server"),
-                                        }
-                                        }
-                                    }
-                                    None => {
-                                        for q in &packet.question {
-                                            answers.push(Answer {
-                                                name: q.qname.clone(),
-                                                typ: QuestionType::A,
-                                                class: QuestionClass::IN,
-                                                ttl: 3600,
-                                                length: 4,
-                                                data: RData::A(Ipv4Addr::new(8, 8, 8, 8)),
-                                            });
-                                        }
-                                    }
-                                }
-
-                                let response = UdpPacket {
-                                    header,
-                                    question: packet.question,
-                                    answer: Some(answers),
-                                };
-                                if let Err(_) = tx_clone.send((response, source)).await {
-                                    error!("Failed to send UDP response to async channel")
-                                }
-                            });
-                        }
-                        Err(e) => {
-                            error!("Error receiving data: {}", e);
-                            break;
-                        }
+            let val = tokio::select! {
+                _ = shutdown.cancelled() => {
+                    info!("Shutting down, no longer accepting queries");
+                    break;
+                }
+                val = stream.next() => match val {
+                    Some(val) => val,
+                    None => break,
+                },
+            };
+            match val {
+                Ok((view, source)) => {
+                    if !admit(&view) {
+                        debug!("Dropping response received from {}", source);
+                        continue;
                     }
+                    let tx_clone = tx.clone();
+                    let handler = self.handler.clone();
+                    let client = ClientInfo {
+                        addr: source,
+                        protocol: Protocol::Udp,
+                    };
+                    let local = self.socket;
+                    tracker.spawn(async move {
+                        let query = dnstap::enabled().then(|| {
+                            let query = dnstap::Message::query(
+                                MessageType::ClientQuery,
+                                Some(source),
+                                Some(local),
+                                view.as_bytes().clone(),
+                            );
+                            dnstap::log(&query);
+                            query
+                        });
+                        let Some(packet) = decode(view, source) else {
+                            return;
+                        };
+                        debug!("Received {:?} packet from {}", packet, source);
+                        let qtype = packet
+                            .question
+                            .first()
+                            .map_or("NONE".to_string(), |question| type_name(question.qtype));
+                        METRICS.queries.inc(&[&qtype, client.protocol.as_str()]);
+                        let _in_flight = METRICS.queries_in_flight.track();
+                        if let Some(response) = handler.handle(packet, client).await {
+                            if tx_clone.send((response, source, query)).await.is_err() {
+                                error!("Failed to send UDP response to async channel")
+                            }
+                        }
+                    });
+                }
+                Err(e) => {
+                    error!("Error receiving data: {}", e);
+                    break;
                 }
-                None => break,
             }
         }
+
+        // Responses are flushed once every task, and with it every sender, is gone.
+        tracker.close();
+        drop(tx);
+        let deadline = Instant::now() + self.shutdown_timeout;
+        if timeout_at(deadline, tracker.wait()).await.is_err() {
+            return Err(ServerError::ShutdownTimeout(
+                tracker.len(),
+                self.shutdown_timeout,
+            ));
+        }
+        if timeout_at(deadline, responder).await.is_err() {
+            return Err(ServerError::ResponderTimeout(self.shutdown_timeout));
+        }
+        info!("All in-flight queries finished");
+        Ok(())
+    }
+}
+
+/// Hands out every datagram as a [`MessageView`], leaving the full decode to
+/// the task that handles it. Responses are sent as already encoded.
+struct Codec;
+
+impl Decoder for Codec {
+    type Item = MessageView;
+    type Error = std::io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<MessageView>, Self::Error> {
+        if src.is_empty() {
+            return Ok(None);
+        }
+        // The datagram is taken whole even when it is too short for a header,
+        // leaving bytes behind would make `UdpFramed` fail the stream.
+        let len = src.len();
+        match MessageView::new(src.split().freeze()) {
+            Ok(view) => Ok(Some(view)),
+            Err(_) => {
+                METRICS.inbound_malformed.inc();
+                debug!("Dropped a malformed {} byte datagram", len);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Encoder<Bytes> for Codec {
+    type Error = std::io::Error;
+
+    fn encode(&mut self, item: Bytes, dst: &mut BytesMut) -> Result<(), Self::Error> {
+        dst.extend_from_slice(&item);
+        Ok(())
+    }
+}
+
+/// Whether a datagram may be handled as a query.
+///
+/// Responses are dropped without a reply, otherwise two servers pointed at
+/// each other would bounce messages forever and spoofed responses could use
+/// us as a reflector.
+fn admit(view: &MessageView) -> bool {
+    if view.header().qr {
+        METRICS.inbound_responses_dropped.inc();
+        return false;
+    }
+    true
+}
+
+/// Fully decodes a datagram that was admitted, counting it when it turns out
+/// to be malformed.
+fn decode(view: MessageView, source: SocketAddr) -> Option<UdpPacket> {
+    match UdpPacket::try_from(view) {
+        Ok(packet) => Some(packet),
+        Err(e) => {
+            METRICS.inbound_malformed.inc();
+            debug!("Dropped a malformed query from {}: {}", source, e);
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod server_tests {
+    use super::*;
+    use rust_dns::{Message, Parser, Question};
+
+    fn handler() -> Arc<dyn RequestHandler> {
+        Arc::new(Chain::default())
+    }
+
+    fn view(packet: UdpPacket) -> MessageView {
+        let mut buf = BytesMut::new();
+        Parser::new().encode(packet, &mut buf).unwrap();
+        MessageView::new(buf.freeze()).unwrap()
+    }
+
+    #[test]
+    fn test_codec_drops_short_datagrams() {
+        let mut codec = Codec;
+        let malformed = METRICS.inbound_malformed.get();
+        let mut short = BytesMut::from(&b"abc"[..]);
+        assert!(codec.decode_eof(&mut short).unwrap().is_none());
+        assert!(short.is_empty());
+        assert!(METRICS.inbound_malformed.get() > malformed);
+
+        let query = Message::query(1, Question::new("codecrafters.io".to_string(), 1, 1)).build();
+        let mut buf = BytesMut::new();
+        codec
+            .encode(view(query.clone()).into_bytes(), &mut buf)
+            .unwrap();
+        let view = codec.decode_eof(&mut buf).unwrap().unwrap();
+        assert_eq!(decode(view, "127.0.0.1:5353".parse().unwrap()), Some(query));
+    }
+
+    #[test]
+    fn test_decode_drops_malformed_queries() {
+        let malformed = METRICS.inbound_malformed.get();
+        // A header promising a question that is not there.
+        let truncated = Bytes::from_static(&[0, 1, 1, 0, 0, 1, 0, 0, 0, 0, 0, 0]);
+        let view = MessageView::new(truncated).unwrap();
+        assert_eq!(decode(view, "127.0.0.1:5353".parse().unwrap()), None);
+        assert!(METRICS.inbound_malformed.get() > malformed);
+    }
+
+    #[test]
+    fn test_admit() {
+        let query = Message::query(1, Question::new("codecrafters.io".to_string(), 1, 1)).build();
+        assert!(admit(&view(query.clone())));
+
+        let response = Message::response_to(&query).build();
+        let dropped = METRICS.inbound_responses_dropped.get();
+        assert!(!admit(&view(response)));
+        assert!(METRICS.inbound_responses_dropped.get() > dropped);
+    }
+
+    #[test]
+    fn test_chain_for_client() {
+        let config = Config::parse(
+            r#"
+            [server]
+            middleware = ["forward", "fallback"]
+
+            [[groups]]
+            name = "kids"
+            networks = ["10.0.1.0/24", "fd00:1::/64"]
+            blocklist = { mode = "null" }
+
+            [[groups]]
+            name = "lan"
+            networks = ["10.0.0.0/16"]
+            "#,
+        )
+        .unwrap();
+        let state = ServerState::from_config(&config).unwrap();
+        let group = |addr: &str| {
+            let chain = state.chain_for(addr.parse().unwrap());
+            state
+                .groups
+                .iter()
+                .find(|group| std::ptr::eq(&group.chain, chain))
+                .map(|group| group.name.as_str())
+        };
+        assert_eq!(group("10.0.1.7"), Some("kids"));
+        assert_eq!(group("::ffff:10.0.1.7"), Some("kids"));
+        assert_eq!(group("fd00:1::7"), Some("kids"));
+        assert_eq!(group("10.0.2.7"), Some("lan"));
+        assert_eq!(group("192.168.1.7"), None);
+    }
+
+    #[tokio::test]
+    async fn test_run_stops_on_shutdown() {
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        let server = DnsServer::new("127.0.0.1:0".parse().unwrap(), handler());
+        assert!(server.run(shutdown).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_run_fails_when_bind_fails() {
+        let taken = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server = DnsServer::new(taken.local_addr().unwrap(), handler());
+        assert!(matches!(
+            server.run(CancellationToken::new()).await,
+            Err(ServerError::Bind(_))
+        ));
     }
 }
@@ -0,0 +1,22 @@
+use log::{error, info};
+use tokio::signal::unix::{signal, SignalKind};
+use tokio_util::sync::CancellationToken;
+
+/// Cancels `shutdown` on the first SIGTERM or SIGINT.
+pub(crate) async fn shutdown_on_signal(shutdown: CancellationToken) {
+    let (mut terminate, mut interrupt) = match (
+        signal(SignalKind::terminate()),
+        signal(SignalKind::interrupt()),
+    ) {
+        (Ok(terminate), Ok(interrupt)) => (terminate, interrupt),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("Failed to install shutdown signal handlers: {}", e);
+            return;
+        }
+    };
+    tokio::select! {
+        _ = terminate.recv() => info!("Received SIGTERM"),
+        _ = interrupt.recv() => info!("Received SIGINT"),
+    }
+    shutdown.cancel();
+}
@@ -0,0 +1,20 @@
+use crate::handler::{ClientInfo, Protocol};
+use std::path::PathBuf;
+
+/// A UDP client on localhost.
+pub(crate) fn client() -> ClientInfo {
+    client_from("127.0.0.1:5353")
+}
+
+/// A UDP client sending from `addr`.
+pub(crate) fn client_from(addr: &str) -> ClientInfo {
+    ClientInfo {
+        addr: addr.parse().unwrap(),
+        protocol: Protocol::Udp,
+    }
+}
+
+/// A path in the temp directory, unique to `name` and this test process.
+pub(crate) fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("dns-{}-{}", name, std::process::id()))
+}
@@ -0,0 +1,594 @@
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use std::fmt;
+use thiserror::Error;
+
+use crate::{
+    answer::{Answer, RData},
+    edns::OPT_TYPE,
+    header::{Header, Rcode},
+    name::{self, DomainName, NameError},
+    question::{QuestionClass, QuestionType},
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Error)]
+pub enum DecodeError {
+    #[error("Message is shorter than the 12 byte header")]
+    ShortHeader,
+    #[error("Record runs past the end of the message")]
+    Truncated,
+    #[error("Malformed data for record type {0}")]
+    InvalidRData(u16),
+    #[error(transparent)]
+    Name(#[from] NameError),
+}
+
+/// A read-only view over a received message.
+///
+/// Nothing is decoded up front: the header fields are read on demand and the
+/// question and answer sections are walked lazily by [`MessageView::questions`]
+/// and [`MessageView::answers`]. Names are borrowed from the message and record
+/// data is sliced out of it, so inspecting a message never copies it. Malformed
+/// sections surface as errors from the iterators instead of panics.
+#[derive(Debug, Clone)]
+pub struct MessageView {
+    msg: Bytes,
+}
+
+impl MessageView {
+    pub fn new(msg: Bytes) -> Result<Self, DecodeError> {
+        if msg.len() < 12 {
+            return Err(DecodeError::ShortHeader);
+        }
+        Ok(MessageView { msg })
+    }
+
+    /// The raw message, e.g. to pass it on unchanged.
+    pub fn as_bytes(&self) -> &Bytes {
+        &self.msg
+    }
+
+    pub fn into_bytes(self) -> Bytes {
+        self.msg
+    }
+
+    pub fn id(&self) -> u16 {
+        u16::from_be_bytes([self.msg[0], self.msg[1]])
+    }
+
+    pub fn header(&self) -> Header {
+        Header::read(&mut &self.msg[..12]).expect("length checked when the view was made")
+    }
+
+    pub fn qdcount(&self) -> u16 {
+        u16::from_be_bytes([self.msg[4], self.msg[5]])
+    }
+
+    pub fn ancount(&self) -> u16 {
+        u16::from_be_bytes([self.msg[6], self.msg[7]])
+    }
+
+    pub fn nscount(&self) -> u16 {
+        u16::from_be_bytes([self.msg[8], self.msg[9]])
+    }
+
+    pub fn arcount(&self) -> u16 {
+        u16::from_be_bytes([self.msg[10], self.msg[11]])
+    }
+
+    /// The RCODE, with the upper bits from the OPT record if there is one.
+    pub fn rcode(&self) -> Rcode {
+        let extended_rcode = self
+            .additionals()
+            .ok()
+            .and_then(|mut records| {
+                records.find_map(|record| record.ok().filter(|record| record.typ == OPT_TYPE))
+            })
+            .map_or(0, |opt| (opt.ttl >> 24) as u8);
+        Rcode::from_parts(self.header().rcode.low(), extended_rcode)
+    }
+
+    /// Copies the message with another ID, e.g. to relay an upstream answer
+    /// under the ID the client used. Every other byte is kept.
+    pub fn with_id(&self, id: u16) -> MessageView {
+        let mut msg = BytesMut::from(&self.msg[..]);
+        msg[..2].copy_from_slice(&id.to_be_bytes());
+        MessageView { msg: msg.freeze() }
+    }
+
+    /// Copies the message with the type of each question replaced by the
+    /// next of `qtypes`. Every other byte is kept.
+    pub fn with_qtypes(
+        &self,
+        qtypes: impl IntoIterator<Item = u16>,
+    ) -> Result<MessageView, DecodeError> {
+        let mut msg = BytesMut::from(&self.msg[..]);
+        let mut questions = self.questions();
+        for qtype in qtypes {
+            let Some(question) = questions.next() else {
+                break;
+            };
+            question?;
+            let at = questions.pos - 4;
+            msg[at..at + 2].copy_from_slice(&qtype.to_be_bytes());
+        }
+        Ok(MessageView { msg: msg.freeze() })
+    }
+
+    /// Copies the message without the answers `keep` turns down.
+    ///
+    /// Everything before the first dropped answer is copied as it is, so
+    /// compression pointers into it stay valid. The records after it move,
+    /// so they are written with their names in full, including the names
+    /// inside the data of the types [`RData`] knows.
+    pub fn retain_answers(
+        &self,
+        mut keep: impl FnMut(&RecordRef<'_>) -> bool,
+    ) -> Result<MessageView, DecodeError> {
+        let mut answers = self.answers()?;
+        let mut kept = 0;
+        let mut start = answers.pos;
+        loop {
+            let Some(record) = answers.next() else {
+                return Ok(self.clone());
+            };
+            if !keep(&record?) {
+                break;
+            }
+            kept += 1;
+            start = answers.pos;
+        }
+
+        let mut msg = BytesMut::from(&self.msg[..start]);
+        for record in answers.by_ref() {
+            let record = record?;
+            if keep(&record) {
+                record.write(&mut msg)?;
+                kept += 1;
+            }
+        }
+        let mut pos = answers.pos;
+        for count in [self.nscount(), self.arcount()] {
+            let mut records = Records {
+                msg: &self.msg,
+                pos,
+                remaining: count,
+            };
+            for record in records.by_ref() {
+                record?.write(&mut msg)?;
+            }
+            pos = records.pos;
+        }
+        msg[6..8].copy_from_slice(&u16::to_be_bytes(kept));
+        Ok(MessageView { msg: msg.freeze() })
+    }
+
+    pub fn questions(&self) -> Questions<'_> {
+        Questions {
+            msg: &self.msg,
+            pos: 12,
+            remaining: self.qdcount(),
+        }
+    }
+
+    /// The first question, which is the only one in practice.
+    pub fn question(&self) -> Option<Result<QuestionRef<'_>, DecodeError>> {
+        self.questions().next()
+    }
+
+    /// Walks the answer section. The question section is skipped without
+    /// decoding it.
+    pub fn answers(&self) -> Result<Records<'_>, DecodeError> {
+        self.section(0)
+    }
+
+    /// Walks the authority section.
+    pub fn authorities(&self) -> Result<Records<'_>, DecodeError> {
+        self.section(1)
+    }
+
+    /// Walks the additional section, which is where the OPT record lives.
+    pub fn additionals(&self) -> Result<Records<'_>, DecodeError> {
+        self.section(2)
+    }
+
+    /// The `index`th record section, found by skipping everything before it.
+    fn section(&self, index: usize) -> Result<Records<'_>, DecodeError> {
+        let mut questions = self.questions();
+        for question in questions.by_ref() {
+            question?;
+        }
+        let counts = [self.ancount(), self.nscount(), self.arcount()];
+        let mut pos = questions.pos;
+        for &count in &counts[..index] {
+            let mut records = Records {
+                msg: &self.msg,
+                pos,
+                remaining: count,
+            };
+            for record in records.by_ref() {
+                record?;
+            }
+            pos = records.pos;
+        }
+        Ok(Records {
+            msg: &self.msg,
+            pos,
+            remaining: counts[index],
+        })
+    }
+}
+
+/// A name borrowed from a [`MessageView`]. It has been validated when the
+/// view handed it out, so walking its labels cannot fail.
+#[derive(Clone, Copy)]
+pub struct NameRef<'a> {
+    msg: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> NameRef<'a> {
+    /// Validates the name at `pos` and returns it with the offset just past it.
+    fn read(msg: &'a [u8], pos: usize) -> Result<(Self, usize), NameError> {
+        let end = name::walk(msg, pos, |_| {})?;
+        Ok((NameRef { msg, pos }, end))
+    }
+
+    /// The labels of the name, leftmost first, following compression pointers.
+    pub fn labels(&self) -> impl Iterator<Item = &'a [u8]> {
+        let msg = self.msg;
+        let mut pos = self.pos;
+        std::iter::from_fn(move || loop {
+            let first_byte = msg[pos];
+            if first_byte >> 6 == 3 {
+                pos = ((first_byte & 0b0011_1111) as usize) << 8 | msg[pos + 1] as usize;
+                continue;
+            }
+            if first_byte == 0 {
+                return None;
+            }
+            let label = &msg[pos + 1..pos + 1 + first_byte as usize];
+            pos += 1 + first_byte as usize;
+            return Some(label);
+        })
+    }
+
+    /// Copies the name out of the message.
+    pub fn to_name(&self) -> DomainName {
+        DomainName::from_labels(self.labels()).expect("validated when the view was read")
+    }
+}
+
+impl PartialEq<DomainName> for NameRef<'_> {
+    /// Compares without copying, ignoring ASCII case like [`DomainName`] does.
+    fn eq(&self, other: &DomainName) -> bool {
+        let mut labels = self.labels();
+        other
+            .labels()
+            .iter()
+            .all(|b| labels.next().is_some_and(|a| a.eq_ignore_ascii_case(b)))
+            && labels.next().is_none()
+    }
+}
+
+impl fmt::Debug for NameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "NameRef({})", self)
+    }
+}
+
+impl fmt::Display for NameRef<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.to_name(), f)
+    }
+}
+
+/// An entry of the question section of a [`MessageView`].
+#[derive(Debug, Clone, Copy)]
+pub struct QuestionRef<'a> {
+    pub qname: NameRef<'a>,
+    pub qtype: u16,
+    pub qclass: u16,
+}
+
+/// A resource record of a [`MessageView`]. The data is a slice of the message.
+#[derive(Debug, Clone)]
+pub struct RecordRef<'a> {
+    pub name: NameRef<'a>,
+    pub typ: u16,
+    pub class: u16,
+    pub ttl: u32,
+    pub data: Bytes,
+    /// Offset of the data in the message.
+    data_pos: usize,
+}
+
+impl RecordRef<'_> {
+    /// Decodes the data, resolving compressed names in it against the
+    /// message.
+    pub fn rdata(&self) -> Result<RData, DecodeError> {
+        let msg = &self.name.msg[..self.data_pos + self.data.len()];
+        RData::read(
+            &QuestionType::from(self.typ),
+            &mut &msg[self.data_pos..],
+            msg,
+            self.data.len(),
+        )
+    }
+
+    /// Copies the record out of the message.
+    pub fn to_answer(&self) -> Result<Answer, DecodeError> {
+        Ok(Answer {
+            name: self.name.to_name(),
+            typ: QuestionType::from(self.typ),
+            class: QuestionClass::from(self.class),
+            ttl: self.ttl,
+            data: self.rdata()?,
+        })
+    }
+
+    /// Appends the record with its names written in full. Data of types
+    /// [`RData`] does not know is copied as it is.
+    fn write(&self, dst: &mut BytesMut) -> Result<(), DecodeError> {
+        self.name.to_name().write(dst);
+        dst.put_u16(self.typ);
+        dst.put_u16(self.class);
+        dst.put_u32(self.ttl);
+        let data = match self.rdata()? {
+            RData::Unknown(data) => data,
+            data => Bytes::from(data),
+        };
+        dst.put_u16(data.len() as u16);
+        dst.put_slice(&data);
+        Ok(())
+    }
+}
+
+/// Iterator over the question section, see [`MessageView::questions`].
+pub struct Questions<'a> {
+    msg: &'a Bytes,
+    pos: usize,
+    remaining: u16,
+}
+
+impl<'a> Iterator for Questions<'a> {
+    type Item = Result<QuestionRef<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let question = (|| {
+            let (qname, end) = NameRef::read(self.msg, self.pos)?;
+            let mut fixed = self.msg.get(end..end + 4).ok_or(DecodeError::Truncated)?;
+            self.pos = end + 4;
+            Ok(QuestionRef {
+                qname,
+                qtype: fixed.get_u16(),
+                qclass: fixed.get_u16(),
+            })
+        })();
+        // Nothing after a malformed entry can be located.
+        if question.is_err() {
+            self.remaining = 0;
+        }
+        Some(question)
+    }
+}
+
+/// Iterator over the answer section, see [`MessageView::answers`].
+pub struct Records<'a> {
+    msg: &'a Bytes,
+    pos: usize,
+    remaining: u16,
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = Result<RecordRef<'a>, DecodeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let record = (|| {
+            let (name, end) = NameRef::read(self.msg, self.pos)?;
+            let mut fixed = self.msg.get(end..end + 10).ok_or(DecodeError::Truncated)?;
+            let typ = fixed.get_u16();
+            let class = fixed.get_u16();
+            let ttl = fixed.get_u32();
+            let data_start = end + 10;
+            let data_end = data_start + fixed.get_u16() as usize;
+            if data_end > self.msg.len() {
+                return Err(DecodeError::Truncated);
+            }
+            self.pos = data_end;
+            Ok(RecordRef {
+                name,
+                typ,
+                class,
+                ttl,
+                data: self.msg.slice(data_start..data_end),
+                data_pos: data_start,
+            })
+        })();
+        if record.is_err() {
+            self.remaining = 0;
+        }
+        Some(record)
+    }
+}
+
+#[cfg(test)]
+mod view_tests {
+    use super::*;
+    use crate::{Message, Parser, Question};
+    use tokio_util::codec::Encoder;
+
+    fn response() -> Bytes {
+        let query =
+            Message::query(1234, Question::new("Codecrafters.io".to_string(), 1, 1)).build();
+        let response = Message::response_to(&query)
+            .answer(Answer::new(
+                "codecrafters.io".to_string(),
+                1,
+                1,
+                60,
+                "8.8.8.8".to_string(),
+            ))
+            .build();
+        let mut buf = BytesMut::new();
+        Parser::new().encode(response, &mut buf).unwrap();
+        buf.freeze()
+    }
+
+    #[test]
+    fn test_view_reads_sections_lazily() {
+        let msg = response();
+        let view = MessageView::new(msg.clone()).unwrap();
+        assert_eq!(view.id(), 1234);
+        assert_eq!(view.ancount(), 1);
+        assert!(view.header().qr);
+
+        let question = view.question().unwrap().unwrap();
+        assert_eq!(
+            question.qname,
+            "codecrafters.IO".parse::<DomainName>().unwrap()
+        );
+        assert_ne!(question.qname, "io".parse::<DomainName>().unwrap());
+        assert_eq!(question.qname.to_string(), "Codecrafters.io");
+        assert_eq!(question.qtype, 1);
+
+        let answers: Vec<RecordRef> = view.answers().unwrap().map(Result::unwrap).collect();
+        assert_eq!(answers.len(), 1);
+        assert_eq!(answers[0].ttl, 60);
+        assert_eq!(answers[0].data.as_ref(), &[8, 8, 8, 8]);
+        // The record data shares the message buffer instead of copying it.
+        assert_eq!(answers[0].data.as_ptr(), msg[msg.len() - 4..].as_ptr());
+    }
+
+    #[test]
+    fn test_view_follows_compression_pointers() {
+        let mut msg = BytesMut::from(&response()[..]);
+        // Replace the answer name with a pointer to the question name.
+        let answer_start = 12 + 17 + 4;
+        msg[answer_start] = 0b1100_0000;
+        msg[answer_start + 1] = 12;
+        msg.copy_within(answer_start + 17.., answer_start + 2);
+        msg.truncate(msg.len() - 15);
+
+        let view = MessageView::new(msg.freeze()).unwrap();
+        let answer = view.answers().unwrap().next().unwrap().unwrap();
+        assert_eq!(answer.name.to_name(), "codecrafters.io".parse().unwrap());
+        assert_eq!(answer.data.as_ref(), &[8, 8, 8, 8]);
+    }
+
+    /// A response for example.com whose names are all compressed: a CNAME
+    /// to www.example.com, A records for 10.0.0.1 and 1.2.3.4 and a SOA in
+    /// the authority section.
+    fn compressed() -> Bytes {
+        let mut msg = BytesMut::new();
+        msg.put_slice(&[0, 1, 0x81, 0x80, 0, 1, 0, 3, 0, 1, 0, 0]);
+        msg.put_slice(b"\x07example\x03com\x00\x00\x01\x00\x01");
+        let record = |msg: &mut BytesMut, name: &[u8], typ: u16, data: &[u8]| {
+            msg.put_slice(name);
+            msg.put_u16(typ);
+            msg.put_u16(1);
+            msg.put_u32(60);
+            msg.put_u16(data.len() as u16);
+            msg.put_slice(data);
+        };
+        record(&mut msg, &[0xc0, 12], 5, b"\x03www\xc0\x0c");
+        record(&mut msg, &[0xc0, 41], 1, &[10, 0, 0, 1]);
+        record(&mut msg, &[0xc0, 41], 1, &[1, 2, 3, 4]);
+        let mut soa = b"\x02ns\xc0\x0c\xc0\x29".to_vec();
+        soa.extend_from_slice(&[0; 20]);
+        record(&mut msg, &[0xc0, 12], 6, &soa);
+        msg.freeze()
+    }
+
+    fn records(records: Records<'_>) -> Vec<Answer> {
+        records
+            .map(|record| record.unwrap().to_answer().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_rcode_and_id() {
+        let query = Message::query(1, Question::new("codecrafters.io".to_string(), 1, 1)).build();
+        let response = Message::response_to(&query)
+            .rcode(Rcode::BadCookie)
+            .edns(crate::Edns::default())
+            .build();
+        let mut buf = BytesMut::new();
+        Parser::new().encode(response, &mut buf).unwrap();
+        let view = MessageView::new(buf.freeze()).unwrap();
+        assert_eq!(view.rcode(), Rcode::BadCookie);
+
+        let relayed = view.with_id(0xabcd);
+        assert_eq!(relayed.id(), 0xabcd);
+        assert_eq!(relayed.as_bytes()[2..], view.as_bytes()[2..]);
+    }
+
+    #[test]
+    fn test_with_qtypes() {
+        let msg = compressed();
+        let view = MessageView::new(msg.clone()).unwrap();
+        let any = view.with_qtypes([255]).unwrap();
+        assert_eq!(any.question().unwrap().unwrap().qtype, 255);
+        assert_eq!(any.as_bytes()[..25], msg[..25]);
+        assert_eq!(any.as_bytes()[27..], msg[27..]);
+    }
+
+    #[test]
+    fn test_retain_answers() {
+        let view = MessageView::new(compressed()).unwrap();
+        let www: DomainName = "www.example.com".parse().unwrap();
+        let answers = records(view.answers().unwrap());
+        assert_eq!(answers[0].data, RData::CNAME(www.clone()));
+        assert_eq!(answers[1].name, www);
+
+        let public = |record: &RecordRef<'_>| record.data.as_ref() != [10, 0, 0, 1];
+        let stripped = view.retain_answers(public).unwrap();
+        assert_eq!(stripped.ancount(), 2);
+        assert_eq!(stripped.nscount(), 1);
+        assert_eq!(
+            records(stripped.answers().unwrap()),
+            vec![answers[0].clone(), answers[2].clone()]
+        );
+        assert_eq!(
+            records(stripped.authorities().unwrap()),
+            records(view.authorities().unwrap())
+        );
+        // Nothing before the dropped record moved, apart from the count.
+        assert_eq!(stripped.as_bytes()[8..47], view.as_bytes()[8..47]);
+
+        let kept = view.retain_answers(|_| true).unwrap();
+        assert_eq!(kept.as_bytes(), view.as_bytes());
+    }
+
+    #[test]
+    fn test_view_reports_malformed_sections() {
+        assert_eq!(
+            MessageView::new(Bytes::from_static(&[0; 4])).unwrap_err(),
+            DecodeError::ShortHeader
+        );
+
+        let msg = response();
+        let view = MessageView::new(msg.slice(..msg.len() - 2)).unwrap();
+        assert!(view.question().unwrap().is_ok());
+        let mut answers = view.answers().unwrap();
+        assert_eq!(answers.next().unwrap().unwrap_err(), DecodeError::Truncated);
+        assert!(answers.next().is_none());
+
+        let mut msg = BytesMut::from(&msg[..]);
+        msg[12] = 0b1100_0000;
+        msg[13] = 12;
+        let view = MessageView::new(msg.freeze()).unwrap();
+        assert_eq!(
+            view.question().unwrap().unwrap_err(),
+            DecodeError::Name(NameError::InvalidPointer)
+        );
+        assert!(view.answers().is_err());
+    }
+}